@@ -1,20 +1,41 @@
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::{cmp, path::PathBuf};
 
 use ratatui::widgets::ListState;
 use tui_textarea::TextArea;
 
-use crate::git_helper::GitCommitCandidate;
+use crate::git_backend::{ChangeStatus, FileChange, Signature};
+use crate::git_helper::{Authors, GitCommitCandidate, Messages};
+use crate::patch::FileDiff;
+use crate::session::Session;
+use crate::suggest::{suggest, Strategy, Suggestion, Workspace};
 use crate::tree::{Mark, NodeId, Tree, TreeError, TreePtr};
+use crate::ui::display_name;
 
 #[derive(Clone)]
 pub enum CurrentScreen {
     FileNavigator,
+    LineSelector,
     CommitMessageEditor,
-    ErrorMessagePopUp(&'static str, Box<CurrentScreen>),
+    SuggestionPicker,
+    PlanEditor,
+    ErrorMessagePopUp(String, Box<CurrentScreen>),
+    InfoMessagePopUp(String, Box<CurrentScreen>),
     HelpMessagePopUp(Box<CurrentScreen>),
 }
 
+/// Who is recorded as the author of the commit being written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthorChoice {
+    /// The author who last changed most of the selected files.
+    Original,
+    /// The user, who is also the committer.
+    Committer,
+    /// One of [`Authors::signatures`].
+    Author(usize),
+}
+
 #[derive(Debug, Default)]
 pub struct StatefulList<T> {
     pub state: ListState,
@@ -23,7 +44,7 @@ pub struct StatefulList<T> {
 
 impl<T> StatefulList<T> {
     fn new(items: Vec<T>) -> StatefulList<T> {
-        let state = if items.len() > 0 {
+        let state = if !items.is_empty() {
             let mut state = ListState::default();
             state.select(Some(0));
             state
@@ -75,23 +96,138 @@ pub struct AppItem {
 
 impl AppItem {
     pub fn is_directory(&self) -> bool {
-        !self.tree.borrow().get_node(self.node_id).is_leaf_node()
+        let tree = self.tree.borrow();
+        let node = tree.get_node(self.node_id);
+        !node.is_leaf_node() && node.fullpath.is_none()
+    }
+
+    /// Returns the number of hunks if this item is a file split into hunks.
+    pub fn num_hunks(&self) -> usize {
+        let tree = self.tree.borrow();
+        let node = tree.get_node(self.node_id);
+        if node.fullpath.is_some() {
+            node.children.len()
+        } else {
+            0
+        }
     }
 
     pub fn get_mark(&self) -> Mark {
         self.tree.borrow().get_node(self.node_id).mark
     }
+
+    pub fn is_hunk(&self) -> bool {
+        self.tree.borrow().get_node(self.node_id).hunk.is_some()
+    }
+
+    /// Returns how this file is changed. It is `None` for a directory or a
+    /// hunk.
+    pub fn get_status(&self) -> Option<ChangeStatus> {
+        self.tree.borrow().get_node(self.node_id).status
+    }
+
+    /// Counts the files under this directory per change status.
+    pub fn count_statuses(&self) -> Vec<(ChangeStatus, usize)> {
+        self.tree.borrow().count_statuses(self.node_id)
+    }
+
+    /// Returns the source of this file if it is renamed.
+    pub fn get_old_path(&self) -> Option<PathBuf> {
+        let tree = self.tree.borrow();
+        let node = tree.get_node(self.node_id);
+        match (&node.fullpath, node.hunk) {
+            (Some(path), None) => tree.get_old_path(path).cloned(),
+            _ => None,
+        }
+    }
+}
+
+pub struct AppLine {
+    pub index: usize,
+    pub text: String,
+    node_id: NodeId,
+    tree: TreePtr,
+}
+
+impl AppLine {
+    /// Returns true if this is a changed line which is not committed yet.
+    pub fn is_change(&self) -> bool {
+        self.tree
+            .borrow()
+            .get_node(self.node_id)
+            .lines
+            .contains(&self.index)
+    }
+
+    pub fn is_selected(&self) -> bool {
+        self.tree
+            .borrow()
+            .get_selected_lines(self.node_id)
+            .contains(&self.index)
+    }
+}
+
+/// The state of the application before a commit was saved, to undo it.
+struct Snapshot {
+    /// The tree with the selection of the commit.
+    tree: TreePtr,
+    author_choice: AuthorChoice,
+    suggestions: Vec<Suggestion>,
+    suggestion: Option<usize>,
 }
 
 // Application
 pub struct App<'a> {
     pub tree: TreePtr,
+    /// The changed files, from which the tree is rebuilt when a saved commit
+    /// is deleted. It is empty in the hunk mode.
+    changes: Vec<FileChange>,
     pub items: StatefulList<AppItem>,
     pub should_quit: bool,
     pub curr_node_id: NodeId,
     pub current_screen: CurrentScreen,
     pub textarea: TextArea<'a>,
     pub commits: Vec<GitCommitCandidate>,
+    /// Per-file diffs used to preview hunks. It is empty in the file mode.
+    pub diffs: HashMap<PathBuf, FileDiff>,
+    /// Lines of the hunk opened in the line selector.
+    pub lines: StatefulList<AppLine>,
+    pub hunk_node_id: NodeId,
+    /// Authors of the commits being split. It is empty if they are unknown.
+    pub authors: Authors,
+    pub author_choice: AuthorChoice,
+    /// Messages of the commits being split, used to prefill the editor.
+    pub messages: Messages,
+    /// The lines last prefilled in the editor, which are replaced when the
+    /// editor is opened again unless they were edited.
+    prefilled: Vec<String>,
+    /// The index of the saved commit whose message is being fixed after it
+    /// was rejected. It is left set if the user gives up.
+    pub retrying: Option<usize>,
+    /// Whether the user asked to save the commits made so far as a plan.
+    pub save_requested: bool,
+    /// The Cargo workspace at the top of the repository, used to suggest one
+    /// commit per crate.
+    pub workspace: Option<Workspace>,
+    /// Strategies offered by the suggestion picker.
+    pub strategies: StatefulList<Strategy>,
+    /// Commits suggested by the picked strategy. It is empty unless they are
+    /// being reviewed.
+    pub suggestions: Vec<Suggestion>,
+    /// The index of the suggestion whose files are selected.
+    pub suggestion: Option<usize>,
+    /// The cursor of the plan editor over the saved commits.
+    pub plan: ListState,
+    /// The index of the saved commit whose message is edited from the plan
+    /// editor.
+    editing: Option<usize>,
+    /// Whether the user confirmed the saved commits to apply them.
+    pub finished: bool,
+    /// The states before the saved commits, to undo them in reverse order.
+    /// It is cleared when the commits are rearranged or deleted in the plan
+    /// editor or restored from a session, after which the last commit is
+    /// undone from its own files and hunks.
+    history: Vec<Snapshot>,
 }
 
 impl<'a> App<'a> {
@@ -109,7 +245,9 @@ impl<'a> App<'a> {
             .collect();
 
         items.sort_by(|a, b| {
-            if a.is_directory() == b.is_directory() {
+            if a.is_hunk() && b.is_hunk() {
+                a.node_id.cmp(&b.node_id)
+            } else if a.is_directory() == b.is_directory() {
                 a.key.cmp(&b.key)
             } else if a.is_directory() {
                 cmp::Ordering::Less
@@ -122,20 +260,58 @@ impl<'a> App<'a> {
     }
 
     /// Constructs a new instance of [`App`].
-    pub fn new(file_paths: Vec<PathBuf>) -> Result<Self, TreeError> {
-        let tree = Tree::new_from_paths(file_paths)?;
+    pub fn new(changes: Vec<FileChange>) -> Result<Self, TreeError> {
+        let tree = Tree::new_from_changes(&changes)?;
+        let mut app = App::new_from_tree(tree, HashMap::new());
+        app.changes = changes;
+        Ok(app)
+    }
+
+    /// Constructs a new instance of [`App`] whose files are split into hunks.
+    pub fn new_with_diffs(diffs: Vec<FileDiff>) -> Result<Self, TreeError> {
+        let tree = Tree::new_from_diffs(&diffs)?;
+        let diffs = diffs
+            .into_iter()
+            .map(|diff| (diff.path.clone(), diff))
+            .collect();
+        Ok(App::new_from_tree(tree, diffs))
+    }
+
+    fn new_from_tree(tree: TreePtr, diffs: HashMap<PathBuf, FileDiff>) -> Self {
         let curr_node_id = tree.borrow().root_id();
         let items = App::get_item_list(&tree, curr_node_id);
 
-        Ok(Self {
+        Self {
             tree,
+            changes: vec![],
             items,
             should_quit: false,
             curr_node_id,
             current_screen: CurrentScreen::FileNavigator,
             textarea: TextArea::default(),
             commits: vec![],
-        })
+            diffs,
+            lines: StatefulList::new(vec![]),
+            hunk_node_id: curr_node_id,
+            authors: Authors::default(),
+            author_choice: AuthorChoice::Original,
+            messages: Messages::default(),
+            prefilled: vec![],
+            retrying: None,
+            save_requested: false,
+            workspace: None,
+            strategies: StatefulList::new(Strategy::ALL.to_vec()),
+            suggestions: vec![],
+            suggestion: None,
+            plan: ListState::default(),
+            editing: None,
+            finished: false,
+            history: vec![],
+        }
+    }
+
+    pub fn is_hunk_mode(&self) -> bool {
+        !self.diffs.is_empty()
     }
 
     pub fn goto_child(&mut self) {
@@ -145,10 +321,88 @@ impl<'a> App<'a> {
             if !self.tree.borrow().get_node(next_node_id).is_leaf_node() {
                 self.items = App::get_item_list(&self.tree, next_node_id);
                 self.curr_node_id = next_node_id;
+            } else if self.items.items[selected_item_idx].is_hunk() {
+                self.open_line_selector(next_node_id);
             }
         }
     }
 
+    fn get_hunk_lines(&self, node_id: NodeId) -> Vec<(usize, String)> {
+        let tree = self.tree.borrow();
+        let node = tree.get_node(node_id);
+
+        let hunk = node
+            .fullpath
+            .as_ref()
+            .and_then(|path| self.diffs.get(path))
+            .zip(node.hunk)
+            .and_then(|(diff, index)| diff.hunks.get(index));
+
+        match hunk {
+            Some(hunk) => hunk
+                .view(&node.lines)
+                .into_iter()
+                .map(|(index, line)| {
+                    let text = String::from_utf8_lossy(&line);
+                    (index, text.trim_end().to_owned())
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn open_line_selector(&mut self, node_id: NodeId) {
+        let lines = self
+            .get_hunk_lines(node_id)
+            .into_iter()
+            .map(|(index, text)| AppLine {
+                index,
+                text,
+                node_id,
+                tree: self.tree.clone(),
+            })
+            .collect();
+
+        self.lines = StatefulList::new(lines);
+        self.hunk_node_id = node_id;
+        self.current_screen = CurrentScreen::LineSelector;
+    }
+
+    pub fn close_line_selector(&mut self) {
+        self.current_screen = CurrentScreen::FileNavigator;
+    }
+
+    pub fn select_line(&mut self) {
+        if let Some(selected_line_idx) = self.lines.state.selected() {
+            let line = &self.lines.items[selected_line_idx];
+            if !line.is_change() {
+                return;
+            }
+
+            let index = line.index;
+            let mut lines = self.tree.borrow().get_selected_lines(line.node_id);
+            if line.is_selected() {
+                lines.retain(|line| *line != index);
+            } else {
+                lines.push(index);
+            }
+            self.tree
+                .borrow_mut()
+                .select_lines(self.hunk_node_id, lines);
+        }
+    }
+
+    /// Returns the path and the header of the hunk in the line selector.
+    pub fn get_hunk_title(&self) -> String {
+        let tree = self.tree.borrow();
+        let node = tree.get_node(self.hunk_node_id);
+        let path = tree.get_path_buf(self.hunk_node_id);
+        let path = display_name(path.parent().unwrap_or(&path).as_os_str());
+        let key = node.key.clone().unwrap_or_default();
+
+        format!("{} {}", path, key.to_string_lossy())
+    }
+
     pub fn goto_parent(&mut self) {
         if let Some(next_node_id) =
             self.tree.borrow().get_node(self.curr_node_id).parent
@@ -177,6 +431,45 @@ impl<'a> App<'a> {
         self.tree.borrow().get_path_buf(self.curr_node_id)
     }
 
+    /// Returns the diff lines of the item under the cursor.
+    ///
+    /// A hunk shows its own lines and a file shows all of its hunks.
+    pub fn get_preview(&self) -> Vec<String> {
+        let Some(selected_item_idx) = self.items.state.selected() else {
+            return vec![];
+        };
+        let node_id = self.items.items[selected_item_idx].node_id;
+
+        let node_ids: Vec<NodeId> = {
+            let tree = self.tree.borrow();
+            let node = tree.get_node(node_id);
+            if node.hunk.is_some() {
+                vec![node_id]
+            } else {
+                let mut node_ids: Vec<NodeId> = node
+                    .children
+                    .values()
+                    .copied()
+                    .filter(|child_id| tree.get_node(*child_id).hunk.is_some())
+                    .collect();
+                node_ids.sort();
+                node_ids
+            }
+        };
+
+        let mut lines = vec![];
+        for node_id in node_ids {
+            let key = self.tree.borrow().get_node(node_id).key.clone();
+            lines.push(key.unwrap_or_default().to_string_lossy().into_owned());
+            lines.extend(
+                self.get_hunk_lines(node_id)
+                    .into_iter()
+                    .map(|(_, text)| text),
+            );
+        }
+        lines
+    }
+
     pub fn get_stats(&self) -> (usize, usize) {
         let borrowed = self.tree.borrow();
 
@@ -188,16 +481,126 @@ impl<'a> App<'a> {
         if num_selected == 0 {
             let msg = "You should select more than one file before writing your commit message!";
             self.current_screen = CurrentScreen::ErrorMessagePopUp(
-                msg,
+                msg.to_owned(),
                 Box::new(self.current_screen.clone()),
             );
         } else {
+            self.prefill_editor();
             self.current_screen = CurrentScreen::CommitMessageEditor;
         }
     }
 
+    /// Fills the editor with the messages of the original commits which
+    /// changed the selected files, unless a message is being written.
+    fn prefill_editor(&mut self) {
+        let lines = self.textarea.lines();
+        let is_empty = lines.iter().all(|line| line.trim().is_empty());
+        if !is_empty && lines != self.prefilled.as_slice() {
+            return;
+        }
+
+        let draft = self.messages.for_paths(&self.get_selected_paths());
+        self.prefilled = draft
+            .join("\n\n")
+            .lines()
+            .map(|line| line.to_owned())
+            .collect();
+        self.textarea = TextArea::new(self.prefilled.clone());
+    }
+
+    /// Returns the paths of the selected files and hunks.
+    fn get_selected_paths(&self) -> Vec<PathBuf> {
+        let tree = self.tree.borrow();
+        let mut paths = tree.get_selected_file_paths();
+        paths.extend(
+            tree.get_selected_hunks().into_iter().map(|hunk| hunk.path),
+        );
+        paths
+    }
+
     pub fn close_editor(&mut self) {
-        self.current_screen = CurrentScreen::FileNavigator;
+        if self.editing.take().is_some() {
+            self.textarea = TextArea::default();
+            self.current_screen = CurrentScreen::PlanEditor;
+        } else if self.retrying.is_some() {
+            self.quit();
+        } else {
+            self.current_screen = CurrentScreen::FileNavigator;
+        }
+    }
+
+    /// Shows why the saved commit was rejected, e.g. the output of a
+    /// `commit-msg` hook, and reopens the editor with its message to retry.
+    pub fn reject_commit(&mut self, index: usize, output: &str) {
+        let Some(commit) = self.commits.get(index) else {
+            return;
+        };
+
+        let lines = commit.msg.lines().map(|line| line.to_owned()).collect();
+        self.textarea = TextArea::new(lines);
+        self.retrying = Some(index);
+        self.should_quit = false;
+
+        let msg = format!(
+            "The commit was rejected:\n\n{}\n\nEdit the message and press 'Ctrl + W' to retry, or 'Esc' to give up.",
+            output.trim_end()
+        );
+        self.current_screen = CurrentScreen::ErrorMessagePopUp(
+            msg,
+            Box::new(CurrentScreen::CommitMessageEditor),
+        );
+    }
+
+    /// Switches to the next author choice: the original author, the
+    /// committer, then each author of the commits being split.
+    pub fn next_author(&mut self) {
+        if self.editing.is_some() {
+            return;
+        }
+        self.author_choice = match self.author_choice {
+            AuthorChoice::Original => AuthorChoice::Committer,
+            AuthorChoice::Committer if !self.authors.signatures.is_empty() => {
+                AuthorChoice::Author(0)
+            }
+            AuthorChoice::Author(index)
+                if index + 1 < self.authors.signatures.len() =>
+            {
+                AuthorChoice::Author(index + 1)
+            }
+            _ => AuthorChoice::Original,
+        };
+    }
+
+    /// Returns the author of the commit being written, or `None` for the
+    /// committer.
+    pub fn get_author(&self) -> Option<Signature> {
+        if let Some(index) = self.editing {
+            return self.commits[index].author.clone();
+        }
+        match self.author_choice {
+            AuthorChoice::Original => {
+                self.authors.majority(&self.get_selected_paths()).cloned()
+            }
+            AuthorChoice::Committer => None,
+            AuthorChoice::Author(index) => {
+                self.authors.signatures.get(index).cloned()
+            }
+        }
+    }
+
+    /// Describes the author of the commit being written.
+    pub fn get_author_text(&self) -> String {
+        let choice = match self.author_choice {
+            AuthorChoice::Original => "original",
+            AuthorChoice::Committer => "committer",
+            AuthorChoice::Author(_) => "picked",
+        };
+        match self.get_author() {
+            Some(author) => {
+                format!("{} <{}> ({})", author.name, author.email, choice)
+            }
+            None => format!("you ({})", choice),
+        }
     }
 
     pub fn save_commit(&mut self) -> Result<(), TreeError> {
@@ -206,25 +609,52 @@ impl<'a> App<'a> {
             .lines()
             .join("\n")
             .trim_end_matches("\r\n")
-            .trim_end_matches("\n")
-            .trim_end_matches(" ")
+            .trim_end_matches('\n')
+            .trim_end_matches(' ')
             .to_owned();
 
         if msg.is_empty() {
             let msg = "Cannot commit with the empty commit message!";
             self.current_screen = CurrentScreen::ErrorMessagePopUp(
-                msg,
+                msg.to_owned(),
                 Box::new(self.current_screen.clone()),
             );
             return Ok(());
         }
 
+        if let Some(index) = self.editing.take() {
+            self.commits[index].msg = msg;
+            self.textarea = TextArea::default();
+            self.current_screen = CurrentScreen::PlanEditor;
+            return Ok(());
+        }
+
+        if let Some(index) = self.retrying.take() {
+            self.commits[index].msg = msg;
+            self.textarea = TextArea::default();
+            self.should_quit = true;
+            return Ok(());
+        }
+
         let old_tree = self.tree.clone();
         let new_tree = old_tree.borrow().get_remaining_tree()?;
 
         let file_paths = old_tree.borrow().get_selected_file_paths();
+        let hunks = old_tree.borrow().get_selected_hunks();
+        let author = self.get_author();
 
-        self.commits.push(GitCommitCandidate { msg, file_paths });
+        self.history.push(Snapshot {
+            tree: old_tree.clone(),
+            author_choice: self.author_choice,
+            suggestions: self.suggestions.clone(),
+            suggestion: self.suggestion,
+        });
+        self.commits.push(GitCommitCandidate {
+            msg,
+            file_paths,
+            hunks,
+            author,
+        });
 
         let curr_node_id = self.tree.borrow().root_id();
 
@@ -232,25 +662,333 @@ impl<'a> App<'a> {
         self.tree = new_tree;
         self.curr_node_id = curr_node_id;
         self.textarea = TextArea::default();
+        self.prefilled = vec![];
+        self.author_choice = AuthorChoice::Original;
 
         self.current_screen = CurrentScreen::FileNavigator;
 
+        // Review the commits before applying them.
         if self.tree.borrow().num_leaf_node == 0 {
-            self.should_quit = true;
+            self.open_plan_editor();
+        } else {
+            self.next_suggestion();
         }
 
         Ok(())
     }
 
+    /// Removes the last saved commit, restores the selection it was made
+    /// from and reopens the editor with its message.
+    pub fn undo_commit(&mut self) {
+        let Some(commit) = self.commits.pop() else {
+            let msg = "No commit is saved yet!";
+            self.show_error(msg.to_owned());
+            return;
+        };
+
+        match self.history.pop() {
+            Some(snapshot) => {
+                let root_id = snapshot.tree.borrow().root_id();
+                self.items = App::get_item_list(&snapshot.tree, root_id);
+                self.tree = snapshot.tree;
+                self.curr_node_id = root_id;
+                self.author_choice = snapshot.author_choice;
+                self.suggestions = snapshot.suggestions;
+                self.suggestion = snapshot.suggestion;
+            }
+            // Select the files and hunks of the commit again on top of the
+            // changes left by the other commits.
+            None => {
+                if let Err(error) = self.rebuild_tree() {
+                    self.commits.push(commit);
+                    self.show_error(format!(
+                        "Cannot undo the commit: {}",
+                        error
+                    ));
+                    return;
+                }
+                {
+                    let mut tree = self.tree.borrow_mut();
+                    tree.select_paths(&commit.file_paths);
+                    for hunk in &commit.hunks {
+                        tree.select_hunk(hunk);
+                    }
+                }
+                self.author_choice = match &commit.author {
+                    None => AuthorChoice::Committer,
+                    Some(author) => self
+                        .authors
+                        .signatures
+                        .iter()
+                        .position(|signature| signature == author)
+                        .map_or(AuthorChoice::Original, AuthorChoice::Author),
+                };
+            }
+        }
+
+        self.textarea =
+            TextArea::new(commit.msg.lines().map(str::to_owned).collect());
+        self.prefilled = vec![];
+        self.current_screen = CurrentScreen::CommitMessageEditor;
+    }
+
+    /// Restores the saved commits, the selection and the draft message of an
+    /// interrupted session. The selection is replaced if a restored commit
+    /// is undone.
+    pub fn restore(&mut self, session: &Session) -> Result<(), TreeError> {
+        self.commits = session.commits.clone();
+        self.rebuild_tree()?;
+        {
+            let mut tree = self.tree.borrow_mut();
+            tree.select_paths(&session.selected_files);
+            for hunk in &session.selected_hunks {
+                tree.select_hunk(hunk);
+            }
+        }
+        self.textarea =
+            TextArea::new(session.draft.lines().map(str::to_owned).collect());
+
+        if self.tree.borrow().num_leaf_node == 0 {
+            self.open_plan_editor();
+        }
+        Ok(())
+    }
+
+    pub fn open_plan_editor(&mut self) {
+        if self.commits.is_empty() {
+            let msg = "No commit is saved yet!";
+            self.show_error(msg.to_owned());
+            return;
+        }
+
+        let index = self.plan.selected().unwrap_or_default();
+        self.plan.select(Some(index.min(self.commits.len() - 1)));
+        self.current_screen = CurrentScreen::PlanEditor;
+    }
+
+    pub fn close_plan_editor(&mut self) {
+        self.current_screen = CurrentScreen::FileNavigator;
+    }
+
+    pub fn next_commit(&mut self) {
+        if let Some(index) = self.plan.selected() {
+            self.plan.select(Some((index + 1) % self.commits.len()));
+        }
+    }
+
+    pub fn previous_commit(&mut self) {
+        if let Some(index) = self.plan.selected() {
+            let len = self.commits.len();
+            self.plan.select(Some((index + len - 1) % len));
+        }
+    }
+
+    /// Swaps the commit under the cursor with the one above it, or below it
+    /// if `down` is true, and keeps the cursor on it.
+    pub fn move_commit(&mut self, down: bool) {
+        let Some(index) = self.plan.selected() else {
+            return;
+        };
+        let other = if down {
+            index + 1
+        } else {
+            index.wrapping_sub(1)
+        };
+        if other < self.commits.len() {
+            self.commits.swap(index, other);
+            self.plan.select(Some(other));
+            self.history.clear();
+        }
+    }
+
+    /// Opens the editor with the message of the commit under the cursor.
+    pub fn edit_commit(&mut self) {
+        let Some(index) = self.plan.selected() else {
+            return;
+        };
+        let msg = &self.commits[index].msg;
+        self.textarea = TextArea::new(msg.lines().map(str::to_owned).collect());
+        self.editing = Some(index);
+        self.current_screen = CurrentScreen::CommitMessageEditor;
+    }
+
+    /// Merges the commit under the cursor with the one below it.
+    pub fn merge_commit(&mut self) {
+        let Some(index) = self.plan.selected() else {
+            return;
+        };
+        if index + 1 < self.commits.len() {
+            let next = self.commits.remove(index + 1);
+            self.commits[index].merge(next);
+            self.history.clear();
+        }
+    }
+
+    /// Deletes the commit under the cursor and returns its changes to the
+    /// file navigator. The commit is kept if they cannot be returned.
+    pub fn delete_commit(&mut self) {
+        let Some(index) = self.plan.selected() else {
+            return;
+        };
+        let commit = self.commits.remove(index);
+        if let Err(error) = self.rebuild_tree() {
+            self.commits.insert(index, commit);
+            self.show_error(format!("Cannot delete the commit: {}", error));
+            return;
+        }
+
+        if self.commits.is_empty() {
+            self.plan.select(None);
+            self.close_plan_editor();
+        } else {
+            self.plan.select(Some(index.min(self.commits.len() - 1)));
+        }
+    }
+
+    /// Rebuilds the tree of the changes which are in no saved commit. The
+    /// selection and the suggestions are dropped.
+    fn rebuild_tree(&mut self) -> Result<(), TreeError> {
+        let mut tree = if self.is_hunk_mode() {
+            let mut diffs: Vec<FileDiff> =
+                self.diffs.values().cloned().collect();
+            diffs.sort_by(|a, b| a.path.cmp(&b.path));
+            Tree::new_from_diffs(&diffs)?
+        } else {
+            Tree::new_from_changes(&self.changes)?
+        };
+
+        for commit in &self.commits {
+            {
+                let mut tree = tree.borrow_mut();
+                tree.select_paths(&commit.file_paths);
+                for hunk in &commit.hunks {
+                    tree.select_hunk(hunk);
+                }
+            }
+            let remaining = tree.borrow().get_remaining_tree()?;
+            tree = remaining;
+        }
+
+        let root_id = tree.borrow().root_id();
+        self.items = App::get_item_list(&tree, root_id);
+        self.tree = tree;
+        self.curr_node_id = root_id;
+        self.suggestions.clear();
+        self.suggestion = None;
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Confirms the saved commits to apply them once every change is
+    /// committed.
+    pub fn apply_commits(&mut self) {
+        if self.tree.borrow().num_leaf_node == 0 {
+            self.finished = true;
+            self.should_quit = true;
+        } else {
+            let msg = "Some changes are not committed yet!";
+            self.show_error(msg.to_owned());
+        }
+    }
+
+    pub fn open_suggestion_picker(&mut self) {
+        self.current_screen = CurrentScreen::SuggestionPicker;
+    }
+
+    pub fn close_suggestion_picker(&mut self) {
+        self.current_screen = CurrentScreen::FileNavigator;
+    }
+
+    /// Suggests commits for the files left by the strategy.
+    pub fn get_suggestions(&self, strategy: Strategy) -> Vec<Suggestion> {
+        let paths = self.tree.borrow().get_file_paths();
+        suggest(&paths, strategy, self.workspace.as_ref())
+    }
+
+    /// Starts reviewing the commits suggested by the strategy under the
+    /// cursor of the picker.
+    pub fn pick_suggestions(&mut self) {
+        let Some(index) = self.strategies.state.selected() else {
+            return;
+        };
+        self.suggestions = self.get_suggestions(self.strategies.items[index]);
+        self.suggestion = None;
+        self.current_screen = CurrentScreen::FileNavigator;
+
+        if self.suggestions.is_empty() {
+            let msg = "There is no Cargo workspace at the top of the repository to suggest commits per crate!";
+            self.show_error(msg.to_owned());
+        } else {
+            self.next_suggestion();
+        }
+    }
+
+    /// Replaces the selection with the files of the next suggestion which
+    /// are not committed yet, so that it can be reviewed and tweaked before
+    /// writing its message. The suggestions end after the last one.
+    pub fn next_suggestion(&mut self) {
+        if self.suggestions.is_empty() {
+            return;
+        }
+
+        let start = self.suggestion.map_or(0, |index| index + 1);
+        let root_id = self.tree.borrow().root_id();
+        self.tree.borrow_mut().mark(root_id, Mark::Unselected);
+
+        self.suggestion = (start..self.suggestions.len()).find(|index| {
+            let paths = &self.suggestions[*index].paths;
+            self.tree.borrow_mut().select_paths(paths) > 0
+        });
+        if self.suggestion.is_none() {
+            self.suggestions.clear();
+        }
+
+        self.items = App::get_item_list(&self.tree, root_id);
+        self.curr_node_id = root_id;
+    }
+
+    /// Describes the suggestion whose files are selected.
+    pub fn get_suggestion_text(&self) -> Option<String> {
+        self.suggestion.map(|index| {
+            format!(
+                "suggested: {} ({}/{})",
+                self.suggestions[index].name,
+                index + 1,
+                self.suggestions.len()
+            )
+        })
+    }
+
     pub fn open_help_popup(&mut self) {
         self.current_screen = CurrentScreen::HelpMessagePopUp(Box::new(
             self.current_screen.clone(),
         ));
     }
 
+    /// Shows the message in a pop-up over the current screen.
+    pub fn show_error(&mut self, msg: String) {
+        self.current_screen = CurrentScreen::ErrorMessagePopUp(
+            msg,
+            Box::new(self.current_screen.clone()),
+        );
+    }
+
+    /// Asks to save the commits made so far as a plan.
+    pub fn save_plan(&mut self) {
+        self.save_requested = true;
+    }
+
+    pub fn show_info(&mut self, msg: String) {
+        self.current_screen = CurrentScreen::InfoMessagePopUp(
+            msg,
+            Box::new(self.current_screen.clone()),
+        );
+    }
+
     pub fn close_popup(&mut self) {
         match &self.current_screen {
-            CurrentScreen::ErrorMessagePopUp(_, prev) => {
+            CurrentScreen::ErrorMessagePopUp(_, prev)
+            | CurrentScreen::InfoMessagePopUp(_, prev) => {
                 self.current_screen = *prev.clone();
             }
             CurrentScreen::HelpMessagePopUp(prev) => {
@@ -265,3 +1003,173 @@ impl<'a> App<'a> {
         self.should_quit = true;
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::fake_backend::FakeBackend;
+    use crate::git_backend::GitBackend;
+    use crate::git_helper::{GitCommandError, GitHelper};
+
+    pub(crate) fn select_item(app: &mut App, key: &str) {
+        let index = app.items.items.iter().position(|item| item.key == *key);
+        app.items.state.select(index);
+    }
+
+    pub(crate) fn save_commit(app: &mut App, msg: &str) {
+        app.textarea.insert_str(msg);
+        app.save_commit().unwrap();
+    }
+
+    /// Commits the candidates on a temporary branch like `--engine
+    /// porcelain`.
+    fn commit_on_temp_branch(
+        helper: &mut GitHelper,
+        commits: &[GitCommitCandidate],
+    ) -> Result<(), GitCommandError> {
+        helper.checkout_to_temp_branch()?;
+        helper.reset()?;
+        helper.commit(commits)?;
+        helper.restore_branch()
+    }
+
+    #[test]
+    fn test_plan_editor() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        let (lines, _, tip) = fake.commit_mixed_changes();
+
+        let mut helper = GitHelper::new(Box::new(fake.clone()), "HEAD~")?;
+        let mut app = App::new_with_diffs(helper.list_diffs()?).unwrap();
+
+        select_item(&mut app, "b.txt");
+        app.select();
+        save_commit(&mut app, "b");
+        select_item(&mut app, "dir");
+        app.goto_child();
+        select_item(&mut app, "a.txt");
+        app.goto_child();
+        app.items.state.select(Some(0));
+        app.select();
+        save_commit(&mut app, "first hunk");
+        select_item(&mut app, "c.txt");
+        app.select();
+        save_commit(&mut app, "c");
+
+        // Deleting a commit returns its hunk to the file navigator.
+        app.open_plan_editor();
+        app.next_commit();
+        app.delete_commit();
+        assert_eq!(app.tree.borrow().num_leaf_node, 2);
+
+        app.move_commit(false);
+        app.merge_commit();
+        let messages: Vec<&str> = app
+            .commits
+            .iter()
+            .map(|commit| commit.msg.as_str())
+            .collect();
+        assert_eq!(messages, ["c\n\nb"]);
+
+        app.edit_commit();
+        app.textarea = TextArea::new(vec!["c and b".to_owned()]);
+        app.save_commit().unwrap();
+        assert_eq!(app.commits[0].msg, "c and b");
+        assert!(matches!(app.current_screen, CurrentScreen::PlanEditor));
+
+        // The commits cannot be applied before every change is committed.
+        app.apply_commits();
+        assert!(!app.should_quit);
+        app.close_popup();
+        app.close_plan_editor();
+
+        select_item(&mut app, "dir");
+        app.select();
+        save_commit(&mut app, "a");
+        app.apply_commits();
+        assert!(app.finished);
+
+        commit_on_temp_branch(&mut helper, &app.commits)?;
+
+        assert_eq!(fake.messages("HEAD")?, vec!["a", "c and b", "init"]);
+        assert_eq!(fake.tree_of("HEAD")?, fake.tree_of(&tip)?);
+        assert_eq!(fake.read_file("HEAD~", "b.txt")?, None);
+        assert_eq!(fake.read_file("HEAD~", "dir/a.txt")?, Some(lines.concat()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_commit() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        fake.commit_files("init", &[("b", Some("b\n"))]);
+        let tip = fake.commit_files(
+            "change",
+            &[("dir/a", Some("a\n")), ("b", None), ("c", Some("c\n"))],
+        );
+
+        let mut helper = GitHelper::new(Box::new(fake.clone()), "HEAD~")?;
+        let mut app = App::new(helper.list()?).unwrap();
+
+        select_item(&mut app, "b");
+        app.select();
+        select_item(&mut app, "c");
+        app.select();
+        save_commit(&mut app, "b and c");
+        assert_eq!(app.tree.borrow().num_leaf_node, 1);
+
+        // The selection is restored with the message in the editor.
+        app.undo_commit();
+        assert!(app.commits.is_empty());
+        assert!(matches!(
+            app.current_screen,
+            CurrentScreen::CommitMessageEditor
+        ));
+        assert_eq!(app.textarea.lines(), ["b and c"]);
+        assert_eq!(app.get_stats(), (3, 2));
+
+        app.close_editor();
+        select_item(&mut app, "c");
+        app.select();
+        app.open_editor();
+        assert_eq!(app.textarea.lines(), ["b and c"]);
+        app.textarea = TextArea::new(vec!["b".to_owned()]);
+        app.save_commit().unwrap();
+
+        select_item(&mut app, "dir");
+        app.select();
+        save_commit(&mut app, "a");
+        select_item(&mut app, "c");
+        app.select();
+        save_commit(&mut app, "c");
+        app.apply_commits();
+        assert!(app.finished);
+
+        // The last commit is still undone once the commits are rearranged.
+        app.open_plan_editor();
+        app.move_commit(true);
+        app.close_plan_editor();
+        app.undo_commit();
+        assert_eq!(app.commits.len(), 2);
+        assert_eq!(app.textarea.lines(), ["c"]);
+        assert_eq!(app.get_stats(), (1, 1));
+        app.save_commit().unwrap();
+
+        // And once a commit is deleted.
+        app.open_plan_editor();
+        app.plan.select(Some(0));
+        app.delete_commit();
+        app.undo_commit();
+        assert_eq!(app.commits.len(), 1);
+        assert_eq!(app.textarea.lines(), ["c"]);
+        assert_eq!(app.get_stats(), (2, 1));
+        app.save_commit().unwrap();
+        select_item(&mut app, "dir");
+        app.select();
+        save_commit(&mut app, "a");
+
+        commit_on_temp_branch(&mut helper, &app.commits)?;
+
+        assert_eq!(fake.messages("HEAD")?, vec!["a", "c", "b", "init"]);
+        assert_eq!(fake.tree_of("HEAD")?, fake.tree_of(&tip)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,1027 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::rc::Rc;
+
+use crate::git_backend::{
+    ChangeStatus, CommitInfo, FileChange, GitBackend, Signature, WorktreeStatus,
+};
+use crate::git_helper::GitCommandError;
+use crate::patch::{parse_diff, PatchError};
+
+/// Number of context lines around the changes of a hunk, as in `git diff`.
+const CONTEXT: usize = 3;
+
+/// File contents by path.
+type FakeTree = BTreeMap<PathBuf, Vec<u8>>;
+
+struct FakeCommit {
+    tree: String,
+    parents: Vec<String>,
+    author: Signature,
+    message: Vec<u8>,
+}
+
+enum FakeHead {
+    Branch(String),
+    Detached(String),
+}
+
+struct FakeRepo {
+    /// Commits by id. Ids are `c1`, `c2`, ... in creation order.
+    commits: HashMap<String, FakeCommit>,
+    /// Trees by id. Equal trees share the same id.
+    trees: Vec<FakeTree>,
+    branches: HashMap<String, String>,
+    /// References other than branches by full name.
+    refs: BTreeMap<String, String>,
+    head: FakeHead,
+    index: FakeTree,
+    /// Uncommitted changes reported as they are, since there is no working
+    /// tree to compute them from.
+    worktree_status: WorktreeStatus,
+    stashes: Vec<WorktreeStatus>,
+    /// Returns the output of the rejection if a message is rejected, like a
+    /// failing `commit-msg` hook.
+    commit_msg_hook: Option<fn(&str) -> Option<String>>,
+    /// Operations which change the repository, in the form of Git commands.
+    operations: Vec<String>,
+}
+
+/// In-memory [`GitBackend`] modelling a commit graph for tests.
+///
+/// Clones share the same repository, so a test can keep a clone to inspect
+/// the result after handing the backend to a
+/// [`GitHelper`](crate::git_helper::GitHelper). There is no working tree, since
+/// no operation of the helper touches it.
+#[derive(Clone)]
+pub struct FakeBackend {
+    repo: Rc<RefCell<FakeRepo>>,
+}
+
+impl Default for FakeBackend {
+    fn default() -> Self {
+        FakeBackend::new()
+    }
+}
+
+impl FakeBackend {
+    /// Constructs an empty repository whose HEAD is the unborn `main` branch.
+    pub fn new() -> FakeBackend {
+        FakeBackend {
+            repo: Rc::new(RefCell::new(FakeRepo {
+                commits: HashMap::new(),
+                trees: vec![],
+                branches: HashMap::new(),
+                refs: BTreeMap::new(),
+                head: FakeHead::Branch("main".to_owned()),
+                index: FakeTree::new(),
+                worktree_status: WorktreeStatus::default(),
+                stashes: vec![],
+                commit_msg_hook: None,
+                operations: vec![],
+            })),
+        }
+    }
+
+    /// The signature of the commits made by the backend.
+    pub fn signature() -> Signature {
+        Signature {
+            name: "Anonymous".to_owned(),
+            email: "anonymous@anonymous.com".to_owned(),
+            date: "0 +0000".to_owned(),
+        }
+    }
+
+    /// Commits the files on top of HEAD and returns the new commit id.
+    ///
+    /// A file is deleted if its content is `None`. It is not recorded in
+    /// [`FakeBackend::operations`].
+    pub fn commit_files(
+        &self,
+        msg: &str,
+        files: &[(&str, Option<&str>)],
+    ) -> String {
+        self.commit_files_as(FakeBackend::signature(), msg, files)
+    }
+
+    /// Commits `dir/a.txt` of 20 lines and `b.txt`, then a commit changing
+    /// two distant lines of `dir/a.txt`, deleting `b.txt` and adding `c.txt`.
+    /// Returns the original lines of `dir/a.txt` and the ids of the commits.
+    pub fn commit_mixed_changes(&self) -> (Vec<String>, String, String) {
+        let lines: Vec<String> = (0..20).map(|i| format!("{}\n", i)).collect();
+        let mut changed = lines.clone();
+        changed[1] = "first\n".to_owned();
+        changed[18] = "second\n".to_owned();
+
+        let init = self.commit_files(
+            "init",
+            &[("dir/a.txt", Some(&lines.concat())), ("b.txt", Some("b\n"))],
+        );
+        let tip = self.commit_files(
+            "change",
+            &[
+                ("dir/a.txt", Some(&changed.concat())),
+                ("b.txt", None),
+                ("c.txt", Some("c\n")),
+            ],
+        );
+        (lines, init, tip)
+    }
+
+    /// Commits the files like [`FakeBackend::commit_files`] with the author.
+    pub fn commit_files_as(
+        &self,
+        author: Signature,
+        msg: &str,
+        files: &[(&str, Option<&str>)],
+    ) -> String {
+        self.commit_files_with_parents(author, vec![], msg, files)
+    }
+
+    /// Commits the files like [`FakeBackend::commit_files`] as a merge of
+    /// HEAD and the other commit.
+    pub fn merge_files(
+        &self,
+        other: &str,
+        msg: &str,
+        files: &[(&str, Option<&str>)],
+    ) -> String {
+        let other = vec![other.to_owned()];
+        self.commit_files_with_parents(
+            FakeBackend::signature(),
+            other,
+            msg,
+            files,
+        )
+    }
+
+    fn commit_files_with_parents(
+        &self,
+        author: Signature,
+        other_parents: Vec<String>,
+        msg: &str,
+        files: &[(&str, Option<&str>)],
+    ) -> String {
+        let mut repo = self.repo.borrow_mut();
+        let mut tree = match repo.head_commit() {
+            Some(id) => repo.tree(&repo.commits[&id].tree).clone(),
+            None => FakeTree::new(),
+        };
+        for (path, content) in files {
+            match content {
+                Some(content) => tree
+                    .insert(PathBuf::from(path), content.as_bytes().to_vec()),
+                None => tree.remove(Path::new(path)),
+            };
+        }
+
+        repo.index = tree.clone();
+        let tree = repo.intern_tree(tree);
+        let parents = repo
+            .head_commit()
+            .into_iter()
+            .chain(other_parents)
+            .collect();
+        let id = repo.add_commit(tree, parents, author, msg.as_bytes());
+        repo.move_head(&id);
+        id
+    }
+
+    /// Returns the messages of the commits from `rev` to the root following
+    /// the first parents.
+    pub fn messages(&self, rev: &str) -> Result<Vec<String>, GitCommandError> {
+        let repo = self.repo.borrow();
+        let mut next = Some(repo.resolve(rev)?);
+        let mut messages = vec![];
+
+        while let Some(id) = next {
+            let commit = &repo.commits[&id];
+            messages
+                .push(String::from_utf8_lossy(&commit.message).into_owned());
+            next = commit.parents.first().cloned();
+        }
+        Ok(messages)
+    }
+
+    /// Returns the content of the file at `rev`.
+    pub fn read_file(
+        &self,
+        rev: &str,
+        path: &str,
+    ) -> Result<Option<String>, GitCommandError> {
+        let repo = self.repo.borrow();
+        let id = repo.resolve(rev)?;
+        let tree = repo.tree(&repo.commits[&id].tree);
+
+        Ok(tree
+            .get(Path::new(path))
+            .map(|content| String::from_utf8_lossy(content).into_owned()))
+    }
+
+    /// Returns the paths in the index which differ from the tree of HEAD.
+    pub fn staged_paths(&self) -> Vec<PathBuf> {
+        let repo = self.repo.borrow();
+        let head_tree = match repo.head_commit() {
+            Some(id) => repo.tree(&repo.commits[&id].tree).clone(),
+            None => FakeTree::new(),
+        };
+        changed_paths(&head_tree, &repo.index)
+    }
+
+    /// Sets the uncommitted changes reported by the backend.
+    pub fn set_worktree_status(&self, status: WorktreeStatus) {
+        self.repo.borrow_mut().worktree_status = status;
+    }
+
+    /// Sets the hook which may reject the messages of
+    /// [`GitBackend::commit_index`].
+    pub fn set_commit_msg_hook(&self, hook: fn(&str) -> Option<String>) {
+        self.repo.borrow_mut().commit_msg_hook = Some(hook);
+    }
+
+    /// Returns the operations which changed the repository so far.
+    pub fn operations(&self) -> Vec<String> {
+        self.repo.borrow().operations.clone()
+    }
+
+    fn record(&self, operation: String) {
+        self.repo.borrow_mut().operations.push(operation);
+    }
+}
+
+impl FakeRepo {
+    fn tree(&self, id: &str) -> &FakeTree {
+        let index: usize = id[1..].parse().expect("Invalid fake tree id");
+        &self.trees[index - 1]
+    }
+
+    fn intern_tree(&mut self, tree: FakeTree) -> String {
+        let index = match self.trees.iter().position(|t| *t == tree) {
+            Some(index) => index,
+            None => {
+                self.trees.push(tree);
+                self.trees.len() - 1
+            }
+        };
+        format!("t{}", index + 1)
+    }
+
+    fn add_commit(
+        &mut self,
+        tree: String,
+        parents: Vec<String>,
+        author: Signature,
+        message: &[u8],
+    ) -> String {
+        let id = format!("c{}", self.commits.len() + 1);
+        self.commits.insert(
+            id.clone(),
+            FakeCommit {
+                tree,
+                parents,
+                author,
+                message: message.to_vec(),
+            },
+        );
+        id
+    }
+
+    fn head_commit(&self) -> Option<String> {
+        match &self.head {
+            FakeHead::Branch(name) => self.branches.get(name).cloned(),
+            FakeHead::Detached(id) => Some(id.clone()),
+        }
+    }
+
+    /// Moves HEAD and the current branch to the commit.
+    fn move_head(&mut self, id: &str) {
+        match &self.head {
+            FakeHead::Branch(name) => {
+                self.branches.insert(name.clone(), id.to_owned());
+            }
+            FakeHead::Detached(_) => {
+                self.head = FakeHead::Detached(id.to_owned());
+            }
+        }
+    }
+
+    fn invalid(rev: &str) -> GitCommandError {
+        GitCommandError::InvalidObjectError { id: rev.to_owned() }
+    }
+
+    /// Resolves `HEAD`, a branch name or a commit id followed by any number of
+    /// `~<n>` and `^` suffixes.
+    fn resolve(&self, rev: &str) -> Result<String, GitCommandError> {
+        let end = rev.find(['~', '^']).unwrap_or(rev.len());
+        let (name, mut suffixes) = rev.split_at(end);
+
+        let mut id = if name == "HEAD" {
+            self.head_commit()
+        } else if self.commits.contains_key(name) {
+            Some(name.to_owned())
+        } else {
+            self.branches.get(name).cloned()
+        }
+        .ok_or_else(|| FakeRepo::invalid(rev))?;
+
+        while !suffixes.is_empty() {
+            let digits = suffixes[1..]
+                .find(|c: char| !c.is_ascii_digit())
+                .map_or(suffixes.len(), |pos| pos + 1);
+            let count = match &suffixes[1..digits] {
+                "" => 1,
+                count => count.parse().map_err(|_| FakeRepo::invalid(rev))?,
+            };
+            let count = if suffixes.starts_with('^') {
+                count.min(1)
+            } else {
+                count
+            };
+            for _ in 0..count {
+                id = self.commits[&id]
+                    .parents
+                    .first()
+                    .cloned()
+                    .ok_or_else(|| FakeRepo::invalid(rev))?;
+            }
+            suffixes = &suffixes[digits..];
+        }
+        Ok(id)
+    }
+
+    /// Lists the commit and its ancestors in breadth-first order.
+    fn ancestors(&self, id: &str) -> Vec<String> {
+        let mut visited = BTreeSet::new();
+        let mut queue = VecDeque::from([id.to_owned()]);
+        let mut ancestors = vec![];
+
+        while let Some(id) = queue.pop_front() {
+            if visited.insert(id.clone()) {
+                queue.extend(self.commits[&id].parents.iter().cloned());
+                ancestors.push(id);
+            }
+        }
+        ancestors
+    }
+}
+
+impl GitBackend for FakeBackend {
+    fn resolve_commit(&self, rev: &str) -> Result<String, GitCommandError> {
+        self.repo.borrow().resolve(rev)
+    }
+
+    fn merge_base(
+        &self,
+        left: &str,
+        right: &str,
+    ) -> Result<String, GitCommandError> {
+        let repo = self.repo.borrow();
+        let left = repo.ancestors(&repo.resolve(left)?);
+        repo.ancestors(&repo.resolve(right)?)
+            .into_iter()
+            .find(|id| left.contains(id))
+            .ok_or_else(|| FakeRepo::invalid(right))
+    }
+
+    fn is_ancestor(
+        &self,
+        ancestor: &str,
+        descendant: &str,
+    ) -> Result<bool, GitCommandError> {
+        let repo = self.repo.borrow();
+        Ok(repo
+            .ancestors(&repo.resolve(descendant)?)
+            .contains(&repo.resolve(ancestor)?))
+    }
+
+    fn worktree_status(&self) -> Result<WorktreeStatus, GitCommandError> {
+        Ok(self.repo.borrow().worktree_status.clone())
+    }
+
+    fn stash_push(&self) -> Result<String, GitCommandError> {
+        self.record("stash push".to_owned());
+        let mut repo = self.repo.borrow_mut();
+        let status = std::mem::take(&mut repo.worktree_status);
+        repo.stashes.push(status);
+        Ok(format!("s{}", repo.stashes.len()))
+    }
+
+    fn stash_pop(&self) -> Result<(), GitCommandError> {
+        self.record("stash pop".to_owned());
+        let mut repo = self.repo.borrow_mut();
+        // Like `git stash pop`, the stash is kept if it would overwrite
+        // uncommitted changes.
+        if !repo.worktree_status.is_clean() {
+            return Err(GitCommandError::GitError {
+                status: ExitStatus::default(),
+                stderr: "error: could not restore untracked files from stash"
+                    .to_owned(),
+            });
+        }
+        let status = repo
+            .stashes
+            .pop()
+            .ok_or(GitCommandError::InvalidFunctionCallError)?;
+        repo.worktree_status = status;
+        Ok(())
+    }
+
+    fn current_branch_name(&self) -> Result<Option<String>, GitCommandError> {
+        match &self.repo.borrow().head {
+            FakeHead::Branch(name) => Ok(Some(name.clone())),
+            FakeHead::Detached(_) => Ok(None),
+        }
+    }
+
+    /// The repository is in memory, so there is no Git directory.
+    fn git_dir(&self) -> Result<PathBuf, GitCommandError> {
+        Err(GitCommandError::InvalidFunctionCallError)
+    }
+
+    fn diff_files(
+        &self,
+        base: &str,
+        head: &str,
+    ) -> Result<Vec<FileChange>, GitCommandError> {
+        let repo = self.repo.borrow();
+        let base = repo.tree(&repo.commits[&repo.resolve(base)?].tree);
+        let head = repo.tree(&repo.commits[&repo.resolve(head)?].tree);
+        Ok(file_changes(base, head))
+    }
+
+    fn diff_patch(
+        &self,
+        base: &str,
+        head: &str,
+    ) -> Result<Vec<u8>, GitCommandError> {
+        let repo = self.repo.borrow();
+        let base = repo.tree(&repo.commits[&repo.resolve(base)?].tree);
+        let head = repo.tree(&repo.commits[&repo.resolve(head)?].tree);
+
+        let mut patch = vec![];
+        for change in file_changes(base, head) {
+            let path = &change.path;
+            match &change.old_path {
+                Some(old_path) => {
+                    let (old, new) =
+                        (old_path.to_string_lossy(), path.to_string_lossy());
+                    patch.extend(
+                        format!(
+                            "diff --git a/{} b/{}\nsimilarity index 100%\n\
+                             rename from {}\nrename to {}\n",
+                            old, new, old, new
+                        )
+                        .into_bytes(),
+                    );
+                }
+                None => patch.extend(diff_file(
+                    path,
+                    base.get(path),
+                    head.get(path),
+                )),
+            }
+        }
+        Ok(patch)
+    }
+
+    fn create_and_switch_branch(
+        &self,
+        name: &str,
+    ) -> Result<(), GitCommandError> {
+        self.record(format!("checkout -b {}", name));
+        let mut repo = self.repo.borrow_mut();
+        if repo.branches.contains_key(name) {
+            return Err(FakeRepo::invalid(name));
+        }
+        let id = repo
+            .head_commit()
+            .ok_or_else(|| FakeRepo::invalid("HEAD"))?;
+        repo.branches.insert(name.to_owned(), id);
+        repo.head = FakeHead::Branch(name.to_owned());
+        Ok(())
+    }
+
+    fn reset_and_switch_branch(
+        &self,
+        name: &str,
+    ) -> Result<(), GitCommandError> {
+        self.record(format!("checkout -B {}", name));
+        let mut repo = self.repo.borrow_mut();
+        let id = repo
+            .head_commit()
+            .ok_or_else(|| FakeRepo::invalid("HEAD"))?;
+        repo.branches.insert(name.to_owned(), id);
+        repo.head = FakeHead::Branch(name.to_owned());
+        Ok(())
+    }
+
+    fn switch_branch(&self, name: &str) -> Result<(), GitCommandError> {
+        self.record(format!("checkout {}", name));
+        let mut repo = self.repo.borrow_mut();
+        if !repo.branches.contains_key(name) {
+            return Err(FakeRepo::invalid(name));
+        }
+        repo.head = FakeHead::Branch(name.to_owned());
+        Ok(())
+    }
+
+    fn detach_head(&self) -> Result<(), GitCommandError> {
+        self.record("checkout --detach".to_owned());
+        let mut repo = self.repo.borrow_mut();
+        let id = repo
+            .head_commit()
+            .ok_or_else(|| FakeRepo::invalid("HEAD"))?;
+        repo.head = FakeHead::Detached(id);
+        Ok(())
+    }
+
+    fn delete_branch(&self, name: &str) -> Result<(), GitCommandError> {
+        self.record(format!("branch -D {}", name));
+        let mut repo = self.repo.borrow_mut();
+        if matches!(&repo.head, FakeHead::Branch(head) if head == name) {
+            return Err(FakeRepo::invalid(name));
+        }
+        repo.branches
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| FakeRepo::invalid(name))
+    }
+
+    fn reset(&self, commit: &str) -> Result<(), GitCommandError> {
+        self.record(format!("reset {}", commit));
+        let mut repo = self.repo.borrow_mut();
+        let id = repo.resolve(commit)?;
+        repo.index = repo.tree(&repo.commits[&id].tree).clone();
+        repo.move_head(&id);
+        Ok(())
+    }
+
+    fn update_ref(
+        &self,
+        name: &str,
+        commit: &str,
+        old: Option<&str>,
+    ) -> Result<(), GitCommandError> {
+        self.record(format!("update-ref {} {}", name, commit));
+        let mut repo = self.repo.borrow_mut();
+        let id = repo.resolve(commit)?;
+        let branch = name.strip_prefix("refs/heads/");
+
+        let current = match branch {
+            _ if name == "HEAD" => repo.head_commit(),
+            Some(branch) => repo.branches.get(branch).cloned(),
+            None => repo.refs.get(name).cloned(),
+        };
+        if old.is_some() && current.as_deref() != old {
+            return Err(FakeRepo::invalid(name));
+        }
+
+        match branch {
+            _ if name == "HEAD" => repo.head = FakeHead::Detached(id),
+            Some(branch) => {
+                repo.branches.insert(branch.to_owned(), id);
+            }
+            None => {
+                repo.refs.insert(name.to_owned(), id);
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<(), GitCommandError> {
+        self.record(format!("update-ref -d {}", name));
+        self.repo
+            .borrow_mut()
+            .refs
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| FakeRepo::invalid(name))
+    }
+
+    fn list_refs(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, GitCommandError> {
+        Ok(self
+            .repo
+            .borrow()
+            .refs
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, id)| (name.clone(), id.clone()))
+            .collect())
+    }
+
+    fn apply_to_index(&self, patch: &[u8]) -> Result<(), GitCommandError> {
+        self.record("apply --cached".to_owned());
+        let mut repo = self.repo.borrow_mut();
+        repo.index = apply_patch(&repo.index, patch)?;
+        Ok(())
+    }
+
+    fn apply_to_tree(
+        &self,
+        tree: &str,
+        patch: &[u8],
+    ) -> Result<String, GitCommandError> {
+        self.record(format!("apply --cached {}", tree));
+        let mut repo = self.repo.borrow_mut();
+        let tree = apply_patch(repo.tree(tree), patch)?;
+        Ok(repo.intern_tree(tree))
+    }
+
+    fn signature(&self) -> Result<Signature, GitCommandError> {
+        Ok(FakeBackend::signature())
+    }
+
+    fn commit_index(
+        &self,
+        msg: &str,
+        author: Option<&Signature>,
+    ) -> Result<String, GitCommandError> {
+        self.record(format!("commit -m {}", msg));
+        let mut repo = self.repo.borrow_mut();
+        if let Some(stderr) = repo.commit_msg_hook.and_then(|hook| hook(msg)) {
+            return Err(GitCommandError::GitError {
+                status: ExitStatus::default(),
+                stderr,
+            });
+        }
+        let index = repo.index.clone();
+        let tree = repo.intern_tree(index);
+        let parents = repo.head_commit().into_iter().collect();
+        let author = author.cloned().unwrap_or_else(FakeBackend::signature);
+        let id = repo.add_commit(tree, parents, author, msg.as_bytes());
+        repo.move_head(&id);
+        Ok(id)
+    }
+
+    fn tree_of(&self, commit: &str) -> Result<String, GitCommandError> {
+        let repo = self.repo.borrow();
+        Ok(repo.commits[&repo.resolve(commit)?].tree.clone())
+    }
+
+    fn read_blob(
+        &self,
+        commit: &str,
+        path: &Path,
+    ) -> Result<Option<Vec<u8>>, GitCommandError> {
+        let repo = self.repo.borrow();
+        let tree = repo.tree(&repo.commits[&repo.resolve(commit)?].tree);
+        Ok(tree.get(path).cloned())
+    }
+
+    fn rev_list(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<String>, GitCommandError> {
+        let repo = self.repo.borrow();
+        let excluded = repo.ancestors(&repo.resolve(from)?);
+        let mut ids: Vec<String> = repo
+            .ancestors(&repo.resolve(to)?)
+            .into_iter()
+            .filter(|id| !excluded.contains(id))
+            .collect();
+        // Ids increase in creation order, so parents come first.
+        ids.sort_by_key(|id| id[1..].parse::<usize>().unwrap_or_default());
+        Ok(ids)
+    }
+
+    fn list_merges(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<String>, GitCommandError> {
+        let ids = self.rev_list(from, to)?;
+        let repo = self.repo.borrow();
+        Ok(ids
+            .into_iter()
+            .filter(|id| repo.commits[id].parents.len() > 1)
+            .collect())
+    }
+
+    fn read_commit(&self, commit: &str) -> Result<CommitInfo, GitCommandError> {
+        let repo = self.repo.borrow();
+        let commit = &repo.commits[&repo.resolve(commit)?];
+
+        Ok(CommitInfo {
+            tree: commit.tree.clone(),
+            parents: commit.parents.clone(),
+            author: commit.author.clone(),
+            message: commit.message.clone(),
+            encoding: None,
+            signed: false,
+        })
+    }
+
+    fn commit_tree(
+        &self,
+        tree: &str,
+        parents: &[String],
+        author: &Signature,
+        message: &[u8],
+        _encoding: Option<&str>,
+    ) -> Result<String, GitCommandError> {
+        self.record(format!("commit-tree {}", tree));
+        let mut repo = self.repo.borrow_mut();
+        Ok(repo.add_commit(
+            tree.to_owned(),
+            parents.to_vec(),
+            author.clone(),
+            message,
+        ))
+    }
+}
+
+/// Applies a patch to a tree, like `git apply --cached` does to the index.
+fn apply_patch(
+    tree: &FakeTree,
+    patch: &[u8],
+) -> Result<FakeTree, GitCommandError> {
+    let mut index = tree.clone();
+
+    for diff in parse_diff(patch)? {
+        let is_deleted = diff
+            .header
+            .iter()
+            .any(|line| line.starts_with(b"deleted file mode"));
+        let source = diff.old_path.clone().unwrap_or(diff.path.clone());
+        let old = match index.get(&source) {
+            Some(content) => content.clone(),
+            None if diff
+                .header
+                .iter()
+                .any(|line| line.starts_with(b"new file mode")) =>
+            {
+                vec![]
+            }
+            None => {
+                return Err(PatchError::FileNotFoundError {
+                    path: diff.path.clone(),
+                }
+                .into())
+            }
+        };
+        if diff
+            .header
+            .iter()
+            .any(|line| line.starts_with(b"rename from "))
+        {
+            index.remove(&source);
+        }
+        let old: Vec<&[u8]> = old.split_inclusive(|c| *c == b'\n').collect();
+
+        let mut new: Vec<Vec<u8>> = vec![];
+        let mut cursor = 0;
+        for (index, hunk) in diff.hunks.iter().enumerate() {
+            let mismatch = || PatchError::HunkNotFoundError {
+                path: diff.path.clone(),
+                index,
+            };
+            let (start, count) =
+                old_range(&hunk.header).ok_or_else(mismatch)?;
+            let begin = if count == 0 { start } else { start - 1 };
+            if begin < cursor || begin > old.len() {
+                return Err(mismatch().into());
+            }
+            new.extend(old[cursor..begin].iter().map(|line| line.to_vec()));
+            cursor = begin;
+
+            for (kind, line) in hunk_lines(&hunk.lines) {
+                match kind {
+                    b'+' => new.push(line),
+                    b' ' | b'-' => {
+                        if old.get(cursor) != Some(&line.as_slice()) {
+                            return Err(mismatch().into());
+                        }
+                        if kind == b' ' {
+                            new.push(line);
+                        }
+                        cursor += 1;
+                    }
+                    _ => return Err(mismatch().into()),
+                }
+            }
+        }
+        new.extend(old[cursor..].iter().map(|line| line.to_vec()));
+
+        if is_deleted && new.is_empty() {
+            index.remove(&diff.path);
+        } else {
+            index.insert(diff.path.clone(), new.concat());
+        }
+    }
+
+    Ok(index)
+}
+
+fn changed_paths(base: &FakeTree, head: &FakeTree) -> Vec<PathBuf> {
+    base.keys()
+        .chain(head.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|path| base.get(*path) != head.get(*path))
+        .cloned()
+        .collect()
+}
+
+/// Lists the changed files, where a file removed and added elsewhere with the
+/// same content is a rename. Copies are not detected.
+fn file_changes(base: &FakeTree, head: &FakeTree) -> Vec<FileChange> {
+    let paths = changed_paths(base, head);
+    let mut renames: BTreeMap<&PathBuf, &PathBuf> = BTreeMap::new();
+
+    for path in paths.iter().filter(|path| !base.contains_key(*path)) {
+        let old_path = paths.iter().find(|old_path| {
+            !head.contains_key(*old_path)
+                && base.get(*old_path) == head.get(path)
+                && !renames.values().any(|renamed| renamed == old_path)
+        });
+        if let Some(old_path) = old_path {
+            renames.insert(path, old_path);
+        }
+    }
+
+    paths
+        .iter()
+        .filter(|path| !renames.values().any(|old_path| old_path == path))
+        .map(|path| {
+            let old_path =
+                renames.get(path).map(|old_path| (*old_path).clone());
+            let status =
+                match (base.contains_key(path), head.contains_key(path)) {
+                    _ if old_path.is_some() => ChangeStatus::Renamed,
+                    (false, _) => ChangeStatus::Added,
+                    (_, false) => ChangeStatus::Deleted,
+                    _ => ChangeStatus::Modified,
+                };
+            FileChange {
+                status,
+                path: path.clone(),
+                old_path,
+            }
+        })
+        .collect()
+}
+
+/// Parses the old range `(a, b)` of `@@ -a,b +c,d @@`.
+fn old_range(header: &[u8]) -> Option<(usize, usize)> {
+    let header = std::str::from_utf8(header).ok()?.strip_prefix("@@ -")?;
+    let (old, _) = header.split_once(' ')?;
+    match old.split_once(',') {
+        Some((start, count)) => {
+            Some((start.parse().ok()?, count.parse().ok()?))
+        }
+        None => Some((old.parse().ok()?, 1)),
+    }
+}
+
+/// Splits the body lines of a hunk into their kinds and contents, where
+/// `\ No newline at end of file` strips the newline of the previous line.
+fn hunk_lines(lines: &[Vec<u8>]) -> Vec<(u8, Vec<u8>)> {
+    let mut result: Vec<(u8, Vec<u8>)> = vec![];
+    for line in lines {
+        match line.split_first() {
+            Some((b'\\', _)) => {
+                if let Some((_, last)) = result.last_mut() {
+                    last.pop();
+                }
+            }
+            Some((kind, content)) => result.push((*kind, content.to_vec())),
+            None => {}
+        }
+    }
+    result
+}
+
+/// Renders the diff of a file in the format of `git diff-tree -p`.
+fn diff_file(
+    path: &Path,
+    old: Option<&Vec<u8>>,
+    new: Option<&Vec<u8>>,
+) -> Vec<u8> {
+    let name = path.to_string_lossy();
+    let mut patch = format!("diff --git a/{} b/{}\n", name, name).into_bytes();
+
+    match (old, new) {
+        (None, _) => patch.extend(b"new file mode 100644\n"),
+        (_, None) => patch.extend(b"deleted file mode 100644\n"),
+        _ => {}
+    }
+
+    let old: Vec<&[u8]> = old
+        .map(|content| content.split_inclusive(|c| *c == b'\n').collect())
+        .unwrap_or_default();
+    let new: Vec<&[u8]> = new
+        .map(|content| content.split_inclusive(|c| *c == b'\n').collect())
+        .unwrap_or_default();
+    if old.is_empty() && new.is_empty() {
+        return patch;
+    }
+
+    let (minus, plus) = match (old.is_empty(), new.is_empty()) {
+        (true, _) => ("/dev/null".to_owned(), format!("b/{}", name)),
+        (_, true) => (format!("a/{}", name), "/dev/null".to_owned()),
+        _ => (format!("a/{}", name), format!("b/{}", name)),
+    };
+    patch.extend(format!("--- {}\n+++ {}\n", minus, plus).into_bytes());
+
+    let ops = diff_lines(&old, &new);
+    let changes: Vec<usize> = (0..ops.len())
+        .filter(|i| !matches!(ops[*i], (Some(_), Some(_))))
+        .collect();
+
+    let mut groups: Vec<(usize, usize)> = vec![];
+    for change in changes {
+        match groups.last_mut() {
+            Some((_, last)) if change - *last <= 2 * CONTEXT => *last = change,
+            _ => groups.push((change, change)),
+        }
+    }
+
+    for (first, last) in groups {
+        let begin = first.saturating_sub(CONTEXT);
+        let end = (last + CONTEXT + 1).min(ops.len());
+        let hunk = &ops[begin..end];
+
+        // The positions before the hunk in the old and new files.
+        let old_pos = ops[..begin].iter().filter(|op| op.0.is_some()).count();
+        let new_pos = ops[..begin].iter().filter(|op| op.1.is_some()).count();
+        let old_count = hunk.iter().filter(|op| op.0.is_some()).count();
+        let new_count = hunk.iter().filter(|op| op.1.is_some()).count();
+        let start = |pos: usize, count: usize| {
+            if count == 0 {
+                pos
+            } else {
+                pos + 1
+            }
+        };
+        patch.extend(
+            format!(
+                "@@ -{},{} +{},{} @@\n",
+                start(old_pos, old_count),
+                old_count,
+                start(new_pos, new_count),
+                new_count
+            )
+            .into_bytes(),
+        );
+
+        for op in hunk {
+            let (kind, line) = match *op {
+                (Some(i), Some(_)) => (b' ', old[i]),
+                (Some(i), None) => (b'-', old[i]),
+                (None, Some(j)) => (b'+', new[j]),
+                (None, None) => unreachable!(),
+            };
+            patch.push(kind);
+            patch.extend(line);
+            if !line.ends_with(b"\n") {
+                patch.extend(b"\n\\ No newline at end of file\n");
+            }
+        }
+    }
+
+    patch
+}
+
+/// Computes the longest common subsequence of the lines and returns the
+/// pairs of old and new line indices, where `None` means a removed or an added
+/// line.
+fn diff_lines(
+    old: &[&[u8]],
+    new: &[&[u8]],
+) -> Vec<(Option<usize>, Option<usize>)> {
+    let mut lengths = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut ops = vec![];
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((Some(i), Some(j)));
+            i += 1;
+            j += 1;
+        } else if j == new.len()
+            || (i < old.len() && lengths[i + 1][j] >= lengths[i][j + 1])
+        {
+            ops.push((Some(i), None));
+            i += 1;
+        } else {
+            ops.push((None, Some(j)));
+            j += 1;
+        }
+    }
+    ops
+}
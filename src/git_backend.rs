@@ -0,0 +1,909 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use serde::{Deserialize, Serialize};
+use tempfile::{tempdir, NamedTempFile};
+
+use crate::git_helper::GitCommandError;
+
+/// Author or committer of a commit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    /// Date in the Git internal format, e.g. `1700000000 +0900`.
+    pub date: String,
+}
+
+/// How a file is changed, as in `git diff --raw`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChangeStatus {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+    /// The file is changed to or from a symbolic link or a submodule.
+    TypeChanged,
+    /// Only the file mode is changed, e.g. by `chmod +x`.
+    ModeChanged,
+}
+
+impl ChangeStatus {
+    /// Parses the status letter of `git diff --raw` or `--name-status`.
+    pub fn from_letter(letter: char) -> Option<ChangeStatus> {
+        match letter {
+            'A' => Some(ChangeStatus::Added),
+            'M' => Some(ChangeStatus::Modified),
+            'D' => Some(ChangeStatus::Deleted),
+            'R' => Some(ChangeStatus::Renamed),
+            'T' => Some(ChangeStatus::TypeChanged),
+            _ => None,
+        }
+    }
+
+    /// Returns the letter shown for the status. Git has no letter for a mode
+    /// change, so it is shown as `P` for permissions.
+    pub fn letter(&self) -> char {
+        match self {
+            ChangeStatus::Added => 'A',
+            ChangeStatus::Modified => 'M',
+            ChangeStatus::Deleted => 'D',
+            ChangeStatus::Renamed => 'R',
+            ChangeStatus::TypeChanged => 'T',
+            ChangeStatus::ModeChanged => 'P',
+        }
+    }
+}
+
+/// A file changed between two commits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileChange {
+    pub status: ChangeStatus,
+    pub path: PathBuf,
+    /// The source of the file if it is renamed.
+    pub old_path: Option<PathBuf>,
+}
+
+/// Uncommitted changes in the index and the working tree.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorktreeStatus {
+    pub staged: Vec<PathBuf>,
+    pub unstaged: Vec<PathBuf>,
+    pub untracked: Vec<PathBuf>,
+}
+
+impl WorktreeStatus {
+    pub fn is_clean(&self) -> bool {
+        self.staged.is_empty()
+            && self.unstaged.is_empty()
+            && self.untracked.is_empty()
+    }
+}
+
+impl fmt::Display for WorktreeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (title, paths) in [
+            ("Staged", &self.staged),
+            ("Unstaged", &self.unstaged),
+            ("Untracked", &self.untracked),
+        ] {
+            if !paths.is_empty() {
+                writeln!(f, "{}:", title)?;
+                for path in paths {
+                    writeln!(f, "  {}", path.display())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The parts of a commit object needed to recreate it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitInfo {
+    pub tree: String,
+    pub parents: Vec<String>,
+    pub author: Signature,
+    pub message: Vec<u8>,
+    /// The `encoding` header of the message, e.g. `ISO-8859-1`, if it is not
+    /// UTF-8.
+    pub encoding: Option<String>,
+    /// Whether the commit has a GPG or SSH signature.
+    pub signed: bool,
+}
+
+/// Options passed to `git commit` and `git commit-tree`.
+#[derive(Debug, Clone, Default)]
+pub struct CommitOptions {
+    /// Skips the `pre-commit` and `commit-msg` hooks, like `--no-verify`.
+    pub no_verify: bool,
+    /// Signs the commits with the key, or the default key if it is empty,
+    /// like `--gpg-sign[=<keyid>]`.
+    pub gpg_sign: Option<String>,
+    /// Does not sign the commits even if `commit.gpgSign` is set, like
+    /// `--no-gpg-sign`.
+    pub no_gpg_sign: bool,
+}
+
+impl CommitOptions {
+    /// Returns the signing arguments shared by `git commit` and
+    /// `git commit-tree`.
+    pub(crate) fn signing_args(&self) -> Vec<String> {
+        match &self.gpg_sign {
+            Some(key) if key.is_empty() => vec!["-S".to_owned()],
+            Some(key) => vec![format!("-S{}", key)],
+            None if self.no_gpg_sign => vec!["--no-gpg-sign".to_owned()],
+            None => vec![],
+        }
+    }
+}
+
+/// Git operations used by [`crate::git_helper::GitHelper`].
+///
+/// Commits, trees and revisions are given as strings, which are commit ids
+/// unless stated otherwise.
+pub trait GitBackend {
+    /// Resolves a revision expression to a commit id.
+    fn resolve_commit(&self, rev: &str) -> Result<String, GitCommandError>;
+
+    fn merge_base(
+        &self,
+        left: &str,
+        right: &str,
+    ) -> Result<String, GitCommandError>;
+
+    fn is_ancestor(
+        &self,
+        ancestor: &str,
+        descendant: &str,
+    ) -> Result<bool, GitCommandError>;
+
+    /// Lists the changes which are not committed yet, like `git status`.
+    fn worktree_status(&self) -> Result<WorktreeStatus, GitCommandError>;
+
+    /// Stashes the staged, unstaged and untracked changes, like
+    /// `git stash push --include-untracked`, and returns the id of the
+    /// stash commit.
+    fn stash_push(&self) -> Result<String, GitCommandError>;
+
+    /// Restores the latest stash including its index, like
+    /// `git stash pop --index`.
+    fn stash_pop(&self) -> Result<(), GitCommandError>;
+
+    /// Returns the name of the current branch or `None` if HEAD is detached.
+    fn current_branch_name(&self) -> Result<Option<String>, GitCommandError>;
+
+    /// Returns the Git directory, which is private to the worktree in a
+    /// linked worktree, like `git rev-parse --absolute-git-dir`.
+    fn git_dir(&self) -> Result<PathBuf, GitCommandError>;
+
+    /// Lists the files changed between two commits, detecting renames as in
+    /// `git diff-tree -r --raw -M`, which ignores the `diff.*` settings just
+    /// like [`GitBackend::diff_patch`].
+    ///
+    /// Copies are not detected, since a copy would be built against the
+    /// content of its source in the index, which may have changed by then.
+    /// A copied file is listed as added instead.
+    fn diff_files(
+        &self,
+        base: &str,
+        head: &str,
+    ) -> Result<Vec<FileChange>, GitCommandError>;
+
+    /// Returns the binary-safe patch between two commits in the format of
+    /// `git diff-tree -p --binary --full-index -M`. Renames must be detected
+    /// in the same way as [`GitBackend::diff_files`].
+    fn diff_patch(
+        &self,
+        base: &str,
+        head: &str,
+    ) -> Result<Vec<u8>, GitCommandError>;
+
+    /// Creates a branch at HEAD and switches to it.
+    fn create_and_switch_branch(
+        &self,
+        name: &str,
+    ) -> Result<(), GitCommandError>;
+
+    /// Points the branch to HEAD and switches to it, like `git checkout -B`.
+    fn reset_and_switch_branch(
+        &self,
+        name: &str,
+    ) -> Result<(), GitCommandError>;
+
+    /// Switches to the branch whose tree must be the same as HEAD's.
+    fn switch_branch(&self, name: &str) -> Result<(), GitCommandError>;
+
+    /// Detaches HEAD at the current commit, like `git checkout --detach`.
+    fn detach_head(&self) -> Result<(), GitCommandError>;
+
+    fn delete_branch(&self, name: &str) -> Result<(), GitCommandError>;
+
+    /// Moves HEAD and the index to the commit without touching the working
+    /// tree, like `git reset --mixed`.
+    fn reset(&self, commit: &str) -> Result<(), GitCommandError>;
+
+    /// Creates or moves the reference to the commit, like `git update-ref`.
+    ///
+    /// If `old` is given, the reference is only moved if it still points to
+    /// `old`, so it is never moved over a concurrent change. `HEAD` itself is
+    /// moved even if it is a symbolic reference.
+    fn update_ref(
+        &self,
+        name: &str,
+        commit: &str,
+        old: Option<&str>,
+    ) -> Result<(), GitCommandError>;
+
+    /// Deletes the reference, like `git update-ref -d`.
+    fn delete_ref(&self, name: &str) -> Result<(), GitCommandError>;
+
+    /// Lists the references starting with the prefix and their commit ids.
+    fn list_refs(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, GitCommandError>;
+
+    /// Applies a patch to the index, like `git apply --cached`.
+    fn apply_to_index(&self, patch: &[u8]) -> Result<(), GitCommandError>;
+
+    /// Applies a patch to the tree without touching the index and returns
+    /// the new tree id, like `git apply --cached` with a temporary index.
+    fn apply_to_tree(
+        &self,
+        tree: &str,
+        patch: &[u8],
+    ) -> Result<String, GitCommandError>;
+
+    /// Returns the identity of the user for new commits, dated now.
+    fn signature(&self) -> Result<Signature, GitCommandError>;
+
+    /// Commits the index on top of HEAD and returns the new commit id.
+    ///
+    /// The author is the user, unless another author is given.
+    fn commit_index(
+        &self,
+        msg: &str,
+        author: Option<&Signature>,
+    ) -> Result<String, GitCommandError>;
+
+    /// Returns the tree id of the commit.
+    fn tree_of(&self, commit: &str) -> Result<String, GitCommandError>;
+
+    /// Lists the commits reachable from `to` but not from `from`, parents
+    /// first.
+    fn rev_list(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<String>, GitCommandError>;
+
+    /// Lists the merge commits reachable from `to` but not from `from`, like
+    /// `git rev-list --merges`.
+    fn list_merges(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<String>, GitCommandError>;
+
+    fn read_commit(&self, commit: &str) -> Result<CommitInfo, GitCommandError>;
+
+    /// Reads the content of the file at the commit, or `None` if there is no
+    /// such file, like `git cat-file blob <commit>:<path>`.
+    fn read_blob(
+        &self,
+        commit: &str,
+        path: &Path,
+    ) -> Result<Option<Vec<u8>>, GitCommandError>;
+
+    /// Creates a commit object of the tree without moving any reference.
+    /// The message is labelled with the encoding if it is given, like
+    /// `git -c i18n.commitEncoding=<encoding> commit-tree`.
+    fn commit_tree(
+        &self,
+        tree: &str,
+        parents: &[String],
+        author: &Signature,
+        message: &[u8],
+        encoding: Option<&str>,
+    ) -> Result<String, GitCommandError>;
+}
+
+pub(crate) fn run_git(
+    dir: &Path,
+    args: &[&str],
+) -> Result<Output, GitCommandError> {
+    let output = Command::new("git").current_dir(dir).args(args).output()?;
+
+    if !output.status.success() {
+        return Err(GitCommandError::GitError {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    Ok(output)
+}
+
+pub(crate) fn trim_output(output: &Output) -> Result<String, GitCommandError> {
+    Ok(std::str::from_utf8(output.stdout.as_slice())?
+        .trim_end_matches("\r\n")
+        .trim_end_matches('\n')
+        .to_owned())
+}
+
+/// Converts a path printed by git into a [`PathBuf`] without assuming that
+/// it is valid UTF-8.
+pub(crate) fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// Splits a raw commit object into its headers and its message.
+fn split_commit_object(object: &[u8]) -> (&[u8], &[u8]) {
+    match object.windows(2).position(|w| w == b"\n\n") {
+        Some(pos) => (&object[..pos], &object[pos + 2..]),
+        None => (object, b""),
+    }
+}
+
+/// Parses `Name <email> 1700000000 +0900` into a [`Signature`].
+pub(crate) fn parse_signature(value: &str) -> Option<Signature> {
+    let (name, rest) = value.split_once(" <")?;
+    let (email, date) = rest.split_once("> ")?;
+    Some(Signature {
+        name: name.to_owned(),
+        email: email.to_owned(),
+        date: date.to_owned(),
+    })
+}
+
+/// Backend which runs the `git` executable.
+#[derive(Debug)]
+pub struct CliBackend {
+    /// Directory where every command runs, which may be a subdirectory or a
+    /// linked worktree of the repository.
+    repo: PathBuf,
+    options: CommitOptions,
+}
+
+impl CliBackend {
+    pub fn new(repo: &Path) -> CliBackend {
+        CliBackend::new_with_options(repo, CommitOptions::default())
+    }
+
+    /// Constructs a backend passing the options to every commit.
+    pub fn new_with_options(repo: &Path, options: CommitOptions) -> CliBackend {
+        CliBackend {
+            repo: repo.to_path_buf(),
+            options,
+        }
+    }
+
+    fn get_project_dir(&self) -> Result<PathBuf, GitCommandError> {
+        let output = run_git(&self.repo, &["rev-parse", "--show-toplevel"])?;
+        let stdout = output.stdout.as_slice();
+        let stdout = stdout.strip_suffix(b"\n").unwrap_or(stdout);
+        Ok(bytes_to_path(stdout))
+    }
+
+    /// Runs git from the project root with a separate index file, so the
+    /// index of the repository is left as it is.
+    fn run_git_with_index<S: AsRef<OsStr>>(
+        &self,
+        index_file: &Path,
+        args: &[S],
+    ) -> Result<Output, GitCommandError> {
+        let output = Command::new("git")
+            .current_dir(self.get_project_dir()?)
+            .env("GIT_INDEX_FILE", index_file)
+            .args(args)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(GitCommandError::GitError {
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        Ok(output)
+    }
+}
+
+impl GitBackend for CliBackend {
+    fn resolve_commit(&self, rev: &str) -> Result<String, GitCommandError> {
+        let rev = format!("{}^{{commit}}", rev);
+        let output = run_git(
+            &self.repo,
+            &["rev-parse", "--verify", "--end-of-options", rev.as_str()],
+        )?;
+        trim_output(&output)
+    }
+
+    fn merge_base(
+        &self,
+        left: &str,
+        right: &str,
+    ) -> Result<String, GitCommandError> {
+        trim_output(&run_git(&self.repo, &["merge-base", left, right])?)
+    }
+
+    fn is_ancestor(
+        &self,
+        ancestor: &str,
+        descendant: &str,
+    ) -> Result<bool, GitCommandError> {
+        let output = Command::new("git")
+            .current_dir(&self.repo)
+            .args(["merge-base", "--is-ancestor", ancestor, descendant])
+            .output()?;
+
+        match output.status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(GitCommandError::GitError {
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }),
+        }
+    }
+
+    fn worktree_status(&self) -> Result<WorktreeStatus, GitCommandError> {
+        let output = run_git(&self.repo, &["status", "--porcelain", "-z"])?;
+
+        let mut status = WorktreeStatus::default();
+        let mut entries = output.stdout.split(|c| *c == 0);
+        while let Some(entry) = entries.next() {
+            // The entry is "{index}{worktree} {path}", followed by the
+            // original path as a separate entry for a rename or a copy.
+            let (Some(codes), Some(path)) = (entry.get(..2), entry.get(3..))
+            else {
+                continue;
+            };
+            if codes.contains(&b'R') || codes.contains(&b'C') {
+                entries.next();
+            }
+            let path = bytes_to_path(path);
+
+            match (codes[0], codes[1]) {
+                (b'?', _) => status.untracked.push(path),
+                (staged, unstaged) => {
+                    if staged != b' ' {
+                        status.staged.push(path.clone());
+                    }
+                    if unstaged != b' ' {
+                        status.unstaged.push(path);
+                    }
+                }
+            }
+        }
+
+        Ok(status)
+    }
+
+    fn stash_push(&self) -> Result<String, GitCommandError> {
+        run_git(
+            &self.repo,
+            &[
+                "stash",
+                "push",
+                "-q",
+                "--include-untracked",
+                "-m",
+                "git-break-commits autostash",
+            ],
+        )?;
+        self.resolve_commit("refs/stash")
+    }
+
+    fn stash_pop(&self) -> Result<(), GitCommandError> {
+        run_git(&self.repo, &["stash", "pop", "-q", "--index"])?;
+        Ok(())
+    }
+
+    fn current_branch_name(&self) -> Result<Option<String>, GitCommandError> {
+        let name =
+            trim_output(&run_git(&self.repo, &["branch", "--show-current"])?)?;
+        Ok((!name.is_empty()).then_some(name))
+    }
+
+    fn git_dir(&self) -> Result<PathBuf, GitCommandError> {
+        let output = run_git(&self.repo, &["rev-parse", "--absolute-git-dir"])?;
+        let stdout = output.stdout.as_slice();
+        let stdout = stdout.strip_suffix(b"\n").unwrap_or(stdout);
+        Ok(bytes_to_path(stdout))
+    }
+
+    fn diff_files(
+        &self,
+        base: &str,
+        head: &str,
+    ) -> Result<Vec<FileChange>, GitCommandError> {
+        let output = run_git(
+            &self.repo,
+            &["diff-tree", "-r", "--raw", "-z", "-M", base, head],
+        )?;
+
+        let mut changes = vec![];
+        let mut fields = output.stdout.split(|c| *c == 0);
+        while let Some(meta) = fields.next() {
+            // The record is ":{old_mode} {new_mode} {old_id} {new_id} {status}"
+            // followed by "{path}" or, for a rename, "{old_path}" and
+            // "{path}", where the status has a score.
+            let meta = String::from_utf8_lossy(meta);
+            let meta: Vec<&str> = meta.split(' ').collect();
+            let [old_mode, new_mode, old_id, new_id, status] = meta[..] else {
+                continue;
+            };
+            let status = status
+                .chars()
+                .next()
+                .and_then(ChangeStatus::from_letter)
+                .ok_or_else(|| GitCommandError::InvalidObjectError {
+                    id: meta.join(" "),
+                })?;
+            let old_path = match status {
+                ChangeStatus::Renamed => fields.next().map(bytes_to_path),
+                _ => None,
+            };
+            let Some(path) = fields.next().map(bytes_to_path) else {
+                break;
+            };
+            let status = if status == ChangeStatus::Modified
+                && old_mode != new_mode
+                && old_id == new_id
+            {
+                ChangeStatus::ModeChanged
+            } else {
+                status
+            };
+
+            changes.push(FileChange {
+                status,
+                path,
+                old_path,
+            });
+        }
+
+        Ok(changes)
+    }
+
+    fn diff_patch(
+        &self,
+        base: &str,
+        head: &str,
+    ) -> Result<Vec<u8>, GitCommandError> {
+        let output = run_git(
+            &self.repo,
+            &[
+                "diff-tree",
+                "-r",
+                "-p",
+                "--binary",
+                "--full-index",
+                "-M",
+                base,
+                head,
+            ],
+        )?;
+        Ok(output.stdout)
+    }
+
+    fn create_and_switch_branch(
+        &self,
+        name: &str,
+    ) -> Result<(), GitCommandError> {
+        run_git(&self.repo, &["checkout", "-q", "-b", name])?;
+        Ok(())
+    }
+
+    fn reset_and_switch_branch(
+        &self,
+        name: &str,
+    ) -> Result<(), GitCommandError> {
+        run_git(&self.repo, &["checkout", "-q", "-B", name])?;
+        Ok(())
+    }
+
+    fn switch_branch(&self, name: &str) -> Result<(), GitCommandError> {
+        run_git(&self.repo, &["checkout", "-q", name])?;
+        Ok(())
+    }
+
+    fn detach_head(&self) -> Result<(), GitCommandError> {
+        run_git(&self.repo, &["checkout", "-q", "--detach"])?;
+        Ok(())
+    }
+
+    fn delete_branch(&self, name: &str) -> Result<(), GitCommandError> {
+        run_git(&self.repo, &["branch", "-D", name])?;
+        Ok(())
+    }
+
+    fn reset(&self, commit: &str) -> Result<(), GitCommandError> {
+        run_git(&self.repo, &["reset", "-q", commit])?;
+        Ok(())
+    }
+
+    fn update_ref(
+        &self,
+        name: &str,
+        commit: &str,
+        old: Option<&str>,
+    ) -> Result<(), GitCommandError> {
+        let mut args = vec!["update-ref", "--no-deref", name, commit];
+        args.extend(old);
+        run_git(&self.repo, &args)?;
+        Ok(())
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<(), GitCommandError> {
+        run_git(&self.repo, &["update-ref", "-d", name])?;
+        Ok(())
+    }
+
+    fn list_refs(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, GitCommandError> {
+        let output = run_git(
+            &self.repo,
+            &["for-each-ref", "--format=%(refname) %(objectname)", prefix],
+        )?;
+        Ok(std::str::from_utf8(&output.stdout)?
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, id)| (name.to_owned(), id.to_owned()))
+            .collect())
+    }
+
+    fn apply_to_index(&self, patch: &[u8]) -> Result<(), GitCommandError> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(patch)?;
+
+        // Paths in the patch are relative to the project root.
+        let output = Command::new("git")
+            .current_dir(self.get_project_dir()?)
+            .args(["apply", "--cached"])
+            .arg(file.path())
+            .output()?;
+
+        if !output.status.success() {
+            return Err(GitCommandError::GitError {
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    fn apply_to_tree(
+        &self,
+        tree: &str,
+        patch: &[u8],
+    ) -> Result<String, GitCommandError> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(patch)?;
+
+        // The index file must not exist before `read-tree` creates it.
+        let index_dir = tempdir()?;
+        let index_file = index_dir.path().join("index");
+
+        self.run_git_with_index(&index_file, &["read-tree", tree])?;
+        self.run_git_with_index(
+            &index_file,
+            &[
+                OsStr::new("apply"),
+                OsStr::new("--cached"),
+                file.path().as_ref(),
+            ],
+        )?;
+        trim_output(&self.run_git_with_index(&index_file, &["write-tree"])?)
+    }
+
+    fn signature(&self) -> Result<Signature, GitCommandError> {
+        let ident =
+            trim_output(&run_git(&self.repo, &["var", "GIT_AUTHOR_IDENT"])?)?;
+        parse_signature(&ident)
+            .ok_or(GitCommandError::InvalidObjectError { id: ident })
+    }
+
+    fn commit_index(
+        &self,
+        msg: &str,
+        author: Option<&Signature>,
+    ) -> Result<String, GitCommandError> {
+        let mut command = Command::new("git");
+        if let Some(author) = author {
+            command
+                .env("GIT_AUTHOR_NAME", &author.name)
+                .env("GIT_AUTHOR_EMAIL", &author.email)
+                .env("GIT_AUTHOR_DATE", &author.date);
+        }
+        command
+            .current_dir(self.get_project_dir()?)
+            .args(["commit", "-q"]);
+        if self.options.no_verify {
+            command.arg("--no-verify");
+        }
+        let output = command
+            .args(self.options.signing_args())
+            .args(["-m", msg])
+            .output()?;
+
+        if !output.status.success() {
+            return Err(GitCommandError::GitError {
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+
+        self.resolve_commit("HEAD")
+    }
+
+    fn tree_of(&self, commit: &str) -> Result<String, GitCommandError> {
+        let rev = format!("{}^{{tree}}", commit);
+        trim_output(&run_git(&self.repo, &["rev-parse", rev.as_str()])?)
+    }
+
+    fn read_blob(
+        &self,
+        commit: &str,
+        path: &Path,
+    ) -> Result<Option<Vec<u8>>, GitCommandError> {
+        // The path is relative to the top of the repository, not to the
+        // current directory, and it is not taken as a pathspec.
+        let mut spec = OsString::from(format!("{}:", commit));
+        spec.push(path);
+        let output = Command::new("git")
+            .current_dir(&self.repo)
+            .args(["cat-file", "-e"])
+            .arg(&spec)
+            .output()?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        let output = Command::new("git")
+            .current_dir(&self.repo)
+            .args(["cat-file", "blob"])
+            .arg(&spec)
+            .output()?;
+        if !output.status.success() {
+            return Err(GitCommandError::GitError {
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        Ok(Some(output.stdout))
+    }
+
+    fn rev_list(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<String>, GitCommandError> {
+        let range = format!("{}..{}", from, to);
+        let output = run_git(
+            &self.repo,
+            &["rev-list", "--reverse", "--topo-order", &range],
+        )?;
+
+        Ok(std::str::from_utf8(&output.stdout)?
+            .lines()
+            .map(|line| line.to_owned())
+            .collect())
+    }
+
+    fn list_merges(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<String>, GitCommandError> {
+        let range = format!("{}..{}", from, to);
+        let output = run_git(
+            &self.repo,
+            &["rev-list", "--merges", "--reverse", "--topo-order", &range],
+        )?;
+
+        Ok(std::str::from_utf8(&output.stdout)?
+            .lines()
+            .map(|line| line.to_owned())
+            .collect())
+    }
+
+    fn read_commit(&self, commit: &str) -> Result<CommitInfo, GitCommandError> {
+        let output = run_git(&self.repo, &["cat-file", "commit", commit])?;
+        let (headers, message) = split_commit_object(&output.stdout);
+
+        let mut tree = String::new();
+        let mut parents = vec![];
+        let mut author = None;
+        let mut encoding = None;
+        let mut signed = false;
+        // The headers are in the encoding of the message, e.g. the name of
+        // the author in a Latin-1 commit, so they are decoded lossily as
+        // libgit2 does.
+        for line in headers.split(|c| *c == b'\n') {
+            let line = String::from_utf8_lossy(line);
+            if let Some(value) = line.strip_prefix("tree ") {
+                tree = value.to_owned();
+            } else if let Some(value) = line.strip_prefix("parent ") {
+                parents.push(value.to_owned());
+            } else if let Some(value) = line.strip_prefix("author ") {
+                author = parse_signature(value);
+            } else if let Some(value) = line.strip_prefix("encoding ") {
+                encoding = Some(value.to_owned());
+            } else if line.starts_with("gpgsig") {
+                signed = true;
+            }
+        }
+
+        let author =
+            author.ok_or_else(|| GitCommandError::InvalidObjectError {
+                id: commit.to_owned(),
+            })?;
+
+        Ok(CommitInfo {
+            tree,
+            parents,
+            author,
+            message: message.to_vec(),
+            encoding,
+            signed,
+        })
+    }
+
+    fn commit_tree(
+        &self,
+        tree: &str,
+        parents: &[String],
+        author: &Signature,
+        message: &[u8],
+        encoding: Option<&str>,
+    ) -> Result<String, GitCommandError> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(message)?;
+
+        let mut command = Command::new("git");
+        command.current_dir(&self.repo);
+        if let Some(encoding) = encoding {
+            command
+                .arg("-c")
+                .arg(format!("i18n.commitEncoding={}", encoding));
+        }
+        command
+            .args(["commit-tree", tree])
+            .args(self.options.signing_args());
+        for parent in parents {
+            command.args(["-p", parent.as_str()]);
+        }
+        let output = command
+            .arg("-F")
+            .arg(file.path())
+            .env("GIT_AUTHOR_NAME", &author.name)
+            .env("GIT_AUTHOR_EMAIL", &author.email)
+            .env("GIT_AUTHOR_DATE", &author.date)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(GitCommandError::GitError {
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+
+        trim_output(&output)
+    }
+}
@@ -1,12 +1,21 @@
 use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
 
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::{Command, ExitStatus, Output};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
-use tempfile::NamedTempFile;
+use crate::git_backend::{
+    CommitInfo, CommitOptions, FileChange, GitBackend, Signature,
+    WorktreeStatus,
+};
+use crate::patch::{
+    parse_diff, FileDiff, HunkSelection, PatchBuilder, PatchError,
+};
 
 #[derive(Error, Debug)]
 pub enum GitCommandError {
@@ -16,10 +25,71 @@ pub enum GitCommandError {
     UTF8Error { value: std::str::Utf8Error },
     #[error("status: {0:?}, stderr: {1:?}", status, stderr)]
     GitError { status: ExitStatus, stderr: String },
+    #[error("libgit2 error: {0}", value)]
+    Libgit2Error { value: git2::Error },
     #[error("Invalid function call")]
     InvalidFunctionCallError,
-    #[error("Empty list (level: {0:?}", level)]
-    EmptyListError { level: u8 },
+    #[error("The libgit2 backend does not support {0}", option)]
+    UnsupportedOptionError { option: String },
+    #[error("Invalid object {0:?}", id)]
+    InvalidObjectError { id: String },
+    #[error("Empty list (base: {0:?})", base)]
+    EmptyListError { base: String },
+    #[error("{0:?} is not an ancestor of {1:?}", ancestor, descendant)]
+    NotAncestorError {
+        ancestor: String,
+        descendant: String,
+    },
+    #[error("The new commits do not reproduce the tree of {0:?}", commit)]
+    ReplayConflictError { commit: String },
+    #[error("Cannot replay the merge commit {0:?}", commit)]
+    ReplayMergeError { commit: String },
+    #[error(
+        "Uncommitted changes (commit or stash them, or use --autostash):\n{0}",
+        status
+    )]
+    DirtyWorktreeError { status: WorktreeStatus },
+    #[error("Commit #{0} was rejected:\n{1}", index + 1, output)]
+    CommitRejectedError { index: usize, output: String },
+    #[error(
+        "The commits to split contain the merge commits {0:?}, which would be \
+         flattened into a single line. Use --first-parent to fold their side \
+         branches into the new commits",
+        merges
+    )]
+    MergeInRangeError { merges: Vec<String> },
+    #[error("No split to undo")]
+    NoBackupError,
+    #[error(
+        "HEAD moved since the split (expected: {0:?}, actual: {1:?})",
+        expected,
+        actual
+    )]
+    BranchMovedError { expected: String, actual: String },
+    #[error("Patch error: {0}", value)]
+    PatchError { value: PatchError },
+    #[error(
+        "Cannot restore the uncommitted changes, which are kept in the stash \
+         {0} (restore them with `git stash apply --index {0}`): {1}",
+        stash,
+        value
+    )]
+    UnstashError {
+        stash: String,
+        value: Box<GitCommandError>,
+    },
+}
+
+impl From<PatchError> for GitCommandError {
+    fn from(value: PatchError) -> Self {
+        GitCommandError::PatchError { value }
+    }
+}
+
+impl From<git2::Error> for GitCommandError {
+    fn from(value: git2::Error) -> Self {
+        GitCommandError::Libgit2Error { value }
+    }
 }
 
 impl From<std::io::Error> for GitCommandError {
@@ -41,82 +111,574 @@ impl From<std::str::Utf8Error> for GitCommandError {
     }
 }
 
+/// References to the original HEAD before each split, named by the split
+/// branch (`HEAD` if detached) and the time of the split in milliseconds,
+/// e.g. `refs/break-commits/backup/main/1700000000000`.
+const BACKUP_REF_PREFIX: &str = "refs/break-commits/backup/";
+/// References to the new HEAD after each split, named as the backups.
+const RESULT_REF_PREFIX: &str = "refs/break-commits/result/";
+
+/// Returns the prefix of the backups or the results of the splits of the
+/// branch, or of a detached HEAD if `None`. No branch can be named `HEAD`.
+fn branch_ref_prefix(prefix: &str, branch_name: Option<&str>) -> String {
+    format!("{}{}/", prefix, branch_name.unwrap_or("HEAD"))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct GitCommitCandidate {
     pub msg: String,
     pub file_paths: Vec<PathBuf>,
+    /// Lines of the hunks to commit in addition to the whole files.
+    pub hunks: Vec<HunkSelection>,
+    /// The author of the commit, or `None` for the user.
+    pub author: Option<Signature>,
+}
+
+impl GitCommitCandidate {
+    /// Merges the next commit into this one. The messages are joined and
+    /// the author of this commit is kept.
+    pub fn merge(&mut self, next: GitCommitCandidate) {
+        self.msg = format!("{}\n\n{}", self.msg, next.msg);
+        self.file_paths.extend(next.file_paths);
+        for hunk in next.hunks {
+            let same = self.hunks.iter_mut().find(|selection| {
+                selection.path == hunk.path && selection.index == hunk.index
+            });
+            match same {
+                Some(selection) => {
+                    selection.lines.extend(hunk.lines);
+                    selection.lines.sort();
+                    selection.lines.dedup();
+                }
+                None => self.hunks.push(hunk),
+            }
+        }
+    }
+}
+
+/// Authors of the commits being split.
+#[derive(Debug, Default)]
+pub struct Authors {
+    /// Distinct authors in the order of their first commit, dated at their
+    /// last commit.
+    pub signatures: Vec<Signature>,
+    /// The author who changed each path last, as an index into `signatures`.
+    by_path: HashMap<PathBuf, usize>,
+}
+
+impl Authors {
+    /// Collects the authors of the commits and the files they changed, as
+    /// listed by [`GitHelper::list_commit_infos`].
+    pub fn new(commits: &[(CommitInfo, Vec<FileChange>)]) -> Authors {
+        let mut authors = Authors::default();
+
+        for (info, changes) in commits {
+            let position = authors.signatures.iter().position(|signature| {
+                signature.name == info.author.name
+                    && signature.email == info.author.email
+            });
+            let index = match position {
+                Some(index) => {
+                    authors.signatures[index] = info.author.clone();
+                    index
+                }
+                None => {
+                    authors.signatures.push(info.author.clone());
+                    authors.signatures.len() - 1
+                }
+            };
+
+            for change in changes {
+                authors.by_path.insert(change.path.clone(), index);
+            }
+        }
+
+        authors
+    }
+
+    /// Returns the author who last changed most of the paths. A tie goes to
+    /// the author of the earlier commits.
+    pub fn majority(&self, paths: &[PathBuf]) -> Option<&Signature> {
+        let mut counts = vec![0; self.signatures.len()];
+        for path in paths {
+            if let Some(index) = self.by_path.get(path) {
+                counts[*index] += 1;
+            }
+        }
+
+        let (index, count) = counts
+            .into_iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, count)| *count)?;
+        (count > 0).then(|| &self.signatures[index])
+    }
+}
+
+/// A merge commit whose side branch is folded into the new commits.
+#[derive(Debug)]
+pub struct FoldedMerge {
+    pub id: String,
+    pub message: String,
+    /// The changes brought by the side branch, i.e. from the first parent.
+    pub changes: Vec<FileChange>,
+}
+
+impl fmt::Display for FoldedMerge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let summary = self.message.lines().next().unwrap_or_default();
+        writeln!(f, "{} {}", &self.id[..self.id.len().min(7)], summary)?;
+        for change in &self.changes {
+            writeln!(
+                f,
+                "  {} {}",
+                change.status.letter(),
+                change.path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A file changed by a [`PlannedCommit`].
+#[derive(Debug, PartialEq)]
+pub struct FileStat {
+    pub path: PathBuf,
+    /// The source of the file if it is renamed.
+    pub old_path: Option<PathBuf>,
+    /// The numbers of added and removed lines, or `None` for a binary file.
+    pub counts: Option<(usize, usize)>,
+}
+
+/// A candidate as it would be committed, shown by a dry run.
+#[derive(Debug)]
+pub struct PlannedCommit {
+    pub msg: String,
+    pub author: Signature,
+    pub stats: Vec<FileStat>,
+}
+
+impl fmt::Display for PlannedCommit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Author: {} <{}>", self.author.name, self.author.email)?;
+        writeln!(f)?;
+        for line in self.msg.trim_end().lines() {
+            writeln!(f, "    {}", line)?;
+        }
+        writeln!(f)?;
+
+        // Renamed files are shown as `old => new`, as `git diff --stat` does.
+        let names: Vec<String> = self
+            .stats
+            .iter()
+            .map(|stat| match &stat.old_path {
+                Some(old_path) => format!(
+                    "{} => {}",
+                    old_path.to_string_lossy(),
+                    stat.path.to_string_lossy()
+                ),
+                None => stat.path.to_string_lossy().into_owned(),
+            })
+            .collect();
+        let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+        let (mut insertions, mut deletions) = (0, 0);
+        for (name, stat) in names.iter().zip(&self.stats) {
+            match stat.counts {
+                Some((added, removed)) => {
+                    // A rename without changes has no bar.
+                    let line = format!(
+                        " {:width$} | {} {}",
+                        name,
+                        added + removed,
+                        stat_bar(added, removed)
+                    );
+                    writeln!(f, "{}", line.trim_end())?;
+                    insertions += added;
+                    deletions += removed;
+                }
+                None => writeln!(f, " {:width$} | Bin", name)?,
+            }
+        }
+        writeln!(
+            f,
+            " {} changed, {}(+), {}(-)",
+            plural(self.stats.len(), "file"),
+            plural(insertions, "insertion"),
+            plural(deletions, "deletion")
+        )
+    }
+}
+
+/// Draws the `+` and `-` bar of a diffstat, scaled down to at most 40
+/// characters as `git diff --stat` does.
+fn stat_bar(added: usize, removed: usize) -> String {
+    const MAX_WIDTH: usize = 40;
+    let total = added + removed;
+    let scale = |count: usize| match count {
+        0 => 0,
+        _ if total <= MAX_WIDTH => count,
+        _ => (count * MAX_WIDTH / total).max(1),
+    };
+    format!("{}{}", "+".repeat(scale(added)), "-".repeat(scale(removed)))
+}
+
+pub(crate) fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("{} {}", count, noun),
+        _ => format!("{} {}s", count, noun),
+    }
+}
+
+/// Quotes an argument for a POSIX shell if needed.
+fn quote(arg: &str) -> String {
+    let is_safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_./:@%+=,-^{}~".contains(c));
+    if is_safe {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Returns the `git commit-tree` command line as run by the CLI backend.
+fn commit_tree_command(
+    author: &Signature,
+    tree: &str,
+    parent: &str,
+    message: &str,
+    options: &CommitOptions,
+) -> String {
+    let mut args = vec!["git".to_owned(), "commit-tree".to_owned()];
+    args.push(tree.to_owned());
+    args.extend(options.signing_args());
+    args.extend(["-p", parent, "-F", message].map(str::to_owned));
+    format!("{}{}", author_env(author), args.join(" "))
+}
+
+/// Returns the environment variables which set the author of a commit.
+fn author_env(author: &Signature) -> String {
+    format!(
+        "GIT_AUTHOR_NAME={} GIT_AUTHOR_EMAIL={} GIT_AUTHOR_DATE={} ",
+        quote(&author.name),
+        quote(&author.email),
+        quote(&author.date)
+    )
+}
+
+/// Messages of the commits being split.
+#[derive(Debug, Default)]
+pub struct Messages {
+    /// Messages in commit order.
+    messages: Vec<String>,
+    /// The commits changing each path, as indices into `messages`.
+    by_path: HashMap<PathBuf, Vec<usize>>,
+}
+
+impl Messages {
+    /// Collects the messages of the commits and the files they changed, as
+    /// listed by [`GitHelper::list_commit_infos`].
+    pub fn new(commits: &[(CommitInfo, Vec<FileChange>)]) -> Messages {
+        let mut messages = Messages::default();
+
+        for (info, changes) in commits {
+            let index = messages.messages.len();
+            let message = String::from_utf8_lossy(&info.message);
+            messages.messages.push(message.trim_end().to_owned());
+
+            for change in changes {
+                messages
+                    .by_path
+                    .entry(change.path.clone())
+                    .or_default()
+                    .push(index);
+            }
+        }
+
+        messages
+    }
+
+    /// Returns the messages of the commits which changed any of the paths,
+    /// in commit order.
+    pub fn for_paths(&self, paths: &[PathBuf]) -> Vec<&str> {
+        let mut indices: Vec<usize> = paths
+            .iter()
+            .filter_map(|path| self.by_path.get(path))
+            .flatten()
+            .copied()
+            .collect();
+        indices.sort();
+        indices.dedup();
+
+        indices
+            .into_iter()
+            .map(|index| self.messages[index].as_str())
+            .collect()
+    }
 }
 
 pub struct GitHelper {
-    depth: u8,
-    curr_branch_name: String,
+    backend: Box<dyn GitBackend>,
+    base: String,
+    head: String,
+    /// The original HEAD. It differs from `head` if the commits after `head`
+    /// should be replayed on top of the new commits.
+    tip: String,
+    /// The branch to restore, or `None` if HEAD was detached at `tip`.
+    curr_branch_name: Option<String>,
     temp_branch_name: Option<String>,
+    /// The id of the stash of the uncommitted changes which have to be
+    /// restored.
+    stash: Option<String>,
+    /// The name of the backup of the original HEAD under
+    /// [`BACKUP_REF_PREFIX`].
+    backup_name: Option<String>,
 }
 
 impl GitHelper {
-    pub fn new(depth: u8) -> Result<GitHelper, GitCommandError> {
+    /// Constructs a new instance of [`GitHelper`] which breaks the commits
+    /// between the given base revision and HEAD.
+    pub fn new(
+        backend: Box<dyn GitBackend>,
+        base: &str,
+    ) -> Result<GitHelper, GitCommandError> {
+        let tip = resolve_commit(backend.as_ref(), "HEAD")?;
+        let base = resolve_commit(backend.as_ref(), base)?;
+
+        GitHelper::new_with_range(backend, base, tip.clone(), tip)
+    }
+
+    /// Constructs a new instance of [`GitHelper`] which breaks only the given
+    /// commit and replays the commits after it.
+    pub fn new_for_commit(
+        backend: Box<dyn GitBackend>,
+        commit: &str,
+    ) -> Result<GitHelper, GitCommandError> {
+        let tip = resolve_commit(backend.as_ref(), "HEAD")?;
+        let head = resolve_commit(backend.as_ref(), commit)?;
+        let base = resolve_commit(backend.as_ref(), &format!("{}~", head))?;
+
+        check_ancestor(backend.as_ref(), &head, &tip)?;
+        GitHelper::new_with_range(backend, base, head, tip)
+    }
+
+    fn new_with_range(
+        backend: Box<dyn GitBackend>,
+        base: String,
+        head: String,
+        tip: String,
+    ) -> Result<GitHelper, GitCommandError> {
+        check_ancestor(backend.as_ref(), &base, &head)?;
+
+        let curr_branch_name = backend.current_branch_name()?;
         let git_helper = GitHelper {
-            depth,
-            curr_branch_name: GitHelper::get_current_branch_name()?,
+            backend,
+            base,
+            head,
+            tip,
+            curr_branch_name,
             temp_branch_name: None,
+            stash: None,
+            backup_name: None,
         };
 
         Ok(git_helper)
     }
 
-    fn get_current_branch_name() -> Result<String, GitCommandError> {
-        let output = Command::new("git")
-            .args(["branch", "--show-current"])
-            .output()?;
+    /// Returns the id of the base commit.
+    pub fn base_id(&self) -> &str {
+        &self.base
+    }
 
-        if !output.status.success() {
-            return Err(GitCommandError::GitError {
-                status: output.status,
-                stderr: String::from_utf8(output.stderr)?,
-            });
+    /// Returns the id of HEAD before the split.
+    pub fn original_head(&self) -> &str {
+        &self.tip
+    }
+
+    /// Returns the Git directory of the repository or of the linked
+    /// worktree.
+    pub fn git_dir(&self) -> Result<PathBuf, GitCommandError> {
+        self.backend.git_dir()
+    }
+
+    /// Reads the file as it is at the top of the commits being split, e.g.
+    /// the manifest of a Cargo workspace.
+    pub fn read_file(
+        &self,
+        path: &Path,
+    ) -> Result<Option<Vec<u8>>, GitCommandError> {
+        self.backend.read_blob(&self.head, path)
+    }
+
+    /// Lists the changed files, where a renamed file is a single entry linked
+    /// to its source.
+    pub fn list(&self) -> Result<Vec<FileChange>, GitCommandError> {
+        let diff_list = self.backend.diff_files(&self.base, &self.head)?;
+
+        if !diff_list.is_empty() {
+            Ok(diff_list)
+        } else {
+            Err(GitCommandError::EmptyListError {
+                base: self.base.clone(),
+            })
+        }
+    }
+
+    /// Lists the commits being split, parents first, with the files each
+    /// of them changed, like `git log --raw` over the range.
+    ///
+    /// [`Authors`] and [`Messages`] are collected from it.
+    pub fn list_commit_infos(
+        &self,
+    ) -> Result<Vec<(CommitInfo, Vec<FileChange>)>, GitCommandError> {
+        let mut log = vec![];
+
+        for commit in self.backend.rev_list(&self.base, &self.head)? {
+            let info = self.backend.read_commit(&commit)?;
+            let changes = match info.parents.first() {
+                Some(parent) => self.backend.diff_files(parent, &commit)?,
+                None => vec![],
+            };
+            log.push((info, changes));
         }
 
-        let branch_name = std::str::from_utf8(output.stdout.as_slice())?
-            .trim_end_matches("\r\n")
-            .trim_end_matches("\n")
-            .to_owned();
-        Ok(branch_name)
+        Ok(log)
     }
 
-    pub fn list(&self) -> Result<Vec<PathBuf>, GitCommandError> {
-        let start = format!("HEAD~{}", self.depth);
-        let output = Command::new("git")
-            .args(["diff", "--name-only", start.as_str(), "HEAD"])
-            .output()?;
+    /// Lists the changes as per-file diffs which can be split into hunks.
+    pub fn list_diffs(&self) -> Result<Vec<FileDiff>, GitCommandError> {
+        let patch = self.backend.diff_patch(&self.base, &self.head)?;
+        let diffs = parse_diff(&patch)?;
 
-        if !output.status.success() {
-            return Err(GitCommandError::GitError {
-                status: output.status,
-                stderr: String::from_utf8(output.stderr)?,
-            });
+        if !diffs.is_empty() {
+            Ok(diffs)
+        } else {
+            Err(GitCommandError::EmptyListError {
+                base: self.base.clone(),
+            })
+        }
+    }
+
+    /// Checks the commits for merges, which cannot be kept by a split.
+    ///
+    /// Merges between the base and HEAD are refused unless `first_parent` is
+    /// set, in which case their side branches are folded into the new
+    /// commits and returned to be reported. Merges after the split commit
+    /// are always refused, since they cannot be replayed.
+    pub fn check_merges(
+        &self,
+        first_parent: bool,
+    ) -> Result<Vec<FoldedMerge>, GitCommandError> {
+        if let Some(commit) =
+            self.backend.list_merges(&self.head, &self.tip)?.pop()
+        {
+            return Err(GitCommandError::ReplayMergeError { commit });
+        }
+
+        let merges = self.backend.list_merges(&self.base, &self.head)?;
+        if merges.is_empty() {
+            return Ok(vec![]);
+        }
+        if !first_parent {
+            return Err(GitCommandError::MergeInRangeError { merges });
         }
 
-        let diff_list: Vec<PathBuf> =
-            std::str::from_utf8(output.stdout.as_ref())?
-                .split("\n")
-                .map(|line| {
-                    std::ffi::OsStr::new(
-                        line.strip_suffix("\r").unwrap_or(line),
-                    )
+        merges
+            .into_iter()
+            .map(|id| {
+                let info = self.backend.read_commit(&id)?;
+                let changes = self.backend.diff_files(&info.parents[0], &id)?;
+                Ok(FoldedMerge {
+                    id,
+                    message: String::from_utf8_lossy(&info.message)
+                        .into_owned(),
+                    changes,
                 })
-                .filter(|f| !f.is_empty())
-                .map(|line| std::path::Path::new(line).to_owned())
-                .collect();
+            })
+            .collect()
+    }
 
-        if diff_list.len() != 0 {
-            Ok(diff_list)
+    /// Checks that there are no staged, unstaged or untracked changes, which
+    /// would otherwise be swept into the new commits.
+    ///
+    /// With `autostash`, the changes are allowed and
+    /// [`GitHelper::stash`] has to be called before splitting.
+    pub fn check_worktree(
+        &self,
+        autostash: bool,
+    ) -> Result<(), GitCommandError> {
+        let status = self.backend.worktree_status()?;
+
+        if status.is_clean() || autostash {
+            Ok(())
         } else {
-            Err(GitCommandError::EmptyListError { level: self.depth })
+            Err(GitCommandError::DirtyWorktreeError { status })
+        }
+    }
+
+    /// Stashes the uncommitted changes if there are any.
+    pub fn stash(&mut self) -> Result<(), GitCommandError> {
+        if !self.backend.worktree_status()?.is_clean() {
+            self.stash = Some(self.backend.stash_push()?);
         }
+        Ok(())
+    }
+
+    /// Restores the changes stashed by [`GitHelper::stash`].
+    ///
+    /// It is called after [`GitHelper::restore_branch`] or
+    /// [`GitHelper::abort`], so the changes are restored on top of the new
+    /// or the original commits. If they cannot be restored, the error names
+    /// the stash, which is kept.
+    pub fn unstash(&mut self) -> Result<(), GitCommandError> {
+        if let Some(stash) = self.stash.take() {
+            self.backend.stash_pop().map_err(|error| {
+                GitCommandError::UnstashError {
+                    stash,
+                    value: Box::new(error),
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Records the original HEAD under [`BACKUP_REF_PREFIX`], so the split
+    /// can be reverted by [`undo`].
+    pub fn backup(&mut self) -> Result<(), GitCommandError> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or_default();
+
+        // Keep the history ordered even if two splits share a timestamp.
+        let prefix = branch_ref_prefix(
+            BACKUP_REF_PREFIX,
+            self.curr_branch_name.as_deref(),
+        );
+        let latest = self
+            .backend
+            .list_refs(&prefix)?
+            .iter()
+            .filter_map(|(name, _)| backup_time(name, &prefix))
+            .max()
+            .unwrap_or_default();
+        let name = format!(
+            "{}{}",
+            prefix.trim_start_matches(BACKUP_REF_PREFIX),
+            millis.max(latest + 1)
+        );
+
+        self.backend.update_ref(
+            &format!("{}{}", BACKUP_REF_PREFIX, name),
+            &self.tip,
+            None,
+        )?;
+        self.backup_name = Some(name);
+        Ok(())
     }
 
-    pub fn checkout_to_temp_branch(
-        &mut self,
-    ) -> Result<Output, GitCommandError> {
+    pub fn checkout_to_temp_branch(&mut self) -> Result<(), GitCommandError> {
         let rand_key: String = rand::thread_rng()
             .sample_iter(&Alphanumeric)
             .take(16)
@@ -124,187 +686,2096 @@ impl GitHelper {
             .collect();
 
         let branch_name = format!("tmp-branch/{}", rand_key);
-        let output = Command::new("git")
-            .args(["checkout", "-b", branch_name.as_str()])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(GitCommandError::GitError {
-                status: output.status,
-                stderr: String::from_utf8(output.stderr)?,
-            });
-        }
+        self.backend.create_and_switch_branch(&branch_name)?;
 
         self.temp_branch_name = Some(branch_name);
-        Ok(output)
+        Ok(())
     }
 
-    pub fn restore_branch(&mut self) -> Result<Output, GitCommandError> {
-        if let Some(name) = &self.temp_branch_name {
-            let output = Command::new("git")
-                .args(["checkout", "-B", self.curr_branch_name.as_str()])
-                .output()?;
+    /// Moves the original branch to the new commits and switches back to it,
+    /// or detaches HEAD at the new commits if HEAD was detached.
+    ///
+    /// If the new commits do not reproduce the tree of the original HEAD,
+    /// the split is aborted instead and the branch is left as it was.
+    pub fn restore_branch(&mut self) -> Result<(), GitCommandError> {
+        if self.temp_branch_name.is_some() {
+            let head = self.backend.resolve_commit("HEAD")?;
+            if let Err(error) = self.check_tree(&head, &self.tip) {
+                self.abort()?;
+                return Err(error);
+            }
+        }
 
-            if !output.status.success() {
-                return Err(GitCommandError::GitError {
-                    status: output.status,
-                    stderr: String::from_utf8(output.stderr)?,
-                });
+        if let Some(name) = &self.temp_branch_name {
+            match &self.curr_branch_name {
+                Some(branch_name) => {
+                    self.backend.reset_and_switch_branch(branch_name)?
+                }
+                None => self.backend.detach_head()?,
             }
+            self.backend.delete_branch(name)?;
+            self.temp_branch_name = None;
 
-            let output = Command::new("git")
-                .args(["branch", "-d", name.as_str()])
-                .output()?;
+            let head = self.backend.resolve_commit("HEAD")?;
+            self.record_result(&head)
+        } else {
+            Err(GitCommandError::InvalidFunctionCallError)
+        }
+    }
+
+    /// Moves back to the original branch without changing it.
+    ///
+    /// It is called instead of [`GitHelper::restore_branch`] if any step
+    /// fails, so the original branch is only moved on success. The backup of
+    /// the split is deleted.
+    pub fn abort(&mut self) -> Result<(), GitCommandError> {
+        self.discard_backup()?;
 
-            if !output.status.success() {
-                return Err(GitCommandError::GitError {
-                    status: output.status,
-                    stderr: String::from_utf8(output.stderr)?,
-                });
+        if let Some(name) = &self.temp_branch_name {
+            // The working tree is untouched, so only HEAD and the index have
+            // to be restored before switching back.
+            self.backend.reset(&self.tip)?;
+            match &self.curr_branch_name {
+                Some(branch_name) => self.backend.switch_branch(branch_name)?,
+                None => self.backend.detach_head()?,
             }
+            self.backend.delete_branch(name)?;
 
-            Ok(output)
+            self.temp_branch_name = None;
+            Ok(())
         } else {
             Err(GitCommandError::InvalidFunctionCallError)
         }
     }
 
-    pub fn reset(&self) -> Result<Output, GitCommandError> {
-        let start = format!("HEAD~{}", self.depth);
-        let output = Command::new("git")
-            .args(["reset", "--soft", start.as_str()])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(GitCommandError::GitError {
-                status: output.status,
-                stderr: String::from_utf8(output.stderr)?,
-            });
+    /// Deletes the backup made by [`GitHelper::backup`] for a split which
+    /// failed, so [`undo`] does not offer it and a retry makes a new one.
+    pub fn discard_backup(&mut self) -> Result<(), GitCommandError> {
+        if let Some(name) = self.backup_name.take() {
+            self.backend
+                .delete_ref(&format!("{}{}", BACKUP_REF_PREFIX, name))?;
         }
+        Ok(())
+    }
 
-        Ok(output)
+    /// Moves HEAD and the index to the base revision.
+    ///
+    /// The working tree is not touched, since every commit is built by
+    /// applying a patch to the index.
+    pub fn reset(&self) -> Result<(), GitCommandError> {
+        self.backend.reset(&self.base)
     }
 
+    /// Commits the candidates in order and returns the new commit ids.
+    ///
+    /// A renamed file is a single diff, so its removal from the old path is
+    /// always staged together with its addition to the new path. The new
+    /// commits must reproduce the tree of the split commits.
     pub fn commit(
         &self,
-        commits: &Vec<GitCommitCandidate>,
-    ) -> Result<Vec<Output>, GitCommandError> {
-        let args = vec!["rev-parse", "--show-toplevel"];
-        let project_dir = PathBuf::from(
-            String::from_utf8(Command::new("git").args(args).output()?.stdout)?
-                .trim_end_matches("\r\n")
-                .trim_end_matches("\n"),
-        );
+        commits: &[GitCommitCandidate],
+    ) -> Result<Vec<String>, GitCommandError> {
+        let mut builder = PatchBuilder::new(self.list_diffs()?);
+
+        let mut ids = vec![];
+
+        for (index, commit) in commits.iter().enumerate() {
+            let patch = builder.build(&commit.file_paths, &commit.hunks)?;
+            self.backend.apply_to_index(&patch)?;
+
+            // A failing `git commit` is most likely rejected by a hook, whose
+            // output is shown to fix the message or the changes.
+            let id = self
+                .backend
+                .commit_index(&commit.msg, commit.author.as_ref())
+                .map_err(|error| match error {
+                    GitCommandError::GitError { stderr, .. } => {
+                        GitCommandError::CommitRejectedError {
+                            index,
+                            output: stderr,
+                        }
+                    }
+                    error => error,
+                })?;
+            ids.push(id);
+        }
+
+        let head = self.backend.resolve_commit("HEAD")?;
+        self.check_tree(&head, &self.head)?;
+        Ok(ids)
+    }
+
+    /// Builds the candidates and replays the commits after them without
+    /// touching HEAD, the index or the working tree, then moves the branch
+    /// (or the detached HEAD) to the result at once. Returns the new commit
+    /// ids of the candidates.
+    ///
+    /// Each commit is created from a tree built with a temporary index, so
+    /// the repository is unchanged until the branch is moved. The branch is
+    /// only moved if it still points to the original HEAD. Git hooks are not
+    /// run.
+    pub fn commit_with_plumbing(
+        &self,
+        commits: &[GitCommitCandidate],
+    ) -> Result<Vec<String>, GitCommandError> {
+        let mut builder = PatchBuilder::new(self.list_diffs()?);
+        let user = self.backend.signature()?;
 
-        let mut outputs = vec![];
+        let mut ids = vec![];
+        let mut parent = self.base.clone();
+        let mut tree = self.backend.tree_of(&self.base)?;
 
         for commit in commits {
-            let mut file = NamedTempFile::new()?;
-
-            let path: Vec<&str> = commit
-                .file_paths
-                .iter()
-                .map(|item| {
-                    item.as_os_str()
-                        .to_str()
-                        .expect("Cannot change the file path to str")
+            let patch = builder.build(&commit.file_paths, &commit.hunks)?;
+            tree = self.backend.apply_to_tree(&tree, &patch)?;
+            // `git commit` ends the message with a newline.
+            let message = format!("{}\n", commit.msg.trim_end());
+            parent = self.backend.commit_tree(
+                &tree,
+                &[parent],
+                commit.author.as_ref().unwrap_or(&user),
+                message.as_bytes(),
+                None,
+            )?;
+            ids.push(parent.clone());
+        }
+        self.check_tree(&parent, &self.head)?;
+
+        let tip = self.replay_onto(parent)?;
+        self.check_tree(&tip, &self.tip)?;
+        let name = match &self.curr_branch_name {
+            Some(branch_name) => format!("refs/heads/{}", branch_name),
+            None => "HEAD".to_owned(),
+        };
+        self.backend.update_ref(&name, &tip, Some(&self.tip))?;
+
+        self.record_result(&tip)?;
+        Ok(ids)
+    }
+
+    /// Builds the candidates as they would be committed, without touching
+    /// the repository.
+    pub fn plan(
+        &self,
+        commits: &[GitCommitCandidate],
+    ) -> Result<Vec<PlannedCommit>, GitCommandError> {
+        let mut builder = PatchBuilder::new(self.list_diffs()?);
+        let user = self.backend.signature()?;
+
+        commits
+            .iter()
+            .map(|commit| {
+                let patch = builder.build(&commit.file_paths, &commit.hunks)?;
+                let stats = parse_diff(&patch)?
+                    .iter()
+                    .map(|diff| FileStat {
+                        path: diff.path.clone(),
+                        old_path: diff.old_path.clone(),
+                        counts: diff.line_counts(),
+                    })
+                    .collect();
+                Ok(PlannedCommit {
+                    msg: commit.msg.clone(),
+                    author: commit.author.clone().unwrap_or(user.clone()),
+                    stats,
                 })
-                .collect();
-            let path = path.join("\n");
+            })
+            .collect()
+    }
 
-            file.write(path.as_bytes())?;
-            let spec_filepath = file
-                .path()
-                .to_str()
-                .expect("Cannot change the named temporary file path to str");
+    /// Lists the git commands which would split the commits by checking out
+    /// a temporary branch, as `--engine porcelain` does.
+    ///
+    /// The values which are only known at that time, e.g. the ids of the new
+    /// commits, are shown as placeholders such as `<commit 1>`.
+    pub fn porcelain_commands(
+        &self,
+        commits: &[GitCommitCandidate],
+        options: &CommitOptions,
+    ) -> Result<Vec<String>, GitCommandError> {
+        let stashed = !self.backend.worktree_status()?.is_clean();
+        let mut commands = self.start_commands(stashed);
 
-            let msg = &commit.msg;
-            let args = vec![
-                "commit",
-                "-m",
-                msg.as_str(),
-                "--pathspec-from-file",
-                spec_filepath,
-            ];
+        commands.push("git checkout -q -b tmp-branch/<random>".to_owned());
+        commands.push(format!("git reset -q {}", self.base));
+        let mut parent = self.base.clone();
+        for (index, commit) in commits.iter().enumerate() {
+            let mut args = vec!["commit".to_owned(), "-q".to_owned()];
+            if options.no_verify {
+                args.push("--no-verify".to_owned());
+            }
+            args.extend(options.signing_args());
+            args.extend(["-m".to_owned(), commit.msg.clone()]);
+            let args: Vec<String> = args.iter().map(|arg| quote(arg)).collect();
 
-            let output = Command::new("git")
-                .current_dir(project_dir.as_path())
-                .args(args)
-                .output()?;
+            commands.push(format!("git apply --cached <patch {}>", index + 1));
+            commands.push(format!(
+                "{}git {}",
+                commit.author.as_ref().map(author_env).unwrap_or_default(),
+                args.join(" ")
+            ));
+            parent = format!("<commit {}>", index + 1);
+        }
+
+        let tip = self.replay_commands(&mut commands, parent, options)?;
+        if self.head != self.tip {
+            commands.push(format!("git reset -q {}", tip));
+        }
+        match &self.curr_branch_name {
+            Some(branch_name) => commands
+                .push(format!("git checkout -q -B {}", quote(branch_name))),
+            None => commands.push("git checkout -q --detach".to_owned()),
+        }
+        commands.push("git branch -D tmp-branch/<random>".to_owned());
+        commands.extend(self.finish_commands(&tip, stashed));
+        Ok(commands)
+    }
+
+    /// Lists the git commands which would split the commits with a temporary
+    /// index, as `--engine plumbing` does. The placeholders are as in
+    /// [`GitHelper::porcelain_commands`].
+    pub fn plumbing_commands(
+        &self,
+        commits: &[GitCommitCandidate],
+        options: &CommitOptions,
+    ) -> Result<Vec<String>, GitCommandError> {
+        let stashed = !self.backend.worktree_status()?.is_clean();
+        let mut commands = self.start_commands(stashed);
+        let user = self.backend.signature()?;
+
+        let mut parent = self.base.clone();
+        let mut tree = format!("{}^{{tree}}", self.base);
+        for (index, commit) in commits.iter().enumerate() {
+            let index = index + 1;
+            let author = commit.author.as_ref().unwrap_or(&user);
+            commands.extend([
+                format!("GIT_INDEX_FILE=<index> git read-tree {}", tree),
+                format!(
+                    "GIT_INDEX_FILE=<index> git apply --cached <patch {}>",
+                    index
+                ),
+                "GIT_INDEX_FILE=<index> git write-tree".to_owned(),
+                commit_tree_command(
+                    author,
+                    &format!("<tree {}>", index),
+                    &parent,
+                    &format!("<message {}>", index),
+                    options,
+                ),
+            ]);
+            tree = format!("<tree {}>", index);
+            parent = format!("<commit {}>", index);
+        }
+
+        let tip = self.replay_commands(&mut commands, parent, options)?;
+        let name = match &self.curr_branch_name {
+            Some(branch_name) => format!("refs/heads/{}", branch_name),
+            None => "HEAD".to_owned(),
+        };
+        commands.push(format!(
+            "git update-ref --no-deref {} {} {}",
+            quote(&name),
+            tip,
+            self.tip
+        ));
+        commands.extend(self.finish_commands(&tip, stashed));
+        Ok(commands)
+    }
+
+    /// Lists the commands which stash the changes and back up HEAD.
+    fn start_commands(&self, stashed: bool) -> Vec<String> {
+        let mut commands = vec![];
+        if stashed {
+            commands.push(
+                "git stash push -q --include-untracked \
+                 -m 'git-break-commits autostash'"
+                    .to_owned(),
+            );
+        }
+        commands.push(format!(
+            "git update-ref --no-deref {}<time> {}",
+            branch_ref_prefix(
+                BACKUP_REF_PREFIX,
+                self.curr_branch_name.as_deref()
+            ),
+            self.tip
+        ));
+        commands
+    }
+
+    /// Lists the commands which record the result and restore the changes.
+    fn finish_commands(&self, tip: &str, stashed: bool) -> Vec<String> {
+        let mut commands = vec![format!(
+            "git update-ref --no-deref {}<time> {}",
+            branch_ref_prefix(
+                RESULT_REF_PREFIX,
+                self.curr_branch_name.as_deref()
+            ),
+            tip
+        )];
+        if stashed {
+            commands.push("git stash pop -q --index".to_owned());
+        }
+        commands
+    }
+
+    /// Lists the commands which replay the commits after the split commit on
+    /// top of `parent`, and returns the new tip.
+    fn replay_commands(
+        &self,
+        commands: &mut Vec<String>,
+        mut parent: String,
+        options: &CommitOptions,
+    ) -> Result<String, GitCommandError> {
+        if self.head == self.tip {
+            return Ok(parent);
+        }
+
+        for commit in self.backend.rev_list(&self.head, &self.tip)? {
+            let info = self.backend.read_commit(&commit)?;
+            let short = &commit[..commit.len().min(7)];
+            commands.push(commit_tree_command(
+                &info.author,
+                &format!("{}^{{tree}}", short),
+                &parent,
+                &format!("<message of {}>", short),
+                options,
+            ));
+            parent = format!("<replayed {}>", short);
+        }
+        Ok(parent)
+    }
+
+    /// Lists the commits from the base to HEAD, i.e. the new and the
+    /// replayed commits after a split.
+    pub fn list_new_commits(
+        &self,
+    ) -> Result<Vec<(String, CommitInfo)>, GitCommandError> {
+        let head = self.backend.resolve_commit("HEAD")?;
+        self.backend
+            .rev_list(&self.base, &head)?
+            .into_iter()
+            .map(|id| {
+                let info = self.backend.read_commit(&id)?;
+                Ok((id, info))
+            })
+            .collect()
+    }
+
+    /// Checks that the commit has the same tree as `expected`, so a split
+    /// never changes the content of the branch.
+    fn check_tree(
+        &self,
+        commit: &str,
+        expected: &str,
+    ) -> Result<(), GitCommandError> {
+        if self.backend.tree_of(commit)? == self.backend.tree_of(expected)? {
+            Ok(())
+        } else {
+            Err(GitCommandError::ReplayConflictError {
+                commit: expected.to_owned(),
+            })
+        }
+    }
+
+    /// Records the new HEAD next to the backup, so [`undo`] can check that
+    /// HEAD has not moved since.
+    fn record_result(&self, head: &str) -> Result<(), GitCommandError> {
+        if let Some(backup_name) = &self.backup_name {
+            let name = format!("{}{}", RESULT_REF_PREFIX, backup_name);
+            self.backend.update_ref(&name, head, None)?;
+        }
+        Ok(())
+    }
+
+    /// Replays the commits after the split commit on top of the new commits.
+    ///
+    /// Splitting a commit does not change its tree, so every replayed commit
+    /// keeps its own tree, message and author. If the new commits do not
+    /// reproduce the tree of the split commit, nothing is replayed.
+    pub fn replay(&self) -> Result<(), GitCommandError> {
+        if self.head == self.tip {
+            return Ok(());
+        }
 
-            if !output.status.success() {
-                return Err(GitCommandError::GitError {
-                    status: output.status,
-                    stderr: String::from_utf8(output.stderr)?,
-                });
+        let parent = self.backend.resolve_commit("HEAD")?;
+        let tip = self.replay_onto(parent)?;
+        self.backend.reset(&tip)
+    }
+
+    /// Replays the commits after the split commit on top of `parent` and
+    /// returns the new tip.
+    fn replay_onto(
+        &self,
+        mut parent: String,
+    ) -> Result<String, GitCommandError> {
+        if self.head == self.tip {
+            return Ok(parent);
+        }
+
+        self.check_tree(&parent, &self.head)?;
+
+        for commit in self.backend.rev_list(&self.head, &self.tip)? {
+            let info = self.backend.read_commit(&commit)?;
+
+            if info.parents.len() > 1 {
+                return Err(GitCommandError::ReplayMergeError { commit });
             }
 
-            outputs.push(output);
+            parent = self.backend.commit_tree(
+                &info.tree,
+                &[parent],
+                &info.author,
+                &info.message,
+                info.encoding.as_deref(),
+            )?;
         }
-        Ok(outputs)
+
+        Ok(parent)
+    }
+}
+
+/// Reverts the latest split of the current branch (or of a detached HEAD) by
+/// moving HEAD back to the original commits and returns the restored commit
+/// id.
+///
+/// It refuses if HEAD moved since the split or there are uncommitted
+/// changes. The backup and the result of the split are deleted, so repeated
+/// calls revert the earlier splits in turn.
+pub fn undo(backend: &dyn GitBackend) -> Result<String, GitCommandError> {
+    let status = backend.worktree_status()?;
+    if !status.is_clean() {
+        return Err(GitCommandError::DirtyWorktreeError { status });
+    }
+
+    let branch_name = backend.current_branch_name()?;
+    let prefix = branch_ref_prefix(RESULT_REF_PREFIX, branch_name.as_deref());
+    let (result_ref, expected) = backend
+        .list_refs(&prefix)?
+        .into_iter()
+        .filter(|(name, _)| backup_time(name, &prefix).is_some())
+        .max_by_key(|(name, _)| backup_time(name, &prefix))
+        .ok_or(GitCommandError::NoBackupError)?;
+
+    let actual = backend.resolve_commit("HEAD")?;
+    if actual != expected {
+        return Err(GitCommandError::BranchMovedError { expected, actual });
+    }
+
+    let backup_ref =
+        result_ref.replacen(RESULT_REF_PREFIX, BACKUP_REF_PREFIX, 1);
+    let (_, original) = backend
+        .list_refs(&backup_ref)?
+        .into_iter()
+        .find(|(name, _)| *name == backup_ref)
+        .ok_or(GitCommandError::NoBackupError)?;
+
+    // The original commits have the same tree as the new ones, so the
+    // working tree is left as it is.
+    backend.reset(&original)?;
+    backend.delete_ref(&result_ref)?;
+    backend.delete_ref(&backup_ref)?;
+    Ok(original)
+}
+
+fn backup_time(name: &str, prefix: &str) -> Option<u128> {
+    name.strip_prefix(prefix)?.parse().ok()
+}
+
+/// Resolves a revision to a commit id.
+///
+/// `A...B` is resolved to the merge base of `A` and `B` as in
+/// `git diff A...B`, where an omitted side means HEAD.
+fn resolve_commit(
+    backend: &dyn GitBackend,
+    rev: &str,
+) -> Result<String, GitCommandError> {
+    if let Some((left, right)) = rev.split_once("...") {
+        let left = if left.is_empty() { "HEAD" } else { left };
+        let right = if right.is_empty() { "HEAD" } else { right };
+        return backend.merge_base(left, right);
+    }
+
+    backend.resolve_commit(rev)
+}
+
+fn check_ancestor(
+    backend: &dyn GitBackend,
+    ancestor: &str,
+    descendant: &str,
+) -> Result<(), GitCommandError> {
+    if backend.is_ancestor(ancestor, descendant)? {
+        Ok(())
+    } else {
+        Err(GitCommandError::NotAncestorError {
+            ancestor: ancestor.to_owned(),
+            descendant: descendant.to_owned(),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
-        env,
         fs::{create_dir, File},
         io::Write,
-        process::Command,
+        path::Path,
+        process::{Command, Output},
     };
 
     use super::*;
+    use crate::app::tests::{save_commit, select_item};
+    use crate::app::{App, CurrentScreen};
+    use crate::fake_backend::FakeBackend;
+    use crate::git_backend::{
+        run_git, trim_output, ChangeStatus, CliBackend, CommitOptions,
+    };
+    use crate::libgit2_backend::Libgit2Backend;
     use tempfile::tempdir;
 
-    #[test]
-    fn test() {
-        let result = test_impl();
-        if result.is_err() {
-            dbg!(&result);
-        }
-        assert!(result.is_ok());
+    type NewBackend = fn(&Path) -> Result<Box<dyn GitBackend>, GitCommandError>;
+
+    fn cli(dir: &Path) -> Result<Box<dyn GitBackend>, GitCommandError> {
+        Ok(Box::new(CliBackend::new(dir)))
     }
 
-    fn test_impl() -> Result<(), GitCommandError> {
-        let temp_dir = tempdir()?;
-        env::set_current_dir(&temp_dir)?;
-        dbg!(&temp_dir);
+    fn libgit2(dir: &Path) -> Result<Box<dyn GitBackend>, GitCommandError> {
+        Ok(Box::new(Libgit2Backend::new(dir)?))
+    }
 
-        let log = prepare_git_project()?;
-        println!("{:?}", log);
-        let branch_name = GitHelper::get_current_branch_name()?;
+    fn resolve(dir: &Path, rev: &str) -> Result<String, GitCommandError> {
+        resolve_commit(&CliBackend::new(dir), rev)
+    }
 
-        let mut helper = GitHelper::new(3)?;
-        let file_paths = helper.list()?;
-        println!("{:?}", file_paths);
-        println!("{:?}", helper.checkout_to_temp_branch()?);
-        println!("{:?}", helper.reset()?);
-        let msg = "test".to_owned();
-        let commit_cands = vec![GitCommitCandidate { msg, file_paths }];
+    fn current_branch_name(
+        dir: &Path,
+    ) -> Result<Option<String>, GitCommandError> {
+        CliBackend::new(dir).current_branch_name()
+    }
 
-        // Change to some directory not equal to the project root directory
-        env::set_current_dir(temp_dir.path().join("dir_1"))?;
+    fn list_paths(helper: &GitHelper) -> Result<Vec<PathBuf>, GitCommandError> {
+        Ok(helper
+            .list()?
+            .into_iter()
+            .map(|change| change.path)
+            .collect())
+    }
 
-        let outputs = helper.commit(&commit_cands)?;
-        assert_eq!(outputs.len(), commit_cands.len());
-        for output in outputs {
-            println!("{:?}", output);
-        }
-        let output = helper.restore_branch()?;
+    fn tree(dir: &Path, rev: &str) -> Result<String, GitCommandError> {
+        let rev = format!("{}^{{tree}}", rev);
+        trim_output(&run_git(dir, &["rev-parse", rev.as_str()])?)
+    }
 
-        assert_eq!(branch_name, GitHelper::get_current_branch_name()?);
+    fn is_clean(dir: &Path) -> Result<bool, GitCommandError> {
+        Ok(run_git(dir, &["status", "--porcelain"])?.stdout.is_empty())
+    }
 
-        Ok(())
+    fn candidate(msg: &str, file_paths: Vec<PathBuf>) -> GitCommitCandidate {
+        GitCommitCandidate {
+            msg: msg.to_owned(),
+            file_paths,
+            hunks: vec![],
+            author: None,
+        }
     }
 
-    fn prepare_git_project() -> Result<Output, std::io::Error> {
+    /// Splits the changed files into `first` with all but the first file and
+    /// `second` with the first file.
+    fn split_candidates(
+        helper: &GitHelper,
+    ) -> Result<Vec<GitCommitCandidate>, GitCommandError> {
+        let mut file_paths = list_paths(helper)?;
+        let first = file_paths.split_off(1);
+        Ok(vec![
+            candidate("first", first),
+            candidate("second", file_paths),
+        ])
+    }
+
+    /// Commits the candidates on a temporary branch and moves the branch to
+    /// them, as `--engine porcelain` does.
+    fn commit_on_temp_branch(
+        helper: &mut GitHelper,
+        commits: &[GitCommitCandidate],
+    ) -> Result<Vec<String>, GitCommandError> {
+        helper.checkout_to_temp_branch()?;
+        helper.reset()?;
+        let ids = helper.commit(commits)?;
+        helper.restore_branch()?;
+        Ok(ids)
+    }
+
+    /// Runs the test with each backend as a test of its own, e.g.
+    /// `tests::test_hunk::libgit2`.
+    macro_rules! backend_test {
+        ($name:ident) => {
+            mod $name {
+                use super::GitCommandError;
+
+                #[test]
+                fn cli() -> Result<(), GitCommandError> {
+                    super::$name(super::cli)
+                }
+
+                #[test]
+                fn libgit2() -> Result<(), GitCommandError> {
+                    super::$name(super::libgit2)
+                }
+            }
+        };
+    }
+
+    backend_test!(test_hunk);
+
+    fn test_hunk(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+
+        let lines: Vec<String> = (0..20).map(|i| format!("{}\n", i)).collect();
+        prepare_single_file_project(dir, &lines.concat())?;
+
+        let mut changed = lines.clone();
+        changed[1] = "first\n".to_owned();
+        changed[18] = "second\n".to_owned();
+        File::create(dir.join("file.txt"))?
+            .write_all(changed.concat().as_bytes())?;
+        run_git(dir, &["commit", "-a", "-m", "change"])?;
+
+        let mut helper = GitHelper::new(new_backend(dir)?, "HEAD~")?;
+        let diffs = helper.list_diffs()?;
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].hunks.len(), 2);
+
+        let path = PathBuf::from("file.txt");
+        let select = |index: usize, lines: Vec<usize>| HunkSelection {
+            path: path.clone(),
+            index,
+            lines,
+            hash: None,
+        };
+        let commit_cands = vec![
+            GitCommitCandidate {
+                msg: "second".to_owned(),
+                file_paths: vec![],
+                hunks: vec![select(1, diffs[0].hunks[1].changed_lines())],
+                author: None,
+            },
+            GitCommitCandidate {
+                msg: "add first".to_owned(),
+                file_paths: vec![],
+                hunks: vec![select(0, vec![2])],
+                author: None,
+            },
+            GitCommitCandidate {
+                msg: "remove 1".to_owned(),
+                file_paths: vec![],
+                hunks: vec![select(0, vec![1])],
+                author: None,
+            },
+        ];
+
+        commit_on_temp_branch(&mut helper, &commit_cands)?;
+
+        let mut expected = lines.clone();
+        expected[18] = "second\n".to_owned();
+        let show = run_git(dir, &["show", "HEAD~2:file.txt"])?;
+        assert_eq!(String::from_utf8(show.stdout)?, expected.concat());
+
+        expected.insert(2, "first\n".to_owned());
+        let show = run_git(dir, &["show", "HEAD~1:file.txt"])?;
+        assert_eq!(String::from_utf8(show.stdout)?, expected.concat());
+
+        let show = run_git(dir, &["show", "HEAD:file.txt"])?;
+        assert_eq!(String::from_utf8(show.stdout)?, changed.concat());
+
+        assert!(is_clean(dir)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_flow() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        let (lines, init, tip) = fake.commit_mixed_changes();
+
+        let mut helper = GitHelper::new(Box::new(fake.clone()), "HEAD~")?;
+        let mut app = App::new_with_diffs(helper.list_diffs()?).unwrap();
+
+        select_item(&mut app, "b.txt");
+        app.select();
+        select_item(&mut app, "c.txt");
+        app.select();
+        save_commit(&mut app, "files");
+
+        select_item(&mut app, "dir");
+        app.goto_child();
+        select_item(&mut app, "a.txt");
+        app.goto_child();
+        app.items.state.select(Some(0));
+        app.select();
+        save_commit(&mut app, "first hunk");
+
+        select_item(&mut app, "dir");
+        app.select();
+        save_commit(&mut app, "rest");
+        assert!(matches!(app.current_screen, CurrentScreen::PlanEditor));
+        app.apply_commits();
+        assert!(app.should_quit);
+
+        helper.checkout_to_temp_branch()?;
+        helper.reset()?;
+        let ids = helper.commit(&app.commits)?;
+        helper.replay()?;
+        helper.restore_branch()?;
+
+        assert_eq!(
+            fake.messages("HEAD")?,
+            vec!["rest", "first hunk", "files", "init"]
+        );
+        assert_eq!(fake.resolve_commit("HEAD")?, ids[2]);
+        assert_eq!(fake.tree_of("HEAD")?, fake.tree_of(&tip)?);
+        assert_eq!(fake.read_file("HEAD~2", "b.txt")?, None);
+        assert_eq!(fake.read_file("HEAD~2", "c.txt")?, Some("c\n".to_owned()));
+
+        let mut expected = lines.clone();
+        expected[1] = "first\n".to_owned();
+        assert_eq!(
+            fake.read_file("HEAD~1", "dir/a.txt")?,
+            Some(expected.concat())
+        );
+        assert_eq!(fake.current_branch_name()?, Some("main".to_owned()));
+        assert!(fake.staged_paths().is_empty());
+
+        let operations = fake.operations();
+        let temp_branch_name = operations[0]["checkout -b ".len()..].to_owned();
+        assert!(temp_branch_name.starts_with("tmp-branch/"));
+        assert_eq!(
+            operations[1..],
+            [
+                format!("reset {}", init),
+                "apply --cached".to_owned(),
+                "commit -m files".to_owned(),
+                "apply --cached".to_owned(),
+                "commit -m first hunk".to_owned(),
+                "apply --cached".to_owned(),
+                "commit -m rest".to_owned(),
+                "checkout -B main".to_owned(),
+                format!("branch -D {}", temp_branch_name),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_abort() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        fake.commit_files("init", &[]);
+        fake.commit_files(
+            "two files",
+            &[("a", Some("a\n")), ("b", Some("b\n"))],
+        );
+        let tip = fake.commit_files("after", &[("c", Some("c\n"))]);
+
+        let mut helper =
+            GitHelper::new_for_commit(Box::new(fake.clone()), "HEAD~")?;
+        let commit_cands = vec![candidate("partial", vec![PathBuf::from("a")])];
+
+        helper.backup()?;
+        helper.checkout_to_temp_branch()?;
+        helper.reset()?;
+        assert!(matches!(
+            helper.commit(&commit_cands),
+            Err(GitCommandError::ReplayConflictError { .. })
+        ));
+        helper.abort()?;
+
+        // The backup of the failed split is deleted.
+        assert!(fake.list_refs("refs/break-commits/")?.is_empty());
+        helper.backup()?;
+        assert!(helper.commit_with_plumbing(&commit_cands).is_err());
+        helper.discard_backup()?;
+        assert!(fake.list_refs("refs/break-commits/")?.is_empty());
+
+        assert_eq!(fake.resolve_commit("main")?, tip);
+        assert_eq!(fake.current_branch_name()?, Some("main".to_owned()));
+        assert!(fake.staged_paths().is_empty());
+        assert!(!fake
+            .operations()
+            .iter()
+            .any(|operation| operation.starts_with("checkout -B")));
+
+        Ok(())
+    }
+
+    backend_test!(test_rename);
+
+    fn test_rename(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+
+        let lines: Vec<String> = (0..20).map(|i| format!("{}\n", i)).collect();
+        prepare_single_file_project(dir, &lines.concat())?;
+
+        let mut changed = lines.clone();
+        changed[1] = "first\n".to_owned();
+        changed[18] = "second\n".to_owned();
+        run_git(dir, &["mv", "file.txt", "moved.txt"])?;
+        File::create(dir.join("moved.txt"))?
+            .write_all(changed.concat().as_bytes())?;
+        run_git(dir, &["commit", "-a", "-m", "move"])?;
+        let tip = resolve(dir, "HEAD")?;
+
+        let mut helper = GitHelper::new(new_backend(dir)?, "HEAD~")?;
+        assert_eq!(
+            helper.list()?,
+            vec![FileChange {
+                status: ChangeStatus::Renamed,
+                path: PathBuf::from("moved.txt"),
+                old_path: Some(PathBuf::from("file.txt")),
+            }]
+        );
+        let diffs = helper.list_diffs()?;
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].old_path, Some(PathBuf::from("file.txt")));
+
+        let path = PathBuf::from("moved.txt");
+        let commit_cands = vec![
+            GitCommitCandidate {
+                msg: "move".to_owned(),
+                file_paths: vec![],
+                hunks: vec![HunkSelection {
+                    path: path.clone(),
+                    index: 0,
+                    lines: diffs[0].hunks[0].changed_lines(),
+                    hash: None,
+                }],
+                author: None,
+            },
+            candidate("second", vec![path]),
+        ];
+
+        commit_on_temp_branch(&mut helper, &commit_cands)?;
+
+        let mut expected = lines.clone();
+        expected[1] = "first\n".to_owned();
+        let show = run_git(dir, &["show", "HEAD~1:moved.txt"])?;
+        assert_eq!(String::from_utf8(show.stdout)?, expected.concat());
+        let files = run_git(dir, &["ls-tree", "--name-only", "HEAD~1"])?;
+        assert_eq!(String::from_utf8(files.stdout)?, "moved.txt\n");
+
+        assert_eq!(tree(dir, "HEAD")?, tree(dir, &tip)?);
+        assert!(is_clean(dir)?);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    backend_test!(test_non_utf8_paths);
+
+    #[cfg(unix)]
+    fn test_non_utf8_paths(
+        new_backend: NewBackend,
+    ) -> Result<(), GitCommandError> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_single_file_project(dir, "a\n")?;
+
+        // Latin-1 names, which are not valid UTF-8.
+        let added = PathBuf::from(OsStr::from_bytes(b"caf\xe9.txt"));
+        let moved = PathBuf::from(OsStr::from_bytes(b"\xe0 la carte.txt"));
+        File::create(dir.join(&added))?.write_all(b"x\n")?;
+        Command::new("git")
+            .current_dir(dir)
+            .arg("add")
+            .arg(&added)
+            .output()?;
+        Command::new("git")
+            .current_dir(dir)
+            .arg("mv")
+            .arg("file.txt")
+            .arg(&moved)
+            .output()?;
+        run_git(dir, &["commit", "-m", "latin-1"])?;
+        let tip = resolve(dir, "HEAD")?;
+
+        let untracked = PathBuf::from(OsStr::from_bytes(b"na\xefve.txt"));
+        File::create(dir.join(&untracked))?;
+        let backend = new_backend(dir)?;
+        assert_eq!(backend.worktree_status()?.untracked, vec![untracked]);
+        std::fs::remove_file(dir.join(OsStr::from_bytes(b"na\xefve.txt")))?;
+
+        let mut helper = GitHelper::new(backend, "HEAD~")?;
+        let mut changes = helper.list()?;
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(
+            changes,
+            vec![
+                FileChange {
+                    status: ChangeStatus::Added,
+                    path: added.clone(),
+                    old_path: None,
+                },
+                FileChange {
+                    status: ChangeStatus::Renamed,
+                    path: moved.clone(),
+                    old_path: Some(PathBuf::from("file.txt")),
+                },
+            ]
+        );
+        let diffs = helper.list_diffs()?;
+        assert!(diffs.iter().any(|diff| diff.path == added));
+
+        let commit_cands = vec![
+            candidate("add", vec![added]),
+            candidate("move", vec![moved]),
+        ];
+
+        commit_on_temp_branch(&mut helper, &commit_cands)?;
+
+        let files = run_git(dir, &["ls-tree", "-z", "--name-only", "HEAD~1"])?;
+        assert_eq!(files.stdout, b"caf\xe9.txt\0file.txt\0");
+        assert_eq!(tree(dir, "HEAD")?, tree(dir, &tip)?);
+
+        Ok(())
+    }
+
+    backend_test!(test_copy);
+
+    fn test_copy(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+
+        let lines: Vec<String> = (0..20).map(|i| format!("{}\n", i)).collect();
+        prepare_single_file_project(dir, &lines.concat())?;
+        // The settings of `git diff` do not change the listing.
+        run_git(dir, &["config", "diff.renames", "copies"])?;
+
+        // The copy is made from the content before the change.
+        std::fs::copy(dir.join("file.txt"), dir.join("copy.txt"))?;
+        File::create(dir.join("file.txt"))?.write_all(b"ten\n")?;
+        run_git(dir, &["add", "file.txt", "copy.txt"])?;
+        run_git(dir, &["commit", "-q", "-m", "copy"])?;
+        let tip = resolve(dir, "HEAD")?;
+
+        let mut helper = GitHelper::new(new_backend(dir)?, "HEAD~")?;
+        let statuses: Vec<(ChangeStatus, Option<PathBuf>)> = helper
+            .list()?
+            .into_iter()
+            .map(|change| (change.status, change.old_path))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![(ChangeStatus::Added, None), (ChangeStatus::Modified, None)]
+        );
+
+        let commit_cands = vec![
+            candidate("modify", vec![PathBuf::from("file.txt")]),
+            candidate("copy", vec![PathBuf::from("copy.txt")]),
+        ];
+        commit_on_temp_branch(&mut helper, &commit_cands)?;
+
+        let show = run_git(dir, &["show", "HEAD:copy.txt"])?;
+        assert_eq!(String::from_utf8(show.stdout)?, lines.concat());
+        assert_eq!(tree(dir, "HEAD")?, tree(dir, &tip)?);
+        assert!(is_clean(dir)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_rename() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        fake.commit_files("init", &[("a", Some("a\n")), ("b", Some("b\n"))]);
+        fake.commit_files(
+            "change",
+            &[("a", None), ("dir/a", Some("a\n")), ("b", Some("c\n"))],
+        );
+
+        let mut helper = GitHelper::new(Box::new(fake.clone()), "HEAD~")?;
+        let mut app = App::new(helper.list()?).unwrap();
+
+        select_item(&mut app, "dir");
+        app.goto_child();
+        assert_eq!(app.items.items.len(), 1);
+        assert_eq!(app.items.items[0].get_old_path(), Some(PathBuf::from("a")));
+        app.select();
+        save_commit(&mut app, "rename");
+        select_item(&mut app, "b");
+        app.select();
+        save_commit(&mut app, "modify");
+
+        let planned = helper.plan(&app.commits)?;
+        assert!(planned[0].to_string().contains("\n a => dir/a | 0\n"));
+        assert!(planned[1].to_string().contains("\n b | 2 +-\n"));
+
+        commit_on_temp_branch(&mut helper, &app.commits)?;
+
+        assert_eq!(fake.read_file("HEAD~", "a")?, None);
+        assert_eq!(fake.read_file("HEAD~", "dir/a")?, Some("a\n".to_owned()));
+        assert_eq!(fake.read_file("HEAD~", "b")?, Some("b\n".to_owned()));
+        assert_eq!(fake.read_file("HEAD", "b")?, Some("c\n".to_owned()));
+
+        Ok(())
+    }
+
+    backend_test!(test_status);
+
+    fn test_status(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+
+        File::create(dir.join("dir_1/commit_1"))?.write_all(b"changed\n")?;
+        File::create(dir.join("added"))?.write_all(b"added\n")?;
+        run_git(dir, &["rm", "-q", "dir_2/commit_1"])?;
+        run_git(dir, &["add", "--chmod=+x", "dir_3/commit_1"])?;
+        run_git(dir, &["add", "dir_1/commit_1", "added"])?;
+        run_git(dir, &["commit", "-q", "-m", "status"])?;
+
+        let helper = GitHelper::new(new_backend(dir)?, "HEAD~")?;
+        let statuses: Vec<(ChangeStatus, PathBuf)> = helper
+            .list()?
+            .into_iter()
+            .map(|change| (change.status, change.path))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (ChangeStatus::Added, PathBuf::from("added")),
+                (ChangeStatus::Modified, PathBuf::from("dir_1/commit_1")),
+                (ChangeStatus::Deleted, PathBuf::from("dir_2/commit_1")),
+                (ChangeStatus::ModeChanged, PathBuf::from("dir_3/commit_1")),
+            ]
+        );
+
+        let diffs = helper.list_diffs()?;
+        let statuses: Vec<ChangeStatus> =
+            diffs.iter().map(|diff| diff.status()).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ChangeStatus::Added,
+                ChangeStatus::Modified,
+                ChangeStatus::Deleted,
+                ChangeStatus::ModeChanged,
+            ]
+        );
+
+        Ok(())
+    }
+
+    backend_test!(test_detached);
+
+    fn test_detached(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+
+        let branch_name = current_branch_name(dir)?;
+        let tip = resolve(dir, "HEAD")?;
+        run_git(dir, &["checkout", "-q", "--detach"])?;
+
+        let mut helper = GitHelper::new(new_backend(dir)?, "HEAD~")?;
+        let commit_cands = split_candidates(&helper)?;
+
+        commit_on_temp_branch(&mut helper, &commit_cands)?;
+
+        assert_eq!(current_branch_name(dir)?, None);
+        let log = run_git(dir, &["log", "--format=%s", "-3"])?;
+        assert_eq!(String::from_utf8(log.stdout)?, "second\nfirst\ncommit_2\n");
+        assert_eq!(
+            resolve(dir, branch_name.as_deref().unwrap_or_default())?,
+            tip
+        );
+        assert!(is_clean(dir)?);
+        let branches = run_git(dir, &["branch", "--list", "tmp-branch/*"])?;
+        assert!(branches.stdout.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_detached_abort() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        fake.commit_files("init", &[]);
+        fake.commit_files(
+            "two files",
+            &[("a", Some("a\n")), ("b", Some("b\n"))],
+        );
+        let tip = fake.commit_files("after", &[("c", Some("c\n"))]);
+        fake.detach_head()?;
+
+        // The branch is not moved to commits with another tree, even if
+        // nothing was committed.
+        let mut helper =
+            GitHelper::new_for_commit(Box::new(fake.clone()), "HEAD~")?;
+        helper.checkout_to_temp_branch()?;
+        helper.reset()?;
+        assert!(matches!(
+            helper.restore_branch(),
+            Err(GitCommandError::ReplayConflictError { .. })
+        ));
+
+        assert_eq!(fake.current_branch_name()?, None);
+        assert_eq!(fake.resolve_commit("HEAD")?, tip);
+        assert_eq!(fake.resolve_commit("main")?, tip);
+        assert!(fake.staged_paths().is_empty());
+
+        Ok(())
+    }
+
+    backend_test!(test_autostash);
+
+    fn test_autostash(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+
+        File::create(dir.join("dir_1/commit_1"))?.write_all(b"staged\n")?;
+        run_git(dir, &["add", "dir_1/commit_1"])?;
+        File::create(dir.join("dir_2/commit_1"))?.write_all(b"unstaged\n")?;
+        File::create(dir.join("untracked"))?.write_all(b"untracked\n")?;
+        let status = run_git(dir, &["status", "--porcelain"])?.stdout;
+
+        let mut helper = GitHelper::new(new_backend(dir)?, "HEAD~")?;
+        match helper.check_worktree(false) {
+            Err(GitCommandError::DirtyWorktreeError { status }) => {
+                assert_eq!(
+                    status,
+                    WorktreeStatus {
+                        staged: vec![PathBuf::from("dir_1/commit_1")],
+                        unstaged: vec![PathBuf::from("dir_2/commit_1")],
+                        untracked: vec![PathBuf::from("untracked")],
+                    }
+                )
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        helper.check_worktree(true)?;
+
+        let commit_cands = split_candidates(&helper)?;
+
+        helper.stash()?;
+        assert!(is_clean(dir)?);
+        commit_on_temp_branch(&mut helper, &commit_cands)?;
+        helper.unstash()?;
+
+        let log = run_git(dir, &["log", "--format=%s", "-3"])?;
+        assert_eq!(String::from_utf8(log.stdout)?, "second\nfirst\ncommit_2\n");
+        assert_eq!(run_git(dir, &["status", "--porcelain"])?.stdout, status);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_autostash() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        fake.commit_files("init", &[]);
+        fake.commit_files(
+            "two files",
+            &[("a", Some("a\n")), ("b", Some("b\n"))],
+        );
+        let status = WorktreeStatus {
+            staged: vec![PathBuf::from("a")],
+            unstaged: vec![],
+            untracked: vec![PathBuf::from("c")],
+        };
+        fake.set_worktree_status(status.clone());
+
+        let mut helper = GitHelper::new(Box::new(fake.clone()), "HEAD~")?;
+        assert!(helper.check_worktree(false).is_err());
+        helper.check_worktree(true)?;
+
+        let commit_cands = vec![candidate(
+            "both",
+            vec![PathBuf::from("a"), PathBuf::from("b")],
+        )];
+
+        helper.stash()?;
+        commit_on_temp_branch(&mut helper, &commit_cands)?;
+        helper.unstash()?;
+
+        let operations = fake.operations();
+        assert_eq!(operations.first().map(String::as_str), Some("stash push"));
+        assert_eq!(operations.last().map(String::as_str), Some("stash pop"));
+        assert_eq!(fake.worktree_status()?, status);
+
+        // The stash is named if it cannot be restored.
+        fake.set_worktree_status(status.clone());
+        helper.stash()?;
+        fake.set_worktree_status(status);
+        assert!(matches!(
+            helper.unstash(),
+            Err(GitCommandError::UnstashError { stash, .. }) if stash == "s1"
+        ));
+
+        Ok(())
+    }
+
+    backend_test!(test_undo);
+
+    fn split_in_two(
+        new_backend: NewBackend,
+        dir: &Path,
+        base: &str,
+    ) -> Result<Vec<String>, GitCommandError> {
+        let mut helper = GitHelper::new(new_backend(dir)?, base)?;
+        let commit_cands = split_candidates(&helper)?;
+
+        helper.backup()?;
+        let ids = commit_on_temp_branch(&mut helper, &commit_cands)?;
+        Ok(ids)
+    }
+
+    fn test_undo(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+
+        assert!(matches!(
+            undo(new_backend(dir)?.as_ref()),
+            Err(GitCommandError::NoBackupError)
+        ));
+
+        let tip = resolve(dir, "HEAD")?;
+        split_in_two(new_backend, dir, "HEAD~")?;
+        let split = resolve(dir, "HEAD")?;
+        split_in_two(new_backend, dir, "HEAD~2")?;
+
+        // The latest split is undone first.
+        assert_eq!(undo(new_backend(dir)?.as_ref())?, split);
+        assert_eq!(resolve(dir, "HEAD")?, split);
+        assert_eq!(undo(new_backend(dir)?.as_ref())?, tip);
+        assert!(matches!(
+            undo(new_backend(dir)?.as_ref()),
+            Err(GitCommandError::NoBackupError)
+        ));
+
+        let ids = split_in_two(new_backend, dir, "HEAD~")?;
+        run_git(dir, &["commit", "-q", "--allow-empty", "-m", "moved"])?;
+        match undo(new_backend(dir)?.as_ref()) {
+            Err(GitCommandError::BranchMovedError { expected, .. }) => {
+                assert_eq!(expected, ids[1])
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        run_git(dir, &["reset", "-q", "HEAD~"])?;
+        assert_eq!(undo(new_backend(dir)?.as_ref())?, tip);
+        assert_eq!(resolve(dir, "HEAD")?, tip);
+        assert!(is_clean(dir)?);
+        let refs = run_git(dir, &["for-each-ref", "refs/break-commits/"])?;
+        assert!(refs.stdout.is_empty());
+
+        // Only the splits of the current branch are undone.
+        let branch_name = current_branch_name(dir)?.unwrap_or_default();
+        run_git(dir, &["checkout", "-q", "-b", "feature/other"])?;
+        split_in_two(new_backend, dir, "HEAD~")?;
+        let split = resolve(dir, "HEAD")?;
+        run_git(dir, &["checkout", "-q", &branch_name])?;
+        assert!(matches!(
+            undo(new_backend(dir)?.as_ref()),
+            Err(GitCommandError::NoBackupError)
+        ));
+        run_git(dir, &["checkout", "-q", "--detach"])?;
+        assert!(matches!(
+            undo(new_backend(dir)?.as_ref()),
+            Err(GitCommandError::NoBackupError)
+        ));
+        run_git(dir, &["checkout", "-q", "feature/other"])?;
+        assert_eq!(resolve(dir, "HEAD")?, split);
+        assert_eq!(undo(new_backend(dir)?.as_ref())?, tip);
+
+        Ok(())
+    }
+
+    backend_test!(test_worktree);
+
+    fn test_worktree(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+        let main_tip = resolve(dir, "HEAD")?;
+
+        let worktree_dir = tempdir()?;
+        let worktree = worktree_dir.path().join("worktree");
+        Command::new("git")
+            .current_dir(dir)
+            .args(["worktree", "add", "-q", "-b", "feature"])
+            .arg(&worktree)
+            .output()?;
+
+        // Split from a subdirectory of the linked worktree.
+        let ids = split_in_two(new_backend, &worktree.join("dir_1"), "HEAD~")?;
+        assert_eq!(ids.len(), 2);
+
+        assert_eq!(current_branch_name(&worktree)?, Some("feature".to_owned()));
+        assert_eq!(resolve(&worktree, "HEAD")?, ids[1]);
+        assert_eq!(resolve(&worktree, "HEAD~2")?, resolve(dir, "HEAD~")?);
+        assert_eq!(resolve(dir, "HEAD")?, main_tip);
+        assert_eq!(tree(&worktree, "HEAD")?, tree(dir, "HEAD")?);
+        assert!(is_clean(&worktree)?);
+
+        // The worktree has a Git directory of its own.
+        let git_dir = new_backend(&worktree.join("dir_1"))?.git_dir()?;
+        assert_eq!(
+            git_dir.canonicalize()?,
+            dir.join(".git/worktrees/worktree").canonicalize()?
+        );
+
+        Ok(())
+    }
+
+    backend_test!(test_dry_run);
+
+    fn test_dry_run(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_single_file_project(dir, "a\n")?;
+        File::create(dir.join("file.txt"))?.write_all(b"a\nb\n")?;
+        File::create(dir.join("c"))?.write_all(b"c\n")?;
+        run_git(dir, &["add", "file.txt", "c"])?;
+        run_git(dir, &["commit", "-m", "change"])?;
+        let base = resolve(dir, "HEAD~")?;
+        let tip = resolve(dir, "HEAD")?;
+        let branch_name = current_branch_name(dir)?.unwrap_or_default();
+
+        let other = Signature {
+            name: "Other".to_owned(),
+            email: "other@example.com".to_owned(),
+            date: "1700000000 +0000".to_owned(),
+        };
+        let commit_cands = vec![
+            GitCommitCandidate {
+                msg: "first".to_owned(),
+                file_paths: vec![PathBuf::from("file.txt")],
+                hunks: vec![],
+                author: Some(other.clone()),
+            },
+            candidate("it's second", vec![PathBuf::from("c")]),
+        ];
+
+        let helper = GitHelper::new(new_backend(dir)?, "HEAD~")?;
+        let planned = helper.plan(&commit_cands)?;
+        assert_eq!(planned.len(), 2);
+        assert_eq!(planned[0].author, other);
+        assert_eq!(planned[1].author.name, "Anonymous");
+        assert_eq!(
+            planned[0].to_string(),
+            "Author: Other <other@example.com>\n\n    first\n\n \
+             file.txt | 1 +\n 1 file changed, 1 insertion(+), 0 deletions(-)\n"
+        );
+        assert_eq!(
+            planned[1].stats,
+            vec![FileStat {
+                path: PathBuf::from("c"),
+                old_path: None,
+                counts: Some((1, 0))
+            }]
+        );
+
+        let options = CommitOptions::default();
+        let other_env = "GIT_AUTHOR_NAME=Other \
+            GIT_AUTHOR_EMAIL=other@example.com \
+            GIT_AUTHOR_DATE='1700000000 +0000' ";
+        assert_eq!(
+            helper.porcelain_commands(&commit_cands, &options)?,
+            vec![
+                format!(
+                    "git update-ref --no-deref \
+                     refs/break-commits/backup/{}/<time> {}",
+                    branch_name, tip
+                ),
+                "git checkout -q -b tmp-branch/<random>".to_owned(),
+                format!("git reset -q {}", base),
+                "git apply --cached <patch 1>".to_owned(),
+                format!("{}git commit -q -m first", other_env),
+                "git apply --cached <patch 2>".to_owned(),
+                "git commit -q -m 'it'\\''s second'".to_owned(),
+                format!("git checkout -q -B {}", branch_name),
+                "git branch -D tmp-branch/<random>".to_owned(),
+                format!(
+                    "git update-ref --no-deref \
+                     refs/break-commits/result/{}/<time> <commit 2>",
+                    branch_name
+                ),
+            ]
+        );
+
+        let commands = helper.plumbing_commands(&commit_cands, &options)?;
+        assert_eq!(commands.len(), 11);
+        assert_eq!(
+            commands[4],
+            format!(
+                "{}git commit-tree <tree 1> -p {} -F <message 1>",
+                other_env, base
+            )
+        );
+        assert_eq!(
+            commands[9],
+            format!(
+                "git update-ref --no-deref refs/heads/{} <commit 2> {}",
+                branch_name, tip
+            )
+        );
+
+        // Nothing is changed.
+        assert_eq!(resolve(dir, "HEAD")?, tip);
+        assert!(run_git(dir, &["for-each-ref", "refs/break-commits/"])?
+            .stdout
+            .is_empty());
+        assert!(is_clean(dir)?);
+
+        Ok(())
+    }
+
+    backend_test!(test_read_file);
+
+    fn test_read_file(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_single_file_project(dir, "a\n")?;
+        create_dir(dir.join("sub"))?;
+        File::create(dir.join("sub").join("b"))?.write_all(b"b\n")?;
+        run_git(dir, &["add", "sub"])?;
+        run_git(dir, &["commit", "-m", "add b"])?;
+
+        // Paths are relative to the top of the repository.
+        let backend = new_backend(&dir.join("sub"))?;
+        let git_helper = GitHelper::new(backend, "HEAD~")?;
+        let content = git_helper.read_file(Path::new("file.txt"))?;
+        assert_eq!(content, Some(b"a\n".to_vec()));
+        let content = git_helper.read_file(Path::new("sub/b"))?;
+        assert_eq!(content, Some(b"b\n".to_vec()));
+        assert_eq!(git_helper.read_file(Path::new("b"))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_base() -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+
+        let base = resolve(dir, "HEAD~2")?;
+        assert_eq!(resolve(dir, "HEAD~2...")?, base);
+        assert_eq!(resolve(dir, "...HEAD~2")?, base);
+
+        let helper = GitHelper::new(cli(dir)?, "HEAD~2...")?;
+        assert_eq!(helper.base, base);
+        assert_eq!(helper.list()?.len(), 6);
+
+        run_git(dir, &["checkout", "-q", "-b", "side", "HEAD~1"])?;
+        run_git(dir, &["commit", "-q", "--allow-empty", "-m", "side"])?;
+        run_git(dir, &["checkout", "-q", "-"])?;
+
+        assert!(matches!(
+            GitHelper::new(cli(dir)?, "side"),
+            Err(GitCommandError::NotAncestorError { .. })
+        ));
+        assert!(matches!(
+            GitHelper::new(cli(dir)?, "side...").map(|helper| helper.base),
+            Ok(id) if id == resolve(dir, "HEAD~1")?
+        ));
+
+        Ok(())
+    }
+
+    backend_test!(test_commit);
+
+    fn test_commit(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+
+        let branch_name = current_branch_name(dir)?;
+        let tip = resolve(dir, "HEAD")?;
+
+        let mut helper =
+            GitHelper::new_for_commit(new_backend(dir)?, "HEAD~1")?;
+        assert_eq!(list_paths(&helper)?.len(), 3);
+        let commit_cands = split_candidates(&helper)?;
+
+        helper.checkout_to_temp_branch()?;
+        helper.reset()?;
+        helper.commit(&commit_cands)?;
+        helper.replay()?;
+        helper.restore_branch()?;
+
+        let log = run_git(dir, &["log", "--format=%s"])?;
+        assert_eq!(
+            String::from_utf8(log.stdout)?,
+            "commit_3\nsecond\nfirst\ncommit_1\ninit\n"
+        );
+        assert_eq!(tree(dir, "HEAD")?, tree(dir, &tip)?);
+        assert_eq!(branch_name, current_branch_name(dir)?);
+        assert!(is_clean(dir)?);
+
+        // The split is aborted if the split commit is not fully committed.
+        let tip = resolve(dir, "HEAD")?;
+        let mut helper =
+            GitHelper::new_for_commit(new_backend(dir)?, "HEAD~2")?;
+        let commit_cands =
+            vec![candidate("partial", list_paths(&helper)?.split_off(1))];
+
+        helper.checkout_to_temp_branch()?;
+        helper.reset()?;
+        assert!(matches!(
+            helper.commit(&commit_cands),
+            Err(GitCommandError::ReplayConflictError { .. })
+        ));
+        helper.abort()?;
+
+        assert_eq!(resolve(dir, "HEAD")?, tip);
+        assert_eq!(branch_name, current_branch_name(dir)?);
+        assert!(is_clean(dir)?);
+        let branches = run_git(dir, &["branch", "--list", "tmp-branch/*"])?;
+        assert!(branches.stdout.is_empty());
+
+        Ok(())
+    }
+
+    backend_test!(test_plumbing);
+
+    fn test_plumbing(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+
+        let branch_name = current_branch_name(dir)?;
+        let tip = resolve(dir, "HEAD")?;
+        let index = std::fs::read(dir.join(".git/index"))?;
+
+        let helper = GitHelper::new_for_commit(new_backend(dir)?, "HEAD~1")?;
+        let commit_cands = split_candidates(&helper)?;
+
+        let ids = helper.commit_with_plumbing(&commit_cands)?;
+        assert_eq!(resolve(dir, "HEAD~1")?, ids[1]);
+
+        let log = run_git(dir, &["log", "--format=%s"])?;
+        assert_eq!(
+            String::from_utf8(log.stdout)?,
+            "commit_3\nsecond\nfirst\ncommit_1\ninit\n"
+        );
+        assert_eq!(tree(dir, "HEAD")?, tree(dir, &tip)?);
+        assert_eq!(branch_name, current_branch_name(dir)?);
+        assert_eq!(std::fs::read(dir.join(".git/index"))?, index);
+        assert!(is_clean(dir)?);
+
+        // The branch is not moved over a commit made in the meantime.
+        let helper = GitHelper::new(new_backend(dir)?, "HEAD~1")?;
+        let commit_cands = vec![candidate("all", list_paths(&helper)?)];
+        run_git(dir, &["commit", "-q", "--allow-empty", "-m", "moved"])?;
+        let moved = resolve(dir, "HEAD")?;
+
+        assert!(helper.commit_with_plumbing(&commit_cands).is_err());
+        assert_eq!(resolve(dir, "HEAD")?, moved);
+        assert_eq!(branch_name, current_branch_name(dir)?);
+
+        Ok(())
+    }
+
+    backend_test!(test_latin1_commit);
+
+    #[cfg(unix)]
+    fn test_latin1_commit(
+        new_backend: NewBackend,
+    ) -> Result<(), GitCommandError> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+
+        // A Latin-1 commit, whose message and author are not valid UTF-8.
+        let message = b"caf\xe9\n".to_vec();
+        File::create(dir.join("message"))?.write_all(&message)?;
+        let status = Command::new("git")
+            .current_dir(dir)
+            .env("GIT_AUTHOR_NAME", OsStr::from_bytes(b"Jos\xe9"))
+            .args(["-c", "i18n.commitEncoding=ISO-8859-1", "commit"])
+            .args(["-q", "--allow-empty", "-F", "message"])
+            .status()?;
+        assert!(status.success());
+        std::fs::remove_file(dir.join("message"))?;
+
+        let helper = GitHelper::new_for_commit(new_backend(dir)?, "HEAD~")?;
+        let commit_cands = vec![candidate("all", list_paths(&helper)?)];
+        helper.commit_with_plumbing(&commit_cands)?;
+
+        // The message is replayed as is and keeps its encoding.
+        let backend = new_backend(dir)?;
+        let info = backend.read_commit(&resolve(dir, "HEAD")?)?;
+        assert_eq!(info.message, message);
+        assert_eq!(info.encoding.as_deref(), Some("ISO-8859-1"));
+        let info = backend.read_commit(&resolve(dir, "HEAD~")?)?;
+        assert_eq!(info.message, b"all\n");
+        assert_eq!(info.encoding, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_libgit2_options() -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+
+        // Signing is refused instead of being ignored.
+        let options = CommitOptions {
+            gpg_sign: Some(String::new()),
+            ..CommitOptions::default()
+        };
+        assert!(matches!(
+            Libgit2Backend::new_with_options(dir, &options),
+            Err(GitCommandError::UnsupportedOptionError { .. })
+        ));
+        run_git(dir, &["config", "commit.gpgSign", "true"])?;
+        assert!(matches!(
+            Libgit2Backend::new(dir),
+            Err(GitCommandError::UnsupportedOptionError { .. })
+        ));
+        let options = CommitOptions {
+            no_gpg_sign: true,
+            ..CommitOptions::default()
+        };
+        Libgit2Backend::new_with_options(dir, &options)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_plumbing() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        fake.commit_files("init", &[]);
+        fake.commit_files(
+            "two files",
+            &[("a", Some("a\n")), ("b", Some("b\n"))],
+        );
+        fake.detach_head()?;
+
+        let helper = GitHelper::new(Box::new(fake.clone()), "HEAD~")?;
+        let commit_cands = vec![
+            candidate("a", vec![PathBuf::from("a")]),
+            candidate("b", vec![PathBuf::from("b")]),
+        ];
+        let ids = helper.commit_with_plumbing(&commit_cands)?;
+
+        assert_eq!(fake.current_branch_name()?, None);
+        assert_eq!(fake.resolve_commit("HEAD")?, ids[1]);
+        assert_eq!(fake.messages("HEAD")?, vec!["b\n", "a\n", "init"]);
+        assert_eq!(fake.read_file("HEAD~", "b")?, None);
+        // Only the detaching of HEAD checks anything out.
+        let operations = fake.operations();
+        assert_eq!(operations[0], "checkout --detach");
+        assert!(operations[1..].iter().all(|operation| {
+            !operation.starts_with("checkout")
+                && !operation.starts_with("reset")
+        }));
+
+        Ok(())
+    }
+
+    backend_test!(test_authors);
+
+    fn test_authors(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_single_file_project(dir, "init\n")?;
+
+        File::create(dir.join("a"))?.write_all(b"a\n")?;
+        run_git(dir, &["add", "a"])?;
+        run_git(
+            dir,
+            &[
+                "commit",
+                "-q",
+                "-m",
+                "a",
+                "--author=Alice <alice@example.com>",
+                "--date=1700000000 +0900",
+            ],
+        )?;
+        File::create(dir.join("b"))?.write_all(b"b\n")?;
+        run_git(dir, &["add", "b"])?;
+        run_git(dir, &["commit", "-q", "-m", "b"])?;
+
+        let helper = GitHelper::new(new_backend(dir)?, "HEAD~2")?;
+        let authors = Authors::new(&helper.list_commit_infos()?);
+        let names: Vec<&str> = authors
+            .signatures
+            .iter()
+            .map(|signature| signature.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Alice", "Anonymous"]);
+
+        let alice = authors.majority(&[PathBuf::from("a")]).cloned();
+        assert_eq!(
+            alice.as_ref().map(|a| a.date.as_str()),
+            Some("1700000000 +0900")
+        );
+
+        for engine in ["porcelain", "plumbing"] {
+            let mut helper = GitHelper::new(new_backend(dir)?, "HEAD~2")?;
+            let commit_cands = vec![
+                GitCommitCandidate {
+                    msg: "a".to_owned(),
+                    file_paths: vec![PathBuf::from("a")],
+                    hunks: vec![],
+                    author: alice.clone(),
+                },
+                candidate("b", vec![PathBuf::from("b")]),
+            ];
+            if engine == "plumbing" {
+                helper.commit_with_plumbing(&commit_cands)?;
+            } else {
+                helper.checkout_to_temp_branch()?;
+                helper.reset()?;
+                helper.commit(&commit_cands)?;
+                helper.restore_branch()?;
+            }
+
+            let log = run_git(
+                dir,
+                &["log", "--format=%an <%ae> %ad %cn", "--date=raw", "-2"],
+            )?;
+            let log = String::from_utf8(log.stdout)?;
+            let lines: Vec<&str> = log.lines().collect();
+            assert!(
+                lines[0].starts_with("Anonymous <anonymous@anonymous.com> ")
+            );
+            assert_eq!(
+                lines[1],
+                "Alice <alice@example.com> 1700000000 +0900 Anonymous"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_authors() -> Result<(), GitCommandError> {
+        let alice = Signature {
+            name: "Alice".to_owned(),
+            email: "alice@example.com".to_owned(),
+            date: "1 +0000".to_owned(),
+        };
+        let bob = Signature {
+            name: "Bob".to_owned(),
+            email: "bob@example.com".to_owned(),
+            date: "2 +0000".to_owned(),
+        };
+
+        let fake = FakeBackend::new();
+        fake.commit_files("init", &[]);
+        fake.commit_files_as(
+            alice.clone(),
+            "alice",
+            &[("a", Some("a\n")), ("b", Some("b\n"))],
+        );
+        fake.commit_files_as(bob.clone(), "bob", &[("c", Some("c\n"))]);
+
+        let mut helper = GitHelper::new(Box::new(fake.clone()), "HEAD~2")?;
+        let mut app = App::new(helper.list()?).unwrap();
+        app.authors = Authors::new(&helper.list_commit_infos()?);
+        assert_eq!(app.authors.signatures, vec![alice.clone(), bob.clone()]);
+
+        // A tie between Alice and Bob goes to Alice, who committed first.
+        for key in ["a", "b", "c"] {
+            select_item(&mut app, key);
+            app.select();
+        }
+        select_item(&mut app, "b");
+        app.select();
+        assert_eq!(app.get_author(), Some(alice.clone()));
+        save_commit(&mut app, "a and c");
+
+        // Bob is picked explicitly after the committer.
+        select_item(&mut app, "b");
+        app.select();
+        app.next_author();
+        assert_eq!(app.get_author(), None);
+        app.next_author();
+        app.next_author();
+        assert_eq!(app.get_author(), Some(bob.clone()));
+        save_commit(&mut app, "b");
+
+        commit_on_temp_branch(&mut helper, &app.commits)?;
+
+        assert_eq!(fake.read_commit("HEAD~")?.author, alice);
+        assert_eq!(fake.read_commit("HEAD")?.author, bob);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_messages() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        fake.commit_files("init", &[]);
+        fake.commit_files("add a\n\nbody\n", &[("a", Some("a\n"))]);
+        fake.commit_files("add b", &[("a", Some("b\n")), ("b", Some("b\n"))]);
+
+        let helper = GitHelper::new(Box::new(fake.clone()), "HEAD~2")?;
+        let messages = Messages::new(&helper.list_commit_infos()?);
+        assert_eq!(messages.for_paths(&[PathBuf::from("b")]), vec!["add b"]);
+
+        let mut app = App::new(helper.list()?).unwrap();
+        app.messages = messages;
+        select_item(&mut app, "b");
+        app.select();
+        app.open_editor();
+        assert_eq!(app.textarea.lines(), ["add b"]);
+
+        // The draft follows the selection until it is edited.
+        app.close_editor();
+        select_item(&mut app, "a");
+        app.select();
+        app.open_editor();
+        assert_eq!(app.textarea.lines(), ["add a", "", "body", "", "add b"]);
+
+        app.textarea.insert_str("edited ");
+        app.close_editor();
+        app.select();
+        app.open_editor();
+        assert_eq!(app.textarea.lines()[0], "edited add a");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hooks_and_signing() -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+        let tip = resolve(dir, "HEAD")?;
+
+        let hook = ".git/hooks/commit-msg";
+        File::create(dir.join(hook))?.write_all(
+            b"#!/bin/sh\ngrep -q ok \"$1\" || { echo 'say ok' >&2; exit 1; }\n",
+        )?;
+        Command::new("chmod")
+            .current_dir(dir)
+            .args(["+x", hook])
+            .output()?;
+
+        let commit_cands = |msg: &str, helper: &GitHelper| {
+            Ok::<_, GitCommandError>(vec![candidate(msg, list_paths(helper)?)])
+        };
+
+        let mut helper = GitHelper::new(cli(dir)?, "HEAD~")?;
+        helper.checkout_to_temp_branch()?;
+        helper.reset()?;
+        match helper.commit(&commit_cands("bad", &helper)?) {
+            Err(GitCommandError::CommitRejectedError { index, output }) => {
+                assert_eq!(index, 0);
+                assert_eq!(output, "say ok\n");
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        helper.abort()?;
+        assert_eq!(resolve(dir, "HEAD")?, tip);
+
+        // The hook is skipped and the commit is signed with an SSH key.
+        let keygen = Command::new("ssh-keygen")
+            .current_dir(dir)
+            .args(["-q", "-t", "ed25519", "-N", "", "-f", "key"])
+            .output();
+        match keygen {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!("ssh-keygen is not found, skipping the signing");
+                return Ok(());
+            }
+            keygen => assert!(keygen?.status.success()),
+        }
+        run_git(dir, &["config", "gpg.format", "ssh"])?;
+        run_git(dir, &["config", "user.signingkey", "key"])?;
+
+        let options = CommitOptions {
+            no_verify: true,
+            gpg_sign: Some(String::new()),
+            no_gpg_sign: false,
+        };
+        let backend = Box::new(CliBackend::new_with_options(dir, options));
+        let mut helper = GitHelper::new(backend, "HEAD~")?;
+        helper.checkout_to_temp_branch()?;
+        helper.reset()?;
+        helper.commit(&commit_cands("bad", &helper)?)?;
+        helper.restore_branch()?;
+
+        let commits = helper.list_new_commits()?;
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].1.message, b"bad\n");
+        assert!(commits[0].1.signed);
+        assert!(!libgit2(dir)?.read_commit(&tip)?.signed);
+        assert!(libgit2(dir)?.read_commit(&commits[0].0)?.signed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_rejected_commit() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        fake.commit_files("init", &[]);
+        let tip = fake.commit_files("a", &[("a", Some("a\n"))]);
+        fake.set_commit_msg_hook(|msg| {
+            (!msg.contains("ok")).then(|| "say ok\n".to_owned())
+        });
+
+        let mut helper = GitHelper::new(Box::new(fake.clone()), "HEAD~")?;
+        let mut app = App::new(helper.list()?).unwrap();
+        select_item(&mut app, "a");
+        app.select();
+        save_commit(&mut app, "bad");
+        app.apply_commits();
+        assert!(app.should_quit);
+
+        helper.checkout_to_temp_branch()?;
+        helper.reset()?;
+        let output = match helper.commit(&app.commits) {
+            Err(GitCommandError::CommitRejectedError { index, output }) => {
+                assert_eq!(index, 0);
+                output
+            }
+            result => panic!("unexpected result: {:?}", result),
+        };
+        helper.abort()?;
+        assert_eq!(fake.resolve_commit("HEAD")?, tip);
+
+        // The message is fixed in the reopened editor.
+        app.reject_commit(0, &output);
+        assert!(!app.should_quit);
+        assert_eq!(app.textarea.lines(), ["bad"]);
+        app.close_popup();
+        app.textarea.insert_str("ok ");
+        app.save_commit().unwrap();
+        assert!(app.should_quit);
+        assert_eq!(app.retrying, None);
+
+        commit_on_temp_branch(&mut helper, &app.commits)?;
+        assert_eq!(fake.messages("HEAD")?, vec!["ok bad", "init"]);
+
+        Ok(())
+    }
+
+    backend_test!(test_merges);
+
+    fn test_merges(new_backend: NewBackend) -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_single_file_project(dir, "init\n")?;
+
+        run_git(dir, &["checkout", "-q", "-b", "side"])?;
+        File::create(dir.join("side"))?.write_all(b"side\n")?;
+        run_git(dir, &["add", "side"])?;
+        run_git(dir, &["commit", "-q", "-m", "side"])?;
+        run_git(dir, &["checkout", "-q", "-"])?;
+        File::create(dir.join("main"))?.write_all(b"main\n")?;
+        run_git(dir, &["add", "main"])?;
+        run_git(dir, &["commit", "-q", "-m", "main"])?;
+        run_git(dir, &["merge", "-q", "--no-ff", "-m", "merge side", "side"])?;
+        let merge = resolve(dir, "HEAD")?;
+
+        let helper = GitHelper::new(new_backend(dir)?, "HEAD~2")?;
+        match helper.check_merges(false) {
+            Err(GitCommandError::MergeInRangeError { merges }) => {
+                assert_eq!(merges, vec![merge.clone()])
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let folded = helper.check_merges(true)?;
+        assert_eq!(folded.len(), 1);
+        assert_eq!(folded[0].id, merge);
+        assert_eq!(
+            folded[0].to_string(),
+            format!("{} merge side\n  A side\n", &merge[..7])
+        );
+
+        let mut helper = helper;
+        let commit_cands = vec![candidate("flat", list_paths(&helper)?)];
+        commit_on_temp_branch(&mut helper, &commit_cands)?;
+
+        let log = run_git(dir, &["log", "--format=%s"])?;
+        assert_eq!(String::from_utf8(log.stdout)?, "flat\ninit\n");
+
+        let helper = GitHelper::new(new_backend(dir)?, "HEAD~")?;
+        assert!(helper.check_merges(false)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fake_merges() -> Result<(), GitCommandError> {
+        let fake = FakeBackend::new();
+        fake.commit_files("init", &[]);
+        let side = fake.commit_files("side", &[("side", Some("side\n"))]);
+        fake.reset("HEAD~")?;
+        fake.commit_files("split", &[("a", Some("a\n"))]);
+        let merge =
+            fake.merge_files(&side, "merge", &[("side", Some("side\n"))]);
+
+        let helper =
+            GitHelper::new_for_commit(Box::new(fake.clone()), "HEAD~")?;
+        match helper.check_merges(true) {
+            Err(GitCommandError::ReplayMergeError { commit }) => {
+                assert_eq!(commit, merge)
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        Ok(())
+    }
+
+    fn prepare_single_file_project(
+        dir: &Path,
+        content: &str,
+    ) -> Result<(), GitCommandError> {
+        run_git(dir, &["init"])?;
+        run_git(dir, &["config", "--local", "user.name", "Anonymous"])?;
+        run_git(
+            dir,
+            &["config", "--local", "user.email", "anonymous@anonymous.com"],
+        )?;
+        File::create(dir.join("file.txt"))?.write_all(content.as_bytes())?;
+        run_git(dir, &["add", "file.txt"])?;
+        run_git(dir, &["commit", "-m", "init"])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test() -> Result<(), GitCommandError> {
+        let temp_dir = tempdir()?;
+        let dir = temp_dir.path();
+        prepare_git_project(dir)?;
+        let branch_name = current_branch_name(dir)?;
+        let tip = resolve(dir, "HEAD")?;
+
+        // Run from some directory not equal to the project root directory
+        let mut helper = GitHelper::new(cli(&dir.join("dir_1"))?, "HEAD~3")?;
+        let commit_cands = vec![candidate("test", list_paths(&helper)?)];
+        let ids = commit_on_temp_branch(&mut helper, &commit_cands)?;
+
+        assert_eq!(ids, vec![resolve(dir, "HEAD")?]);
+        assert_eq!(
+            resolve(dir, "HEAD~")?,
+            resolve(dir, &format!("{}~3", tip))?
+        );
+        assert_eq!(tree(dir, "HEAD")?, tree(dir, &tip)?);
+        assert_eq!(branch_name, current_branch_name(dir)?);
+
+        Ok(())
+    }
+
+    fn prepare_git_project(dir: &Path) -> Result<Output, std::io::Error> {
         let dir_names = ["dir_1", "dir_2", "dir_3"];
         let file_names = ["commit_1", "commit_2", "commit_3"];
-        let curr_dir = env::current_dir()?;
-
         for dir_name in dir_names {
-            let dir_path = curr_dir.join(dir_name);
+            let dir_path = dir.join(dir_name);
             // let dir_path = Path:: dir_name.;
             create_dir(&dir_path)?;
 
@@ -313,11 +2784,13 @@ mod tests {
                 let _ = file.write(b"")?;
             }
         }
-        let init = Command::new("git").arg("init").output()?;
-        let set_author_name = Command::new("git")
+        Command::new("git").current_dir(dir).arg("init").output()?;
+        Command::new("git")
+            .current_dir(dir)
             .args(["config", "--local", "user.name", "Anonymous"])
             .output()?;
-        let set_author_email = Command::new("git")
+        Command::new("git")
+            .current_dir(dir)
             .args([
                 "config",
                 "--local",
@@ -326,25 +2799,28 @@ mod tests {
             ])
             .output()?;
         Command::new("git")
+            .current_dir(dir)
             .arg("commit")
             .args(["-m", "init", "--allow-empty"])
             .output()?;
         for fname in file_names {
             let mut commit_file_list = vec![];
             for dir_name in dir_names {
-                commit_file_list.push(curr_dir.join(dir_name).join(fname));
+                commit_file_list.push(dir.join(dir_name).join(fname));
             }
-            let add = Command::new("git")
+            Command::new("git")
+                .current_dir(dir)
                 .arg("add")
                 .args(commit_file_list)
                 .output()?;
 
-            let commit = Command::new("git")
+            Command::new("git")
+                .current_dir(dir)
                 .arg("commit")
                 .args(["-m", fname])
                 .output()?;
         }
 
-        Command::new("git").arg("log").output()
+        Command::new("git").current_dir(dir).arg("log").output()
     }
 }
@@ -0,0 +1,528 @@
+use git2::{
+    ApplyLocation, BranchType, Delta, Diff, DiffFindOptions, DiffFormat,
+    DiffOptions, ErrorCode, ObjectType, Oid, Repository, ResetType, Sort,
+    StashApplyOptions, StashFlags, Status, StatusOptions, Time,
+};
+
+use crate::git_backend::{
+    bytes_to_path, ChangeStatus, CommitInfo, CommitOptions, FileChange,
+    GitBackend, Signature, WorktreeStatus,
+};
+use crate::git_helper::GitCommandError;
+use std::path::{Path, PathBuf};
+
+/// Backend which runs Git operations in-process with libgit2.
+///
+/// It does not need a `git` executable, but it does not run Git hooks and it
+/// cannot sign commits.
+pub struct Libgit2Backend {
+    repo: Repository,
+}
+
+impl Libgit2Backend {
+    /// Opens the repository containing the path, which may be a
+    /// subdirectory or a linked worktree.
+    pub fn new(repo: &Path) -> Result<Libgit2Backend, GitCommandError> {
+        Libgit2Backend::new_with_options(repo, &CommitOptions::default())
+    }
+
+    /// Opens the repository, refusing the options which libgit2 cannot
+    /// honor instead of ignoring them.
+    ///
+    /// Skipping the hooks is what libgit2 always does, but signing is
+    /// refused, whether it is asked for or configured by `commit.gpgSign`.
+    pub fn new_with_options(
+        repo: &Path,
+        options: &CommitOptions,
+    ) -> Result<Libgit2Backend, GitCommandError> {
+        let repo = Repository::discover(repo)?;
+
+        if options.gpg_sign.is_some() {
+            return Err(GitCommandError::UnsupportedOptionError {
+                option: "--gpg-sign".to_owned(),
+            });
+        }
+        let gpg_sign = repo.config()?.get_bool("commit.gpgSign");
+        if !options.no_gpg_sign && gpg_sign.unwrap_or(false) {
+            return Err(GitCommandError::UnsupportedOptionError {
+                option: "commit.gpgSign (use --no-gpg-sign)".to_owned(),
+            });
+        }
+
+        Ok(Libgit2Backend { repo })
+    }
+
+    /// Opens another handle of the repository, e.g. for stashing which needs
+    /// a mutable one.
+    fn reopen(&self) -> Result<Repository, GitCommandError> {
+        let path = self.repo.workdir().unwrap_or(self.repo.path());
+        Ok(Repository::open(path)?)
+    }
+
+    fn find_commit(
+        &self,
+        commit: &str,
+    ) -> Result<git2::Commit<'_>, GitCommandError> {
+        Ok(self.repo.find_commit(Oid::from_str(commit)?)?)
+    }
+
+    fn head_commit(&self) -> Result<git2::Commit<'_>, GitCommandError> {
+        Ok(self.repo.head()?.peel_to_commit()?)
+    }
+
+    fn diff(
+        &self,
+        base: &str,
+        head: &str,
+    ) -> Result<Diff<'_>, GitCommandError> {
+        let base_tree = self.find_commit(base)?.tree()?;
+        let head_tree = self.find_commit(head)?.tree()?;
+
+        let mut options = DiffOptions::new();
+        options.show_binary(true).id_abbrev(40);
+
+        let mut diff = self.repo.diff_tree_to_tree(
+            Some(&base_tree),
+            Some(&head_tree),
+            Some(&mut options),
+        )?;
+        diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+        Ok(diff)
+    }
+}
+
+fn to_signature(signature: &git2::Signature) -> Signature {
+    let when = signature.when();
+    let offset = when.offset_minutes();
+    let sign = if offset < 0 { '-' } else { '+' };
+
+    Signature {
+        name: String::from_utf8_lossy(signature.name_bytes()).into_owned(),
+        email: String::from_utf8_lossy(signature.email_bytes()).into_owned(),
+        date: format!(
+            "{} {}{:02}{:02}",
+            when.seconds(),
+            sign,
+            offset.abs() / 60,
+            offset.abs() % 60
+        ),
+    }
+}
+
+fn from_signature(
+    signature: &Signature,
+) -> Result<git2::Signature<'static>, GitCommandError> {
+    let invalid = || GitCommandError::InvalidObjectError {
+        id: signature.date.clone(),
+    };
+
+    let (seconds, offset) =
+        signature.date.split_once(' ').ok_or_else(invalid)?;
+    let seconds: i64 = seconds.parse().map_err(|_| invalid())?;
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let offset: i32 = offset
+        .trim_start_matches(['+', '-'])
+        .parse()
+        .map_err(|_| invalid())?;
+    let offset = sign * (offset / 100 * 60 + offset % 100);
+
+    Ok(git2::Signature::new(
+        &signature.name,
+        &signature.email,
+        &Time::new(seconds, offset),
+    )?)
+}
+
+impl GitBackend for Libgit2Backend {
+    fn resolve_commit(&self, rev: &str) -> Result<String, GitCommandError> {
+        let object = self.repo.revparse_single(rev)?;
+        Ok(object.peel_to_commit()?.id().to_string())
+    }
+
+    fn merge_base(
+        &self,
+        left: &str,
+        right: &str,
+    ) -> Result<String, GitCommandError> {
+        let left = self.repo.revparse_single(left)?.peel_to_commit()?.id();
+        let right = self.repo.revparse_single(right)?.peel_to_commit()?.id();
+        Ok(self.repo.merge_base(left, right)?.to_string())
+    }
+
+    fn is_ancestor(
+        &self,
+        ancestor: &str,
+        descendant: &str,
+    ) -> Result<bool, GitCommandError> {
+        let ancestor = Oid::from_str(ancestor)?;
+        let descendant = Oid::from_str(descendant)?;
+        Ok(ancestor == descendant
+            || self.repo.graph_descendant_of(descendant, ancestor)?)
+    }
+
+    fn worktree_status(&self) -> Result<WorktreeStatus, GitCommandError> {
+        let mut options = StatusOptions::new();
+        options.include_untracked(true);
+
+        let mut status = WorktreeStatus::default();
+        for entry in self.repo.statuses(Some(&mut options))?.iter() {
+            let path = bytes_to_path(entry.path_bytes());
+            let flags = entry.status();
+
+            if flags.contains(Status::WT_NEW) {
+                status.untracked.push(path);
+                continue;
+            }
+            if flags.intersects(
+                Status::INDEX_NEW
+                    | Status::INDEX_MODIFIED
+                    | Status::INDEX_DELETED
+                    | Status::INDEX_RENAMED
+                    | Status::INDEX_TYPECHANGE,
+            ) {
+                status.staged.push(path.clone());
+            }
+            if flags.intersects(
+                Status::WT_MODIFIED
+                    | Status::WT_DELETED
+                    | Status::WT_RENAMED
+                    | Status::WT_TYPECHANGE,
+            ) {
+                status.unstaged.push(path);
+            }
+        }
+
+        Ok(status)
+    }
+
+    fn stash_push(&self) -> Result<String, GitCommandError> {
+        let mut repo = self.reopen()?;
+        let signature = repo.signature()?;
+        let id = repo.stash_save(
+            &signature,
+            "git-break-commits autostash",
+            Some(StashFlags::INCLUDE_UNTRACKED),
+        )?;
+        Ok(id.to_string())
+    }
+
+    fn stash_pop(&self) -> Result<(), GitCommandError> {
+        let mut repo = self.reopen()?;
+        let mut options = StashApplyOptions::new();
+        options.reinstantiate_index();
+        repo.stash_pop(0, Some(&mut options))?;
+        Ok(())
+    }
+
+    fn current_branch_name(&self) -> Result<Option<String>, GitCommandError> {
+        if self.repo.head_detached()? {
+            return Ok(None);
+        }
+        let head = self.repo.find_reference("HEAD")?;
+        let target = head.symbolic_target().unwrap_or_default();
+        Ok(Some(target.trim_start_matches("refs/heads/").to_owned()))
+    }
+
+    fn git_dir(&self) -> Result<PathBuf, GitCommandError> {
+        Ok(self.repo.path().to_path_buf())
+    }
+
+    fn diff_files(
+        &self,
+        base: &str,
+        head: &str,
+    ) -> Result<Vec<FileChange>, GitCommandError> {
+        Ok(self
+            .diff(base, head)?
+            .deltas()
+            .filter_map(|delta| {
+                let old_file = delta.old_file();
+                let new_file = delta.new_file();
+                let old_path = old_file.path().map(|p| p.to_owned());
+                let path = new_file.path().map(|p| p.to_owned());
+
+                let status = match delta.status() {
+                    Delta::Added => ChangeStatus::Added,
+                    Delta::Deleted => ChangeStatus::Deleted,
+                    Delta::Renamed => ChangeStatus::Renamed,
+                    Delta::Typechange => ChangeStatus::TypeChanged,
+                    Delta::Modified
+                        if old_file.mode() != new_file.mode()
+                            && old_file.id() == new_file.id() =>
+                    {
+                        ChangeStatus::ModeChanged
+                    }
+                    _ => ChangeStatus::Modified,
+                };
+
+                match status {
+                    ChangeStatus::Renamed => Some(FileChange {
+                        status,
+                        path: path?,
+                        old_path,
+                    }),
+                    _ => Some(FileChange {
+                        status,
+                        path: path.or(old_path)?,
+                        old_path: None,
+                    }),
+                }
+            })
+            .collect())
+    }
+
+    fn diff_patch(
+        &self,
+        base: &str,
+        head: &str,
+    ) -> Result<Vec<u8>, GitCommandError> {
+        let mut patch = vec![];
+        self.diff(base, head)?
+            .print(DiffFormat::Patch, |_, _, line| {
+                if let origin @ ('+' | '-' | ' ') = line.origin() {
+                    patch.push(origin as u8);
+                }
+                patch.extend_from_slice(line.content());
+                true
+            })?;
+        Ok(patch)
+    }
+
+    fn create_and_switch_branch(
+        &self,
+        name: &str,
+    ) -> Result<(), GitCommandError> {
+        let branch = self.repo.branch(name, &self.head_commit()?, false)?;
+        let refname = branch.get().name().unwrap_or_default().to_owned();
+        self.repo.set_head(&refname)?;
+        Ok(())
+    }
+
+    fn reset_and_switch_branch(
+        &self,
+        name: &str,
+    ) -> Result<(), GitCommandError> {
+        let branch = self.repo.branch(name, &self.head_commit()?, true)?;
+        let refname = branch.get().name().unwrap_or_default().to_owned();
+        self.repo.set_head(&refname)?;
+        Ok(())
+    }
+
+    fn switch_branch(&self, name: &str) -> Result<(), GitCommandError> {
+        let branch = self.repo.find_branch(name, BranchType::Local)?;
+        let refname = branch.get().name().unwrap_or_default().to_owned();
+        self.repo.set_head(&refname)?;
+        Ok(())
+    }
+
+    fn detach_head(&self) -> Result<(), GitCommandError> {
+        self.repo.set_head_detached(self.head_commit()?.id())?;
+        Ok(())
+    }
+
+    fn delete_branch(&self, name: &str) -> Result<(), GitCommandError> {
+        self.repo.find_branch(name, BranchType::Local)?.delete()?;
+        Ok(())
+    }
+
+    fn reset(&self, commit: &str) -> Result<(), GitCommandError> {
+        let object = self.find_commit(commit)?.into_object();
+        self.repo.reset(&object, ResetType::Mixed, None)?;
+        Ok(())
+    }
+
+    fn update_ref(
+        &self,
+        name: &str,
+        commit: &str,
+        old: Option<&str>,
+    ) -> Result<(), GitCommandError> {
+        let id = Oid::from_str(commit)?;
+        match old {
+            Some(old) => self.repo.reference_matching(
+                name,
+                id,
+                true,
+                Oid::from_str(old)?,
+                "update-ref",
+            )?,
+            None => self.repo.reference(name, id, true, "update-ref")?,
+        };
+        Ok(())
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<(), GitCommandError> {
+        self.repo.find_reference(name)?.delete()?;
+        Ok(())
+    }
+
+    fn list_refs(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, String)>, GitCommandError> {
+        let mut refs = vec![];
+        for reference in self.repo.references()? {
+            let reference = reference?;
+            let Some(name) = reference.name() else {
+                continue;
+            };
+            if name.starts_with(prefix) {
+                let id = reference.peel_to_commit()?.id().to_string();
+                refs.push((name.to_owned(), id));
+            }
+        }
+        Ok(refs)
+    }
+
+    fn apply_to_index(&self, patch: &[u8]) -> Result<(), GitCommandError> {
+        let diff = Diff::from_buffer(patch)?;
+        self.repo.apply(&diff, ApplyLocation::Index, None)?;
+        Ok(())
+    }
+
+    fn apply_to_tree(
+        &self,
+        tree: &str,
+        patch: &[u8],
+    ) -> Result<String, GitCommandError> {
+        let tree = self.repo.find_tree(Oid::from_str(tree)?)?;
+        let diff = Diff::from_buffer(patch)?;
+        let mut index = self.repo.apply_to_tree(&tree, &diff, None)?;
+        Ok(index.write_tree_to(&self.repo)?.to_string())
+    }
+
+    fn signature(&self) -> Result<Signature, GitCommandError> {
+        Ok(to_signature(&self.repo.signature()?))
+    }
+
+    fn commit_index(
+        &self,
+        msg: &str,
+        author: Option<&Signature>,
+    ) -> Result<String, GitCommandError> {
+        let tree_id = self.repo.index()?.write_tree()?;
+        let tree = self.repo.find_tree(tree_id)?;
+        let signature = self.repo.signature()?;
+        let author = match author {
+            Some(author) => from_signature(author)?,
+            None => signature.clone(),
+        };
+        let parent = self.head_commit()?;
+
+        let id = self.repo.commit(
+            Some("HEAD"),
+            &author,
+            &signature,
+            msg,
+            &tree,
+            &[&parent],
+        )?;
+        Ok(id.to_string())
+    }
+
+    fn tree_of(&self, commit: &str) -> Result<String, GitCommandError> {
+        Ok(self.find_commit(commit)?.tree_id().to_string())
+    }
+
+    fn read_blob(
+        &self,
+        commit: &str,
+        path: &Path,
+    ) -> Result<Option<Vec<u8>>, GitCommandError> {
+        let entry = match self.find_commit(commit)?.tree()?.get_path(path) {
+            Ok(entry) => entry,
+            Err(error) if error.code() == ErrorCode::NotFound => {
+                return Ok(None)
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let blob = entry.to_object(&self.repo)?.peel_to_blob()?;
+        Ok(Some(blob.content().to_vec()))
+    }
+
+    fn rev_list(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<String>, GitCommandError> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        revwalk.push(Oid::from_str(to)?)?;
+        revwalk.hide(Oid::from_str(from)?)?;
+
+        revwalk
+            .map(|id| Ok(id?.to_string()))
+            .collect::<Result<Vec<String>, GitCommandError>>()
+    }
+
+    fn list_merges(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<String>, GitCommandError> {
+        let mut merges = vec![];
+        for id in self.rev_list(from, to)? {
+            if self.find_commit(&id)?.parent_count() > 1 {
+                merges.push(id);
+            }
+        }
+        Ok(merges)
+    }
+
+    fn read_commit(&self, commit: &str) -> Result<CommitInfo, GitCommandError> {
+        let commit = self.find_commit(commit)?;
+        let author = to_signature(&commit.author());
+        let signed = self.repo.extract_signature(&commit.id(), None).is_ok();
+
+        Ok(CommitInfo {
+            tree: commit.tree_id().to_string(),
+            parents: commit.parent_ids().map(|id| id.to_string()).collect(),
+            author,
+            message: commit.message_raw_bytes().to_vec(),
+            encoding: commit.message_encoding().map(str::to_owned),
+            signed,
+        })
+    }
+
+    fn commit_tree(
+        &self,
+        tree: &str,
+        parents: &[String],
+        author: &Signature,
+        message: &[u8],
+        encoding: Option<&str>,
+    ) -> Result<String, GitCommandError> {
+        let tree = self.repo.find_tree(Oid::from_str(tree)?)?;
+        let parents = parents
+            .iter()
+            .map(|parent| self.find_commit(parent))
+            .collect::<Result<Vec<git2::Commit>, GitCommandError>>(
+        )?;
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+
+        // The message is kept as raw bytes, since a replayed message may not
+        // be UTF-8. libgit2 only takes a string, so the object is created
+        // with an empty message, which ends it after the blank line, and the
+        // message is appended.
+        let buffer = self.repo.commit_create_buffer(
+            &from_signature(author)?,
+            &self.repo.signature()?,
+            "",
+            &tree,
+            &parents,
+        )?;
+        let mut object = buffer.to_vec();
+        if let Some(encoding) = encoding {
+            // The encoding header goes last, before the blank line.
+            let blank_line = object.pop();
+            object.extend_from_slice(
+                format!("encoding {}\n", encoding).as_bytes(),
+            );
+            object.extend(blank_line);
+        }
+        object.extend_from_slice(message);
+
+        let id = self.repo.odb()?.write(ObjectType::Commit, &object)?;
+        Ok(id.to_string())
+    }
+}
@@ -17,7 +17,37 @@ pub mod tree;
 
 pub mod git_helper;
 
-use git_helper::{GitCommandError, GitHelper};
+/// Git backend interface and the backend running the `git` executable.
+pub mod git_backend;
+
+/// Git backend running in-process with libgit2.
+pub mod libgit2_backend;
+
+/// In-memory Git backend for tests.
+#[cfg(test)]
+pub mod fake_backend;
+
+/// Diff parser and patch builder.
+pub mod patch;
+
+/// Split plans saved as JSON to apply them without interaction.
+pub mod plan;
+
+/// Commits suggested per directory, file extension or crate.
+pub mod suggest;
+
+/// Interactive sessions autosaved in the Git directory to resume them.
+pub mod session;
+
+use git_backend::{CliBackend, CommitOptions, GitBackend};
+use git_helper::{
+    undo, Authors, GitCommandError, GitCommitCandidate, GitHelper, Messages,
+};
+use libgit2_backend::Libgit2Backend;
+use patch::FileDiff;
+use plan::{split_by_groups, Plan, SplitGroup, PLAN_FILE};
+use session::{Session, SESSION_FILE};
+use suggest::Workspace;
 
 use app::App;
 use color_eyre::Result;
@@ -28,77 +58,480 @@ use ratatui::{backend::CrosstermBackend, Terminal};
 use tui::Tui;
 use update::update;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 const ABOUT: &str = r#"
 Split Git commits interactively.
 
 This is an interactive CLI tool that executes the following Git commands to break and reconstruct your existing top commits:
 
-$ git reset --soft HEAD~{depth}
+$ git reset {base}  # {base} is HEAD~{depth} unless --base is given
 
-The following commands are executed repeatedly until all the reset files are committed:
+The following commands are executed repeatedly until all the reset changes are committed:
 
-$ git add {some-selected-files}
+$ git apply --cached {patch-of-selected-files-or-hunks}
 $ git commit -m "{msg}"
+
+With --engine plumbing, the commits are built with a temporary index and `git commit-tree` instead, and the branch is moved by a single `git update-ref` at the end.
+
+With --commit, only the given commit is split and the commits after it are replayed on top of the new commits.
+
+The original HEAD is kept under refs/break-commits/backup/, so `git-break-commits undo` can revert the split.
 "#;
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Backend {
+    /// Run the `git` executable
+    Cli,
+    /// Run in-process with libgit2. Git hooks are not run
+    Libgit2,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Engine {
+    /// Check out a temporary branch and commit with `git commit`
+    Porcelain,
+    /// Build the commits with a temporary index and `git commit-tree`, and
+    /// move the branch at once at the end. Git hooks are not run
+    Plumbing,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Revert the latest split if HEAD has not moved since
+    Undo,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about = ABOUT)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Depth of commits to split
-    #[arg(short, long, default_value_t = 1)]
-    depth: u8,
+    #[arg(
+        short,
+        long,
+        default_value_t = 1,
+        conflicts_with_all = ["base", "commit"]
+    )]
+    depth: usize,
+
+    /// Base revision of the commits to split, e.g. `origin/main`.
+    /// `<rev>...` splits the commits since the merge base with `<rev>`
+    #[arg(short, long, value_name = "REV", conflicts_with = "commit")]
+    base: Option<String>,
+
+    /// Split only this commit and replay the commits after it on top of the
+    /// new commits
+    #[arg(short, long, value_name = "REV")]
+    commit: Option<String>,
+
+    /// Split the changes at the hunk level instead of the file level
+    #[arg(long)]
+    hunk: bool,
+
+    /// Stash the uncommitted changes before splitting and restore them
+    /// afterwards, instead of refusing to run
+    #[arg(long)]
+    autostash: bool,
+
+    /// How the new commits are created
+    #[arg(long, value_enum, default_value_t = Engine::Porcelain)]
+    engine: Engine,
+
+    /// Allow merge commits in the range by following only their first
+    /// parents. The changes of their side branches are folded into the new
+    /// commits
+    #[arg(long)]
+    first_parent: bool,
+
+    /// Skip the pre-commit and commit-msg hooks
+    #[arg(long)]
+    no_verify: bool,
+
+    /// Sign the new commits with the key, or the default key if none is
+    /// given
+    #[arg(
+        short = 'S',
+        long,
+        value_name = "KEYID",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ""
+    )]
+    gpg_sign: Option<String>,
+
+    /// Do not sign the new commits even if `commit.gpgSign` is set
+    #[arg(long, conflicts_with = "gpg_sign")]
+    no_gpg_sign: bool,
+
+    /// Print the new commits and the git commands which would create them
+    /// instead of changing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Apply the plan saved in the file without any interaction. Its range
+    /// is used unless `--base` or `--commit` is given
+    #[arg(long, value_name = "FILE")]
+    plan: Option<PathBuf>,
+
+    /// Commit the files matching the globs with the message without the
+    /// TUI, e.g. `'docs: update::docs/**,*.md'`. It can be repeated, and a
+    /// file goes to the first group matching it
+    #[arg(long, value_name = "MSG::GLOB", value_parser = SplitGroup::parse, conflicts_with = "plan")]
+    split: Vec<SplitGroup>,
+
+    /// Commit the files in no `--split` group with the message
+    #[arg(long, value_name = "MSG", requires = "split")]
+    rest: Option<String>,
+
+    /// Restore the interrupted session of this repository, which is saved in
+    /// the Git directory on every change, if HEAD has not moved since
+    #[arg(long, conflicts_with_all = ["base", "commit", "plan", "split"])]
+    resume: bool,
+
+    /// Save the plan to the file when the selection is complete. The file is
+    /// also where `s` saves the commits made so far, which is
+    /// `break-commits-plan.json` in the Git directory by default
+    #[arg(long, value_name = "FILE", conflicts_with = "plan")]
+    save_plan: Option<PathBuf>,
+
+    /// Backend which executes the Git operations
+    #[arg(long, value_enum, default_value_t = Backend::Cli, global = true)]
+    backend: Backend,
+
+    /// Run as if started in the path, which may be a subdirectory or a
+    /// linked worktree of the repository
+    #[arg(
+        short = 'C',
+        long,
+        value_name = "PATH",
+        default_value = ".",
+        global = true
+    )]
+    repo: PathBuf,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let mut git_helper = GitHelper::new(args.depth)?;
+    let options = CommitOptions {
+        no_verify: args.no_verify,
+        gpg_sign: args.gpg_sign,
+        no_gpg_sign: args.no_gpg_sign,
+    };
+    let backend: Box<dyn GitBackend> = match args.backend {
+        Backend::Cli => {
+            Box::new(CliBackend::new_with_options(&args.repo, options.clone()))
+        }
+        Backend::Libgit2 => {
+            Box::new(Libgit2Backend::new_with_options(&args.repo, &options)?)
+        }
+    };
 
-    let file_paths = git_helper.list()?;
+    if let Some(Command::Undo) = args.command {
+        let id = undo(backend.as_ref())?;
+        println!("HEAD is restored to {}", id);
+        return Ok(());
+    }
+
+    let plan = args.plan.as_deref().map(Plan::load).transpose()?;
+    let git_dir = backend.git_dir()?;
+    let session_path = git_dir.join(SESSION_FILE);
+    let session = if args.resume {
+        Some(Session::load(&session_path)?)
+    } else {
+        None
+    };
+    let plan_range =
+        plan.is_some() && args.commit.is_none() && args.base.is_none();
+    let (commit, base) = match (&plan, &session) {
+        (Some(plan), _) if plan_range => {
+            (plan.commit.clone(), plan.base.clone())
+        }
+        (_, Some(session)) => (session.commit.clone(), session.base.clone()),
+        _ => match (args.commit, args.base) {
+            (Some(commit), _) => (Some(commit.clone()), format!("{}~", commit)),
+            (None, Some(base)) => (None, base),
+            (None, None) => (None, format!("HEAD~{}", args.depth)),
+        },
+    };
+    let mut git_helper = match &commit {
+        Some(commit) => GitHelper::new_for_commit(backend, commit)?,
+        None => GitHelper::new(backend, &base)?,
+    };
+
+    if let Some(session) = &session {
+        session.check(git_helper.original_head())?;
+    }
+
+    // Refuse before the user makes any selection.
+    git_helper.check_worktree(args.autostash)?;
+    let folded_merges = git_helper.check_merges(args.first_parent)?;
+
+    let head = git_helper.original_head().to_owned();
+    let base_id = git_helper.base_id().to_owned();
+    let new_plan = |commits: &[GitCommitCandidate],
+                    diffs: &HashMap<PathBuf, FileDiff>| {
+        Plan::new(&base, &base_id, commit.as_deref(), &head, commits, diffs)
+    };
+    // The default plan is kept in the Git directory, so it does not dirty
+    // the working tree.
+    let plan_path = args
+        .save_plan
+        .clone()
+        .unwrap_or_else(|| git_dir.join(PLAN_FILE));
+
+    // Split without any interaction.
+    let commits = match plan {
+        Some(plan) => {
+            if plan_range {
+                plan.check_base(&base_id)?;
+            }
+            plan.check(&git_helper.list()?, &git_helper.list_diffs()?)?;
+            Some(plan.commits)
+        }
+        None if !args.split.is_empty() => Some(split_by_groups(
+            &git_helper.list()?,
+            &args.split,
+            args.rest.as_deref(),
+            &Authors::new(&git_helper.list_commit_infos()?),
+        )?),
+        None => None,
+    };
+    if let Some(commits) = commits {
+        // A split by groups selects no hunks.
+        if args.save_plan.is_some() {
+            new_plan(&commits, &HashMap::new()).save(&plan_path)?;
+        }
+        if args.dry_run {
+            return print_plan(&git_helper, &commits, args.engine, &options);
+        }
+        apply_commits(&mut git_helper, &commits, args.engine)?;
+        return print_new_commits(&git_helper);
+    }
 
     // Create an application.
-    let mut app = App::new(file_paths)?;
+    let hunk = session.as_ref().map_or(args.hunk, |session| session.hunk);
+    let mut app = if hunk {
+        App::new_with_diffs(git_helper.list_diffs()?)?
+    } else {
+        App::new(git_helper.list()?)?
+    };
+    let commit_infos = git_helper.list_commit_infos()?;
+    app.authors = Authors::new(&commit_infos);
+    app.messages = Messages::new(&commit_infos);
+    if let Some(manifest) = git_helper.read_file(Path::new("Cargo.toml"))? {
+        match Workspace::parse(&String::from_utf8_lossy(&manifest)) {
+            Ok(workspace) => app.workspace = workspace,
+            Err(error) => app.show_error(format!(
+                "Commits cannot be suggested per crate: {}",
+                error
+            )),
+        }
+    }
+    if !folded_merges.is_empty() {
+        let report: String = folded_merges
+            .iter()
+            .map(|merge| merge.to_string())
+            .collect();
+        app.show_info(format!(
+            "The side branches of these merges are folded into the new commits:\n\n{}",
+            report
+        ));
+    }
+
+    match &session {
+        Some(session) => app.restore(session)?,
+        None if session_path.exists() => app.show_info(
+            "An interrupted session was found. It is replaced once you change anything here, so quit and run with --resume to restore it.".to_owned(),
+        ),
+        None => {}
+    }
+    let new_session =
+        |app: &App| Session::new(&base, commit.as_deref(), &head, app);
+    let mut last_session = new_session(&app);
 
     // Initialize the terminal user interface.
     let backend = CrosstermBackend::new(std::io::stderr());
     let terminal = Terminal::new(backend)?;
     let events = EventHandler::new(250);
     let mut tui = Tui::new(terminal, events);
-    tui.enter()?;
-
-    // Start the main loop.
-    while !app.should_quit {
-        // Render the user interface.
-        tui.draw(&mut app)?;
-        // Handle events.
-        match tui.events.next()? {
-            Event::Tick => {}
-            Event::Key(key_event) => update(&mut app, key_event),
-            Event::Mouse(_) => {}
-            Event::Resize(_, _) => {}
-        };
+
+    loop {
+        tui.enter()?;
+
+        // Start the main loop.
+        while !app.should_quit {
+            // Render the user interface.
+            tui.draw(&mut app)?;
+            // Handle events.
+            match tui.events.next()? {
+                Event::Tick => {}
+                Event::Key(key_event) => {
+                    update(&mut app, key_event);
+
+                    // Autosave the session on every change.
+                    let current = new_session(&app);
+                    if current != last_session {
+                        if let Err(error) = current.save(&session_path) {
+                            app.show_error(format!(
+                                "Cannot save the session: {}",
+                                error
+                            ));
+                        }
+                        last_session = current;
+                    }
+                }
+                Event::Mouse(_) => {}
+                Event::Resize(_, _) => {}
+            };
+
+            if app.save_requested {
+                app.save_requested = false;
+                match new_plan(&app.commits, &app.diffs).save(&plan_path) {
+                    Ok(()) => app.show_info(format!(
+                        "Saved the plan of {} commits to {}",
+                        app.commits.len(),
+                        plan_path.display()
+                    )),
+                    Err(error) => app.show_error(error.to_string()),
+                }
+            }
+        }
+
+        // Exit the user interface, since signing may prompt for a passphrase.
+        tui.exit()?;
+
+        if !app.finished || app.retrying.is_some() {
+            return Err(eyre!("Nothing changed"));
+        }
+
+        if args.save_plan.is_some() {
+            new_plan(&app.commits, &app.diffs).save(&plan_path)?;
+        }
+
+        // The selection is complete, so there is nothing left to resume.
+        if args.dry_run {
+            Session::remove(&session_path)?;
+            return print_plan(
+                &git_helper,
+                &app.commits,
+                args.engine,
+                &options,
+            );
+        }
+
+        // Apply Git changes
+        match apply_commits(&mut git_helper, &app.commits, args.engine) {
+            // Go back to the editor to fix the rejected message.
+            Err(GitCommandError::CommitRejectedError { index, output }) => {
+                app.reject_commit(index, &output)
+            }
+            result => break result?,
+        }
     }
+    Session::remove(&session_path)?;
 
-    // Exit the user interface.
-    tui.exit()?;
+    print_new_commits(&git_helper)
+}
 
-    // Apply Git changes
-    if app.tree.borrow().num_leaf_node == 0 {
-        let mut do_commit = || -> Result<(), GitCommandError> {
-            git_helper.checkout_to_temp_branch()?;
-            git_helper.reset()?;
-            git_helper.commit(&app.commits)?;
-            Ok(())
-        };
+/// Prints the new and the replayed commits with whether they are signed.
+fn print_new_commits(git_helper: &GitHelper) -> Result<()> {
+    for (id, info) in git_helper.list_new_commits()? {
+        let message = String::from_utf8_lossy(&info.message);
+        println!(
+            "{} {} {}",
+            &id[..id.len().min(7)],
+            if info.signed { "signed  " } else { "unsigned" },
+            message.lines().next().unwrap_or_default()
+        );
+    }
 
-        let result = do_commit();
-        git_helper.restore_branch()?;
-        result?;
+    Ok(())
+}
 
-        Ok(())
-    } else {
-        Err(eyre!("Nothing changed"))
+/// Prints the new commits and the git commands which would create them with
+/// the engine.
+fn print_plan(
+    git_helper: &GitHelper,
+    commits: &[GitCommitCandidate],
+    engine: Engine,
+    options: &CommitOptions,
+) -> Result<()> {
+    let planned = git_helper.plan(commits)?;
+    for (index, commit) in planned.iter().enumerate() {
+        println!("Commit {}/{}", index + 1, planned.len());
+        println!("{}", commit);
+    }
+
+    let commands = match engine {
+        Engine::Porcelain => git_helper.porcelain_commands(commits, options)?,
+        Engine::Plumbing => git_helper.plumbing_commands(commits, options)?,
+    };
+    println!("Commands:");
+    for command in commands {
+        println!("  {}", command);
+    }
+    Ok(())
+}
+
+/// Creates the new commits with the engine. On failure, the branch is left
+/// as it was.
+fn apply_commits(
+    git_helper: &mut GitHelper,
+    commits: &[GitCommitCandidate],
+    engine: Engine,
+) -> Result<(), GitCommandError> {
+    git_helper.stash()?;
+
+    let result = match engine {
+        Engine::Porcelain => {
+            let mut do_commit = || -> Result<(), GitCommandError> {
+                git_helper.backup()?;
+                git_helper.checkout_to_temp_branch()?;
+                git_helper.reset()?;
+                git_helper.commit(commits)?;
+                git_helper.replay()?;
+                Ok(())
+            };
+
+            match do_commit() {
+                Ok(()) => git_helper.restore_branch(),
+                Err(error) => {
+                    if let Err(abort_error) = git_helper.abort() {
+                        eprintln!("Cannot abort the split: {}", abort_error);
+                    }
+                    Err(error)
+                }
+            }
+        }
+        // Only the backup has to be deleted on failure, since the branch is
+        // moved by the last step.
+        Engine::Plumbing => {
+            let result = git_helper
+                .backup()
+                .and_then(|_| git_helper.commit_with_plumbing(commits))
+                .map(|_| ());
+            if result.is_err() {
+                if let Err(error) = git_helper.discard_backup() {
+                    eprintln!("Cannot delete the backup: {}", error);
+                }
+            }
+            result
+        }
+    };
+
+    // The changes are restored whatever happened, and losing track of them
+    // matters more than the error of the split.
+    if let Err(error) = git_helper.unstash() {
+        if let Err(split_error) = result {
+            eprintln!("The split failed: {}", split_error);
+        }
+        return Err(error);
     }
+    result
 }
@@ -0,0 +1,831 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::git_backend::{bytes_to_path, ChangeStatus};
+
+#[derive(Error, Debug)]
+pub enum PatchError {
+    #[error("Cannot parse the diff header: {0:?}", header)]
+    InvalidHeaderError { header: String },
+    #[error("Cannot change to UTF8 format")]
+    UTF8Error { value: std::str::Utf8Error },
+    #[error("Cannot find the diff of {0:?}", path)]
+    FileNotFoundError { path: PathBuf },
+    #[error("Cannot find the hunk {1:?} of {0:?}", path, index)]
+    HunkNotFoundError { path: PathBuf, index: usize },
+    #[error(
+        "The hunk {1:?} of {0:?} changed since it was selected",
+        path,
+        index
+    )]
+    HunkChangedError { path: PathBuf, index: usize },
+}
+
+impl From<std::str::Utf8Error> for PatchError {
+    fn from(value: std::str::Utf8Error) -> Self {
+        PatchError::UTF8Error { value }
+    }
+}
+
+/// Changed lines of a diff hunk selected for a commit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HunkSelection {
+    pub path: PathBuf,
+    pub index: usize,
+    /// Indices into [`Hunk::lines`] of the selected `+` and `-` lines.
+    pub lines: Vec<usize>,
+    /// The [`Hunk::hash`] of the hunk, recorded in saved plans so they are
+    /// refused if the hunk is not the same any more.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// A single `@@ ... @@` section of a file diff.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hunk {
+    /// The `@@ -a,b +c,d @@` line including its trailing newline.
+    pub header: Vec<u8>,
+    /// Body lines including their trailing newlines.
+    pub lines: Vec<Vec<u8>>,
+}
+
+impl Hunk {
+    /// Returns the hunk header without the trailing newline.
+    pub fn title(&self) -> String {
+        String::from_utf8_lossy(trim_newline(&self.header)).into_owned()
+    }
+
+    /// Returns a hash of the header and the lines, as 64-bit FNV-1a in hex.
+    ///
+    /// It only has to tell apart the hunks of the same file and index, so a
+    /// fast and stable hash is enough.
+    pub fn hash(&self) -> String {
+        let bytes = self.header.iter().chain(self.lines.iter().flatten());
+        let hash = bytes.fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+        });
+        format!("{:016x}", hash)
+    }
+
+    /// Returns the indices of the `+` and `-` lines.
+    pub fn changed_lines(&self) -> Vec<usize> {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| matches!(line.first(), Some(b'+' | b'-')))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Returns the lines as they are after the changed lines which are not
+    /// `pending` are applied.
+    ///
+    /// An applied `+` line becomes a context line and an applied `-` line
+    /// disappears.
+    pub fn view(&self, pending: &[usize]) -> Vec<(usize, Vec<u8>)> {
+        let applied: HashSet<usize> = self
+            .changed_lines()
+            .into_iter()
+            .filter(|index| !pending.contains(index))
+            .collect();
+
+        self.render_lines(&applied, &pending.iter().copied().collect())
+            .into_iter()
+            .map(|(index, line, _)| (index, line))
+            .collect()
+    }
+
+    /// Renders the body lines with the `selected` lines as changes on top of
+    /// the `applied` lines. The other changed lines are dropped or turned into
+    /// context lines. The last element tells whether the line is a change.
+    fn render_lines(
+        &self,
+        applied: &HashSet<usize>,
+        selected: &HashSet<usize>,
+    ) -> Vec<(usize, Vec<u8>, bool)> {
+        let context = |line: &[u8]| {
+            let mut context = b" ".to_vec();
+            context.extend_from_slice(line.get(1..).unwrap_or_default());
+            context
+        };
+
+        let mut rendered = vec![];
+        let mut is_prev_rendered = false;
+
+        for (index, line) in self.lines.iter().enumerate() {
+            let is_applied = applied.contains(&index);
+            let is_selected = selected.contains(&index);
+
+            let item = match line.first() {
+                Some(b'+') if is_applied => Some((context(line), false)),
+                Some(b'+') if is_selected => Some((line.clone(), true)),
+                Some(b'+') => None,
+                Some(b'-') if is_applied => None,
+                Some(b'-') if is_selected => Some((line.clone(), true)),
+                Some(b'-') => Some((context(line), false)),
+                // "\ No newline at end of file" belongs to the previous line.
+                Some(b'\\') if is_prev_rendered => Some((line.clone(), false)),
+                Some(b'\\') => None,
+                _ => Some((line.clone(), false)),
+            };
+
+            if line.first() != Some(&b'\\') {
+                is_prev_rendered = item.is_some();
+            }
+            if let Some((line, is_change)) = item {
+                rendered.push((index, line, is_change));
+            }
+        }
+
+        rendered
+    }
+
+    /// Parses the old range `(a, b)` of `@@ -a,b +c,d @@` and the rest of the
+    /// line.
+    fn parse_header(&self) -> Option<(usize, usize, &[u8])> {
+        let header = self.header.strip_prefix(b"@@ -")?;
+        let end = header.windows(3).position(|w| w == b" @@")?;
+        let ranges = std::str::from_utf8(&header[..end]).ok()?;
+        let (old, _) = ranges.split_once(" +")?;
+
+        let (start, count) = match old.split_once(',') {
+            Some((start, count)) => (start.parse().ok()?, count.parse().ok()?),
+            None => (old.parse().ok()?, 1),
+        };
+
+        Some((start, count, &header[end + 3..]))
+    }
+
+    /// Renders the hunk with only the `selected` lines on top of the already
+    /// `applied` lines and recomputes its header.
+    ///
+    /// `shift` is the number of lines by which the hunk is moved in the old
+    /// file due to the applied lines of the preceding hunks, and `delta` is
+    /// the same for the new file due to the preceding hunks of this patch.
+    /// Returns the rendered hunk and its own delta, or `None` if no line is
+    /// selected.
+    fn render(
+        &self,
+        applied: &HashSet<usize>,
+        selected: &HashSet<usize>,
+        shift: isize,
+        delta: isize,
+    ) -> Result<Option<(Vec<u8>, isize)>, PatchError> {
+        let (old_start, old_count, rest) =
+            self.parse_header().ok_or_else(|| {
+                PatchError::InvalidHeaderError {
+                    header: self.title(),
+                }
+            })?;
+
+        let lines = self.render_lines(applied, selected);
+        if !lines.iter().any(|(_, _, is_change)| *is_change) {
+            return Ok(None);
+        }
+
+        let count = |skipped: u8| {
+            lines
+                .iter()
+                .filter(|(_, line, _)| {
+                    line.first() != Some(&skipped)
+                        && line.first() != Some(&b'\\')
+                })
+                .count() as isize
+        };
+        let (new_old_count, new_new_count) = (count(b'+'), count(b'-'));
+
+        // A range with zero lines starts at the line before it.
+        let first_line = if old_count == 0 {
+            old_start as isize + 1
+        } else {
+            old_start as isize
+        } + shift;
+        let start = |first_line: isize, count: isize| {
+            if count == 0 {
+                first_line - 1
+            } else {
+                first_line
+            }
+        };
+
+        let mut hunk = format!(
+            "@@ -{},{} +{},{} @@",
+            start(first_line, new_old_count),
+            new_old_count,
+            start(first_line + delta, new_new_count),
+            new_new_count,
+        )
+        .into_bytes();
+        hunk.extend_from_slice(rest);
+        for (_, line, _) in lines {
+            hunk.extend(line);
+        }
+
+        Ok(Some((hunk, new_new_count - new_old_count)))
+    }
+}
+
+/// The diff of a single file, split into its extended header and its hunks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileDiff {
+    pub path: PathBuf,
+    /// The source of the file if it is renamed.
+    pub old_path: Option<PathBuf>,
+    /// Every line before the first hunk, e.g. `diff --git`, `index` or a
+    /// binary patch.
+    pub header: Vec<Vec<u8>>,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FileDiff {
+    /// Derives the change status from the extended header.
+    pub fn status(&self) -> ChangeStatus {
+        let has = |prefix: &[u8]| {
+            self.header.iter().any(|line| line.starts_with(prefix))
+        };
+
+        if has(b"new file mode ") {
+            ChangeStatus::Added
+        } else if has(b"deleted file mode ") {
+            ChangeStatus::Deleted
+        } else if has(b"rename from ") {
+            ChangeStatus::Renamed
+        } else if has(b"old mode ") && !has(b"index ") {
+            // The content is unchanged if there is no index line.
+            ChangeStatus::ModeChanged
+        } else {
+            ChangeStatus::Modified
+        }
+    }
+
+    /// Counts the added and the removed lines, or returns `None` for a
+    /// binary file.
+    pub fn line_counts(&self) -> Option<(usize, usize)> {
+        let is_binary = self.header.iter().any(|line| {
+            line.starts_with(b"GIT binary patch")
+                || line.starts_with(b"Binary files ")
+        });
+        if is_binary {
+            return None;
+        }
+
+        let lines = self.hunks.iter().flat_map(|hunk| hunk.lines.iter());
+        let (mut added, mut removed) = (0, 0);
+        for line in lines {
+            match line.first() {
+                Some(b'+') => added += 1,
+                Some(b'-') => removed += 1,
+                _ => {}
+            }
+        }
+        Some((added, removed))
+    }
+
+    /// Selects every changed line of every hunk.
+    pub fn select_all(&self) -> Vec<HunkSelection> {
+        self.hunks
+            .iter()
+            .enumerate()
+            .map(|(index, hunk)| HunkSelection {
+                path: self.path.clone(),
+                index,
+                lines: hunk.changed_lines(),
+                hash: None,
+            })
+            .collect()
+    }
+
+    /// Renders a patch containing only the selected lines on top of the
+    /// `applied` lines, which are given per hunk index.
+    ///
+    /// The extended header is kept only as far as it is still valid, e.g. a
+    /// file is created by the first patch of the file and deleted by the last.
+    pub fn to_patch(
+        &self,
+        selections: &[&HunkSelection],
+        applied: &HashMap<usize, HashSet<usize>>,
+    ) -> Result<Vec<u8>, PatchError> {
+        let mut selected: HashMap<usize, HashSet<usize>> = HashMap::new();
+        for selection in selections {
+            if selection.index >= self.hunks.len() {
+                return Err(PatchError::HunkNotFoundError {
+                    path: self.path.clone(),
+                    index: selection.index,
+                });
+            }
+            selected
+                .entry(selection.index)
+                .or_default()
+                .extend(selection.lines.iter().copied());
+        }
+
+        let empty = HashSet::new();
+        let is_first = applied.values().all(|lines| lines.is_empty());
+        let is_last = self.hunks.iter().enumerate().all(|(index, hunk)| {
+            let applied = applied.get(&index).unwrap_or(&empty);
+            let selected = selected.get(&index).unwrap_or(&empty);
+            hunk.changed_lines()
+                .iter()
+                .all(|line| applied.contains(line) || selected.contains(line))
+        });
+
+        let mut hunks = vec![];
+        let mut shift = 0;
+        let mut delta = 0;
+
+        for (index, hunk) in self.hunks.iter().enumerate() {
+            let applied = applied.get(&index).unwrap_or(&empty);
+
+            if let Some(selected) = selected.get(&index) {
+                if let Some((rendered, hunk_delta)) =
+                    hunk.render(applied, selected, shift, delta)?
+                {
+                    hunks.extend(rendered);
+                    delta += hunk_delta;
+                }
+            }
+
+            for line in applied {
+                match hunk.lines[*line].first() {
+                    Some(b'+') => shift += 1,
+                    Some(b'-') => shift -= 1,
+                    _ => {}
+                }
+            }
+        }
+
+        // Nothing to apply if none of the selected lines is a change.
+        if hunks.is_empty() && !self.hunks.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut patch = if is_first && is_last {
+            self.header.concat()
+        } else {
+            self.partial_header(is_first, is_last)
+        };
+        patch.extend(hunks);
+
+        Ok(patch)
+    }
+
+    fn partial_header(&self, is_first: bool, is_last: bool) -> Vec<u8> {
+        let find = |prefix: &[u8]| {
+            self.header
+                .iter()
+                .find(|line| line.starts_with(prefix))
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        let mut header = vec![];
+        // A renamed file is at its new path after the first patch.
+        let is_moved = !is_first && self.old_path.is_some();
+
+        for line in &self.header {
+            if line.starts_with(b"diff --git ") {
+                if is_moved {
+                    let plus = find(b"+++ ");
+                    let minus = flip_side(&plus);
+                    header.extend(b"diff --git ");
+                    header.extend(trim_newline(&minus[4..]));
+                    header.push(b' ');
+                    header.extend(plus.get(4..).unwrap_or_default());
+                } else {
+                    header.extend_from_slice(line);
+                }
+            } else if line.starts_with(b"old mode ")
+                || line.starts_with(b"new mode ")
+                || line.starts_with(b"new file mode ")
+                || line.starts_with(b"similarity index ")
+                || line.starts_with(b"dissimilarity index ")
+                || line.starts_with(b"rename from ")
+                || line.starts_with(b"rename to ")
+            {
+                if is_first {
+                    header.extend_from_slice(line);
+                }
+            } else if line.starts_with(b"index ") {
+                // libgit2 expects the index line after the rename lines, and its old side is still valid for the first patch.
+                if is_first && self.old_path.is_some() {
+                    header.extend_from_slice(line);
+                }
+            } else if line.starts_with(b"deleted file mode ") {
+                if is_last {
+                    header.extend_from_slice(line);
+                }
+            } else if line.starts_with(b"--- ") {
+                if is_moved || (!is_first && line.starts_with(b"--- /dev/null"))
+                {
+                    header.extend(flip_side(&find(b"+++ ")));
+                } else {
+                    header.extend_from_slice(line);
+                }
+            } else if line.starts_with(b"+++ ") {
+                if !is_last && line.starts_with(b"+++ /dev/null") {
+                    header.extend(flip_side(&find(b"--- ")));
+                } else {
+                    header.extend_from_slice(line);
+                }
+            }
+        }
+        header
+    }
+}
+
+/// Builds the patches of consecutive commits from the same diffs.
+///
+/// It remembers the lines applied by the previous patches so that every
+/// patch applies on top of them.
+pub struct PatchBuilder {
+    diffs: HashMap<PathBuf, FileDiff>,
+    applied: HashMap<PathBuf, HashMap<usize, HashSet<usize>>>,
+}
+
+impl PatchBuilder {
+    pub fn new(diffs: Vec<FileDiff>) -> PatchBuilder {
+        PatchBuilder {
+            diffs: diffs
+                .into_iter()
+                .map(|diff| (diff.path.clone(), diff))
+                .collect(),
+            applied: HashMap::new(),
+        }
+    }
+
+    /// Builds a patch of the whole files and the selected hunk lines.
+    pub fn build(
+        &mut self,
+        file_paths: &[PathBuf],
+        hunks: &[HunkSelection],
+    ) -> Result<Vec<u8>, PatchError> {
+        let whole_paths: HashSet<&PathBuf> = file_paths.iter().collect();
+
+        let mut paths: Vec<&PathBuf> = file_paths
+            .iter()
+            .chain(hunks.iter().map(|selection| &selection.path))
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        let mut patch = vec![];
+
+        for path in paths {
+            let diff = self.diffs.get(path).ok_or_else(|| {
+                PatchError::FileNotFoundError { path: path.clone() }
+            })?;
+            let whole = if whole_paths.contains(path) {
+                diff.select_all()
+            } else {
+                vec![]
+            };
+            let selections: Vec<&HunkSelection> = whole
+                .iter()
+                .chain(hunks.iter().filter(|selection| &selection.path == path))
+                .collect();
+            for selection in &selections {
+                let hunk = diff.hunks.get(selection.index);
+                if selection.hash.is_some()
+                    && selection.hash != hunk.map(Hunk::hash)
+                {
+                    return Err(PatchError::HunkChangedError {
+                        path: path.clone(),
+                        index: selection.index,
+                    });
+                }
+            }
+
+            let applied = self.applied.entry(path.clone()).or_default();
+            patch.extend(diff.to_patch(&selections, applied)?);
+
+            for selection in selections {
+                applied
+                    .entry(selection.index)
+                    .or_default()
+                    .extend(selection.lines.iter().copied());
+            }
+        }
+
+        Ok(patch)
+    }
+}
+
+/// Turns a `+++ b/{path}` line into `--- a/{path}` and vice versa.
+fn flip_side(line: &[u8]) -> Vec<u8> {
+    let (mut flipped, side) = if line.starts_with(b"+++ ") {
+        (b"--- ".to_vec(), b'a')
+    } else {
+        (b"+++ ".to_vec(), b'b')
+    };
+
+    let mut name = line.get(4..).unwrap_or_default();
+    if let Some(rest) = name.strip_prefix(b"\"") {
+        flipped.push(b'"');
+        name = rest;
+    }
+    flipped.push(side);
+    flipped.extend_from_slice(name.get(1..).unwrap_or_default());
+    flipped
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Parses the output of `git diff-tree -p` into per-file diffs.
+pub fn parse_diff(output: &[u8]) -> Result<Vec<FileDiff>, PatchError> {
+    let mut diffs: Vec<FileDiff> = vec![];
+
+    for line in output.split_inclusive(|c| *c == b'\n') {
+        if line.starts_with(b"diff --git ") {
+            diffs.push(FileDiff {
+                path: PathBuf::new(),
+                old_path: None,
+                header: vec![line.to_vec()],
+                hunks: vec![],
+            });
+            continue;
+        }
+
+        let Some(diff) = diffs.last_mut() else {
+            continue;
+        };
+
+        if line.starts_with(b"@@ ") {
+            diff.hunks.push(Hunk {
+                header: line.to_vec(),
+                lines: vec![],
+            });
+        } else if let Some(hunk) = diff.hunks.last_mut() {
+            hunk.lines.push(line.to_vec());
+        } else {
+            diff.header.push(line.to_vec());
+        }
+    }
+
+    for diff in diffs.iter_mut() {
+        diff.path = parse_path(&diff.header)?;
+        diff.old_path = find_name(&diff.header, b"rename from ")
+            .map(|name| bytes_to_path(&name));
+    }
+
+    Ok(diffs)
+}
+
+/// Finds the name in the first header line starting with the prefix, e.g.
+/// `rename to `.
+fn find_name(header: &[Vec<u8>], prefix: &[u8]) -> Option<Vec<u8>> {
+    let name = header
+        .iter()
+        .find_map(|line| trim_newline(line).strip_prefix(prefix))?;
+    match name.first() {
+        Some(b'"') => unquote(name).map(|(name, _)| name),
+        _ => Some(name.to_vec()),
+    }
+}
+
+fn parse_path(header: &[Vec<u8>]) -> Result<PathBuf, PatchError> {
+    if let Some(name) = find_name(header, b"rename to ") {
+        return Ok(bytes_to_path(&name));
+    }
+
+    let first = header.first().map(|line| trim_newline(line)).unwrap_or(b"");
+    let rest = first.strip_prefix(b"diff --git ").unwrap_or(b"");
+
+    let name = if rest.first() == Some(&b'"') {
+        unquote(rest).and_then(|(_, consumed)| {
+            let b_name = rest.get(consumed + 1..)?;
+            let b_name = match b_name.first() {
+                Some(b'"') => unquote(b_name)?.0,
+                _ => b_name.to_vec(),
+            };
+            b_name.strip_prefix(b"b/").map(|name| name.to_vec())
+        })
+    } else if rest.len() > 5 && rest.len() % 2 == 1 {
+        // The header is "a/{path} b/{path}" if the file is not renamed.
+        let len = (rest.len() - 5) / 2;
+        let a_name = &rest[2..2 + len];
+        let b_name = &rest[rest.len() - len..];
+        (a_name == b_name).then(|| b_name.to_vec())
+    } else {
+        None
+    };
+
+    match name {
+        Some(name) => Ok(bytes_to_path(&name)),
+        None => Err(PatchError::InvalidHeaderError {
+            header: String::from_utf8_lossy(first).into_owned(),
+        }),
+    }
+}
+
+/// Decodes a C-style quoted name as written by Git.
+///
+/// Returns the decoded bytes and the number of consumed input bytes.
+fn unquote(input: &[u8]) -> Option<(Vec<u8>, usize)> {
+    if input.first() != Some(&b'"') {
+        return None;
+    }
+
+    let mut output = vec![];
+    let mut i = 1;
+
+    while i < input.len() {
+        match input[i] {
+            b'"' => return Some((output, i + 1)),
+            b'\\' => {
+                let c = *input.get(i + 1)?;
+                i += 2;
+                match c {
+                    b'a' => output.push(0x07),
+                    b'b' => output.push(0x08),
+                    b't' => output.push(b'\t'),
+                    b'n' => output.push(b'\n'),
+                    b'v' => output.push(0x0b),
+                    b'f' => output.push(0x0c),
+                    b'r' => output.push(b'\r'),
+                    b'0'..=b'7' => {
+                        let digits = input.get(i - 1..i + 2)?;
+                        let value = std::str::from_utf8(digits).ok()?;
+                        output.push(u8::from_str_radix(value, 8).ok()?);
+                        i += 2;
+                    }
+                    c => output.push(c),
+                }
+            }
+            c => {
+                output.push(c);
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &[u8] = br#"diff --git a/e b/e
+deleted file mode 100644
+index e69de29bb2d1d6434b8b29ae775ad8c2e48c5391..0000000000000000000000000000000000000000
+diff --git a/f b/f
+old mode 100644
+new mode 100755
+index 422c2b7..0f7bc76
+--- a/f
++++ b/f
+@@ -1,2 +1,2 @@
+ a
+-b
++c
+@@ -10,2 +10,3 @@ fn main() {
+ d
++e
+ f
+diff --git "a/\303\244 b" "b/\303\244 b"
+new file mode 100644
+index 0000000..e69de29
+"#;
+
+    #[test]
+    fn test_parse_diff() {
+        let diffs = parse_diff(DIFF).expect("");
+
+        assert_eq!(diffs.len(), 3);
+        assert_eq!(diffs[0].path, PathBuf::from("e"));
+        assert_eq!(diffs[0].hunks.len(), 0);
+        assert_eq!(diffs[1].path, PathBuf::from("f"));
+        assert_eq!(diffs[1].header.len(), 6);
+        assert_eq!(diffs[1].hunks.len(), 2);
+        assert_eq!(diffs[1].hunks[1].title(), "@@ -10,2 +10,3 @@ fn main() {");
+        assert_eq!(diffs[1].hunks[1].lines.len(), 3);
+        assert_eq!(diffs[2].path, PathBuf::from("ä b"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_non_utf8_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let diff = b"diff --git \"a/caf\\351\" \"b/caf\\351\"\n\
+new file mode 100644\nindex 0000000..e69de29\n";
+        let diffs = parse_diff(diff).expect("");
+        assert_eq!(diffs[0].path.as_os_str().as_bytes(), b"caf\xe9");
+    }
+
+    #[test]
+    fn test_build_renamed_hunks() {
+        let diff = b"diff --git a/a b/b\nsimilarity index 90%\nrename from a\n\
+rename to b\nindex 1111111..2222222 100644\n--- a/a\n+++ b/b\n\
+@@ -1,2 +1,2 @@\n-x\n+y\n z\n@@ -10,2 +10,2 @@\n q\n-r\n+s\n";
+        let diffs = parse_diff(diff).expect("");
+        assert_eq!(diffs[0].path, PathBuf::from("b"));
+        assert_eq!(diffs[0].old_path, Some(PathBuf::from("a")));
+
+        let mut builder = PatchBuilder::new(diffs);
+        let patch = builder.build(&[], &[select("b", 0, &[0, 1])]).expect("");
+        assert_eq!(patch, diff[..diff.len() - 27]);
+
+        let patch = builder.build(&[], &[select("b", 1, &[1, 2])]).expect("");
+        assert_eq!(
+            patch,
+            b"diff --git a/b b/b\n--- a/b\n+++ b/b\n\
+@@ -10,2 +10,2 @@\n q\n-r\n+s\n"
+        );
+    }
+
+    fn select(path: &str, index: usize, lines: &[usize]) -> HunkSelection {
+        HunkSelection {
+            path: PathBuf::from(path),
+            index,
+            lines: lines.to_vec(),
+            hash: None,
+        }
+    }
+
+    #[test]
+    fn test_build_whole_file() {
+        let diffs = parse_diff(DIFF).expect("");
+        let expected = DIFF[diffs[0].header.concat().len()
+            ..DIFF.len() - diffs[2].header.concat().len()]
+            .to_vec();
+
+        let mut builder = PatchBuilder::new(diffs);
+        let patch = builder.build(&[PathBuf::from("f")], &[]).expect("");
+        assert_eq!(patch, expected);
+
+        let patch = builder.build(&[PathBuf::from("e")], &[]).expect("");
+        assert!(patch.starts_with(b"diff --git a/e b/e\ndeleted file mode"));
+
+        assert!(builder.build(&[PathBuf::from("g")], &[]).is_err());
+        assert!(builder.build(&[], &[select("f", 2, &[0])]).is_err());
+    }
+
+    #[test]
+    fn test_build_hunks() {
+        let mut builder = PatchBuilder::new(parse_diff(DIFF).expect(""));
+
+        let patch = builder.build(&[], &[select("f", 1, &[1])]).expect("");
+        let expected = b"diff --git a/f b/f
+old mode 100644
+new mode 100755
+--- a/f
++++ b/f
+@@ -10,2 +10,3 @@ fn main() {
+ d
++e
+ f
+";
+        assert_eq!(patch, expected);
+
+        let patch = builder.build(&[], &[select("f", 0, &[1])]).expect("");
+        let expected = b"diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -1,2 +1,1 @@
+ a
+-b
+";
+        assert_eq!(patch, expected);
+
+        let patch = builder.build(&[], &[select("f", 0, &[2])]).expect("");
+        let expected = b"diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -1,1 +1,2 @@
+ a
++c
+";
+        assert_eq!(patch, expected);
+    }
+
+    #[test]
+    fn test_build_shifted_hunks() {
+        let mut builder = PatchBuilder::new(parse_diff(DIFF).expect(""));
+
+        let patch = builder.build(&[], &[select("f", 0, &[2])]).expect("");
+        assert!(patch.ends_with(b"@@ -1,2 +1,3 @@\n a\n b\n+c\n"));
+
+        let patch = builder
+            .build(&[], &[select("f", 0, &[1]), select("f", 1, &[1])])
+            .expect("");
+        let expected = b"diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -1,3 +1,2 @@
+ a
+-b
+ c
+@@ -11,2 +10,3 @@ fn main() {
+ d
++e
+ f
+";
+        assert_eq!(patch, expected);
+    }
+}
@@ -0,0 +1,532 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::git_backend::FileChange;
+use crate::git_helper::{Authors, GitCommitCandidate};
+use crate::patch::{FileDiff, Hunk};
+use crate::tree::{Mark, Tree, TreeError};
+
+/// Version of the plan file format. It is bumped on incompatible changes.
+pub const PLAN_VERSION: u32 = 2;
+
+/// Name of the plan file in the Git directory, used unless another file is
+/// given.
+pub const PLAN_FILE: &str = "break-commits-plan.json";
+
+#[derive(Error, Debug)]
+pub enum PlanError {
+    #[error("IO error: {0}", value)]
+    IOError { value: io::Error },
+    #[error("Invalid plan: {0}", value)]
+    JSONError { value: serde_json::Error },
+    #[error(
+        "Unsupported plan version {0} (expected {1})",
+        version,
+        PLAN_VERSION
+    )]
+    VersionError { version: u64 },
+    #[error(
+        "The base {0:?} of the plan moved (expected: {1:?}, actual: {2:?}). \
+         Give --base or --commit to apply the plan to another range",
+        base,
+        expected,
+        actual
+    )]
+    BaseMovedError {
+        base: String,
+        expected: String,
+        actual: String,
+    },
+    #[error(
+        "The plan does not match the commits to split (changed but not in \
+         the plan: {0:?}, in the plan but not changed: {1:?})",
+        missing,
+        unknown
+    )]
+    MismatchError {
+        missing: Vec<PathBuf>,
+        unknown: Vec<PathBuf>,
+    },
+    #[error(
+        "The hunk {1:?} of {0:?} changed since the plan was made",
+        path,
+        index
+    )]
+    HunkChangedError { path: PathBuf, index: usize },
+    #[error("The group {0:?} matches no file", group)]
+    EmptyGroupError { group: String },
+    #[error("Files in no group (use --rest to commit them): {0:?}", paths)]
+    UnassignedError { paths: Vec<PathBuf> },
+    #[error("Tree error: {0}", value)]
+    TreeError { value: TreeError },
+}
+
+impl From<TreeError> for PlanError {
+    fn from(value: TreeError) -> Self {
+        PlanError::TreeError { value }
+    }
+}
+
+impl From<io::Error> for PlanError {
+    fn from(value: io::Error) -> Self {
+        PlanError::IOError { value }
+    }
+}
+
+impl From<serde_json::Error> for PlanError {
+    fn from(value: serde_json::Error) -> Self {
+        PlanError::JSONError { value }
+    }
+}
+
+/// The new commits of a split, saved to apply them later without any
+/// interaction, e.g. by a colleague or after a rebase.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    /// The base revision as it was given, e.g. `origin/main` or `HEAD~2`.
+    pub base: String,
+    /// The id `base` was resolved to, so the plan is refused if the revision
+    /// moved since.
+    pub base_id: String,
+    /// The commit given with `--commit`, whose later commits are replayed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// The id of HEAD when the plan was made. It is only informative, since
+    /// HEAD changes when the commits are rebased.
+    pub head: String,
+    pub commits: Vec<GitCommitCandidate>,
+}
+
+impl Plan {
+    /// Makes a plan of the commits, recording the hash of each selected
+    /// hunk from the diffs so the plan is refused if a hunk changed.
+    pub fn new(
+        base: &str,
+        base_id: &str,
+        commit: Option<&str>,
+        head: &str,
+        commits: &[GitCommitCandidate],
+        diffs: &HashMap<PathBuf, FileDiff>,
+    ) -> Plan {
+        let mut commits = commits.to_vec();
+        for hunk in commits.iter_mut().flat_map(|commit| &mut commit.hunks) {
+            hunk.hash = diffs
+                .get(&hunk.path)
+                .and_then(|diff| diff.hunks.get(hunk.index))
+                .map(Hunk::hash);
+        }
+
+        Plan {
+            version: PLAN_VERSION,
+            base: base.to_owned(),
+            base_id: base_id.to_owned(),
+            commit: commit.map(str::to_owned),
+            head: head.to_owned(),
+            commits,
+        }
+    }
+
+    /// Reads a plan, refusing the versions it does not know.
+    pub fn load(path: &Path) -> Result<Plan, PlanError> {
+        // The version is checked first, since another version may not even
+        // deserialize.
+        let value: serde_json::Value =
+            serde_json::from_reader(File::open(path)?)?;
+        let version = value["version"].as_u64().unwrap_or_default();
+        if version != u64::from(PLAN_VERSION) {
+            return Err(PlanError::VersionError { version });
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), PlanError> {
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+        Ok(())
+    }
+
+    /// Checks that the base revision of the plan still resolves to the same
+    /// commit. It is only called if the range of the plan is used.
+    pub fn check_base(&self, base_id: &str) -> Result<(), PlanError> {
+        if self.base_id == base_id {
+            return Ok(());
+        }
+        Err(PlanError::BaseMovedError {
+            base: self.base.clone(),
+            expected: self.base_id.clone(),
+            actual: base_id.to_owned(),
+        })
+    }
+
+    /// Checks that the plan still applies to the changes of the range, even
+    /// if the commits were rebased: it has to commit exactly the changed
+    /// files, and every selected hunk has to be unchanged.
+    pub fn check(
+        &self,
+        changes: &[FileChange],
+        diffs: &[FileDiff],
+    ) -> Result<(), PlanError> {
+        self.check_paths(changes)?;
+
+        for hunk in self.commits.iter().flat_map(|commit| &commit.hunks) {
+            let Some(hash) = &hunk.hash else {
+                continue;
+            };
+            let actual = diffs
+                .iter()
+                .find(|diff| diff.path == hunk.path)
+                .and_then(|diff| diff.hunks.get(hunk.index))
+                .map(Hunk::hash);
+            if actual.as_ref() != Some(hash) {
+                return Err(PlanError::HunkChangedError {
+                    path: hunk.path.clone(),
+                    index: hunk.index,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_paths(&self, changes: &[FileChange]) -> Result<(), PlanError> {
+        let changed: BTreeSet<&PathBuf> =
+            changes.iter().map(|change| &change.path).collect();
+        let planned: BTreeSet<&PathBuf> = self
+            .commits
+            .iter()
+            .flat_map(|commit| {
+                let hunks = commit.hunks.iter().map(|hunk| &hunk.path);
+                commit.file_paths.iter().chain(hunks)
+            })
+            .collect();
+
+        if changed == planned {
+            return Ok(());
+        }
+        Err(PlanError::MismatchError {
+            missing: changed
+                .difference(&planned)
+                .map(|p| p.to_path_buf())
+                .collect(),
+            unknown: planned
+                .difference(&changed)
+                .map(|p| p.to_path_buf())
+                .collect(),
+        })
+    }
+}
+
+/// A commit message with the globs of the files to commit, given as
+/// `MSG::GLOB[,GLOB...]` on the command line.
+#[derive(Debug, Clone)]
+pub struct SplitGroup {
+    pub msg: String,
+    pub patterns: Vec<Pattern>,
+}
+
+impl SplitGroup {
+    /// Parses `MSG::GLOB[,GLOB...]`. The message is split at the last `::`,
+    /// so it may contain `::` itself.
+    pub fn parse(value: &str) -> Result<SplitGroup, String> {
+        let (msg, globs) = value
+            .rsplit_once("::")
+            .ok_or_else(|| format!("expected MSG::GLOB, got {:?}", value))?;
+        if msg.trim().is_empty() {
+            return Err(format!("empty message in {:?}", value));
+        }
+        let patterns = globs
+            .split(',')
+            .map(|glob| Pattern::new(glob).map_err(|error| error.to_string()))
+            .collect::<Result<Vec<Pattern>, String>>()?;
+
+        Ok(SplitGroup {
+            msg: msg.to_owned(),
+            patterns,
+        })
+    }
+}
+
+/// Assigns the changed files to the groups in order, as if they were
+/// selected in the file navigator, where a file matched by several groups
+/// goes to the first one. The files left in no group are committed with
+/// `rest` if it is given. Every commit is authored by the original author
+/// who changed most of its files.
+pub fn split_by_groups(
+    changes: &[FileChange],
+    groups: &[SplitGroup],
+    rest: Option<&str>,
+    authors: &Authors,
+) -> Result<Vec<GitCommitCandidate>, PlanError> {
+    let candidate = |msg: &str, file_paths: Vec<PathBuf>| GitCommitCandidate {
+        msg: msg.to_owned(),
+        author: authors.majority(&file_paths).cloned(),
+        file_paths,
+        hunks: vec![],
+    };
+
+    let mut tree = Tree::new_from_changes(changes)?;
+    let mut commits = vec![];
+    for group in groups {
+        let num_matched: usize = group
+            .patterns
+            .iter()
+            .map(|pattern| tree.borrow_mut().select_matching(pattern))
+            .sum();
+        if num_matched == 0 {
+            return Err(PlanError::EmptyGroupError {
+                group: group.msg.clone(),
+            });
+        }
+
+        let file_paths = tree.borrow().get_selected_file_paths();
+        commits.push(candidate(&group.msg, file_paths));
+        let remaining = tree.borrow().get_remaining_tree()?;
+        tree = remaining;
+    }
+
+    let mut tree = tree.borrow_mut();
+    if tree.num_leaf_node > 0 {
+        let root_id = tree.root_id();
+        tree.mark(root_id, Mark::Selected);
+        let paths = tree.get_selected_file_paths();
+        match rest {
+            Some(msg) => commits.push(candidate(msg, paths)),
+            None => return Err(PlanError::UnassignedError { paths }),
+        }
+    }
+    Ok(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_backend::{ChangeStatus, Signature};
+    use crate::patch::{parse_diff, HunkSelection, PatchBuilder, PatchError};
+    use tempfile::tempdir;
+
+    fn plan() -> Plan {
+        let commits = vec![
+            GitCommitCandidate {
+                msg: "first".to_owned(),
+                file_paths: vec![PathBuf::from("a")],
+                hunks: vec![HunkSelection {
+                    path: PathBuf::from("b"),
+                    index: 1,
+                    lines: vec![0, 2],
+                    hash: None,
+                }],
+                author: Some(Signature {
+                    name: "Other".to_owned(),
+                    email: "other@example.com".to_owned(),
+                    date: "1700000000 +0000".to_owned(),
+                }),
+            },
+            GitCommitCandidate {
+                msg: "second".to_owned(),
+                file_paths: vec![PathBuf::from("c")],
+                hunks: vec![HunkSelection {
+                    path: PathBuf::from("b"),
+                    index: 0,
+                    lines: vec![1],
+                    hash: None,
+                }],
+                author: None,
+            },
+        ];
+        let diffs = diffs()
+            .into_iter()
+            .map(|diff| (diff.path.clone(), diff))
+            .collect();
+        Plan::new("HEAD~2", "89abcde", None, "1234567", &commits, &diffs)
+    }
+
+    fn diffs() -> Vec<FileDiff> {
+        let diff = b"diff --git a/b b/b\n--- a/b\n+++ b/b\n\
+@@ -1 +1 @@\n-a\n+b\n@@ -10 +10 @@\n-c\n+d\n";
+        parse_diff(diff).expect("")
+    }
+
+    fn change(path: &str) -> FileChange {
+        FileChange {
+            status: ChangeStatus::Modified,
+            path: PathBuf::from(path),
+            old_path: None,
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() -> Result<(), PlanError> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("plan.json");
+        let plan = plan();
+        plan.save(&path)?;
+
+        let loaded = Plan::load(&path)?;
+        assert_eq!(loaded.version, PLAN_VERSION);
+        assert_eq!(loaded.base, "HEAD~2");
+        assert_eq!(loaded.base_id, "89abcde");
+        assert_eq!(loaded.commit, None);
+        assert_eq!(loaded.head, "1234567");
+        assert_eq!(loaded.commits.len(), 2);
+        assert_eq!(loaded.commits[0].msg, "first");
+        assert_eq!(loaded.commits[0].hunks, plan.commits[0].hunks);
+        assert_eq!(loaded.commits[0].author, plan.commits[0].author);
+        assert_eq!(loaded.commits[1].file_paths, vec![PathBuf::from("c")]);
+        assert_eq!(loaded.commits[1].author, None);
+
+        // The hunks are saved with their hashes.
+        let hashes: Vec<Option<&str>> = loaded
+            .commits
+            .iter()
+            .flat_map(|commit| &commit.hunks)
+            .map(|hunk| hunk.hash.as_deref())
+            .collect();
+        assert_eq!(hashes.len(), 2);
+        assert!(hashes.iter().all(Option::is_some));
+        assert_ne!(hashes[0], hashes[1]);
+
+        std::fs::write(&path, r#"{"version": 3, "steps": []}"#)?;
+        assert!(matches!(
+            Plan::load(&path),
+            Err(PlanError::VersionError { version: 3 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() {
+        let plan = plan();
+        let changes = [change("a"), change("b"), change("c")];
+        assert!(plan.check(&changes, &diffs()).is_ok());
+        assert!(plan.check_base("89abcde").is_ok());
+
+        match plan.check_base("7654321") {
+            Err(PlanError::BaseMovedError { base, expected, .. }) => {
+                assert_eq!(base, "HEAD~2");
+                assert_eq!(expected, "89abcde");
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // A hunk whose content changed is refused.
+        let diff = b"diff --git a/b b/b\n--- a/b\n+++ b/b\n\
+@@ -1 +1 @@\n-a\n+b\n@@ -10 +10 @@\n-c\n+e\n";
+        match plan.check(&changes, &parse_diff(diff).expect("")) {
+            Err(PlanError::HunkChangedError { path, index }) => {
+                assert_eq!(path, PathBuf::from("b"));
+                assert_eq!(index, 1);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let changes = [change("a"), change("b"), change("d")];
+        match plan.check(&changes, &diffs()) {
+            Err(PlanError::MismatchError { missing, unknown }) => {
+                assert_eq!(missing, vec![PathBuf::from("d")]);
+                assert_eq!(unknown, vec![PathBuf::from("c")]);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_changed_hunk() -> Result<(), PatchError> {
+        let plan = plan();
+        let hunks = &plan.commits[1].hunks;
+
+        let diff =
+            b"diff --git a/b b/b\n--- a/b\n+++ b/b\n@@ -1 +1 @@\n-a\n+b\n";
+        PatchBuilder::new(parse_diff(diff)?).build(&[], hunks)?;
+
+        let diff =
+            b"diff --git a/b b/b\n--- a/b\n+++ b/b\n@@ -1 +1 @@\n-a\n+c\n";
+        assert!(matches!(
+            PatchBuilder::new(parse_diff(diff)?).build(&[], hunks),
+            Err(PatchError::HunkChangedError { index: 0, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_group_parse() {
+        let group = SplitGroup::parse("core::fix: x::src/**,*.rs").expect("");
+        assert_eq!(group.msg, "core::fix: x");
+        assert_eq!(group.patterns.len(), 2);
+        assert_eq!(group.patterns[1].as_str(), "*.rs");
+
+        assert!(SplitGroup::parse("no globs").is_err());
+        assert!(SplitGroup::parse("::src/**").is_err());
+        assert!(SplitGroup::parse("bad::src/[").is_err());
+    }
+
+    #[test]
+    fn test_split_by_groups() -> Result<(), PlanError> {
+        let changes = [
+            change("docs/guide.md"),
+            change("docs/api/index.md"),
+            change("src/main.rs"),
+            change("README.md"),
+            change("Cargo.toml"),
+        ];
+        let groups = [
+            SplitGroup::parse("docs: update::docs/**").expect(""),
+            SplitGroup::parse("core: fix::src/*.rs,*.md").expect(""),
+        ];
+
+        let commits = split_by_groups(
+            &changes,
+            &groups,
+            Some("chore: misc"),
+            &Authors::default(),
+        )?;
+        let paths: Vec<Vec<&str>> = commits
+            .iter()
+            .map(|commit| {
+                commit
+                    .file_paths
+                    .iter()
+                    .map(|path| path.to_str().unwrap_or_default())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                vec!["docs/guide.md", "docs/api/index.md"],
+                vec!["src/main.rs", "README.md"],
+                vec!["Cargo.toml"],
+            ]
+        );
+        assert_eq!(commits[2].msg, "chore: misc");
+
+        match split_by_groups(&changes, &groups, None, &Authors::default()) {
+            Err(PlanError::UnassignedError { paths }) => {
+                assert_eq!(paths, vec![PathBuf::from("Cargo.toml")])
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // The files under docs/ are taken by the first group.
+        let groups = [
+            SplitGroup::parse("docs: update::docs/**").expect(""),
+            SplitGroup::parse("docs: more::docs/*.md").expect(""),
+        ];
+        match split_by_groups(
+            &changes,
+            &groups,
+            Some("rest"),
+            &Authors::default(),
+        ) {
+            Err(PlanError::EmptyGroupError { group }) => {
+                assert_eq!(group, "docs: more")
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::app::App;
+use crate::git_helper::GitCommitCandidate;
+use crate::patch::HunkSelection;
+
+/// Version of the session file format. It is bumped on incompatible changes.
+pub const SESSION_VERSION: u32 = 1;
+
+/// Name of the session file in the Git directory.
+pub const SESSION_FILE: &str = "break-commits-session.json";
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("IO error: {0}", value)]
+    IOError { value: io::Error },
+    #[error("Invalid session: {0}", value)]
+    JSONError { value: serde_json::Error },
+    #[error("No session to resume in {0:?}", path)]
+    NoSessionError { path: PathBuf },
+    #[error(
+        "Unsupported session version {0} (expected {1})",
+        version,
+        SESSION_VERSION
+    )]
+    VersionError { version: u64 },
+    #[error(
+        "HEAD moved since the session was saved (expected: {0:?}, actual: {1:?})",
+        expected,
+        actual
+    )]
+    HeadMovedError { expected: String, actual: String },
+}
+
+impl From<io::Error> for SessionError {
+    fn from(value: io::Error) -> Self {
+        SessionError::IOError { value }
+    }
+}
+
+impl From<serde_json::Error> for SessionError {
+    fn from(value: serde_json::Error) -> Self {
+        SessionError::JSONError { value }
+    }
+}
+
+/// The state of an interactive split, saved on every change so that it can
+/// be resumed after the terminal is closed or the user quits by mistake.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    /// The base revision as it was given, e.g. `origin/main` or `HEAD~2`.
+    pub base: String,
+    /// The commit given with `--commit`, whose later commits are replayed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// The id of HEAD when the session was started.
+    pub head: String,
+    /// Whether the changes are split at the hunk level.
+    pub hunk: bool,
+    pub commits: Vec<GitCommitCandidate>,
+    /// The selected files of the changes which are not committed yet.
+    pub selected_files: Vec<PathBuf>,
+    /// The selected hunk lines of the changes which are not committed yet.
+    pub selected_hunks: Vec<HunkSelection>,
+    /// The text in the commit message editor.
+    pub draft: String,
+}
+
+impl Session {
+    pub fn new(
+        base: &str,
+        commit: Option<&str>,
+        head: &str,
+        app: &App,
+    ) -> Session {
+        let tree = app.tree.borrow();
+        Session {
+            version: SESSION_VERSION,
+            base: base.to_owned(),
+            commit: commit.map(str::to_owned),
+            head: head.to_owned(),
+            hunk: app.is_hunk_mode(),
+            commits: app.commits.clone(),
+            selected_files: tree.get_selected_file_paths(),
+            selected_hunks: tree.get_selected_hunks(),
+            draft: app.textarea.lines().join("\n"),
+        }
+    }
+
+    /// Reads a session, refusing the versions it does not know.
+    pub fn load(path: &Path) -> Result<Session, SessionError> {
+        if !path.exists() {
+            return Err(SessionError::NoSessionError {
+                path: path.to_path_buf(),
+            });
+        }
+
+        let value: serde_json::Value =
+            serde_json::from_reader(File::open(path)?)?;
+        let version = value["version"].as_u64().unwrap_or_default();
+        if version != u64::from(SESSION_VERSION) {
+            return Err(SessionError::VersionError { version });
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Writes the session to a temporary file first, so that a session is
+    /// not lost if the process is killed while writing.
+    pub fn save(&self, path: &Path) -> Result<(), SessionError> {
+        let temp_path = path.with_extension("json.tmp");
+        serde_json::to_writer(File::create(&temp_path)?, self)?;
+        fs::rename(temp_path, path)?;
+        Ok(())
+    }
+
+    /// Removes the session once the split is applied or rehearsed with
+    /// `--dry-run`.
+    pub fn remove(path: &Path) -> Result<(), SessionError> {
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                Err(error.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that HEAD has not moved since the session was started, so
+    /// that the saved selection still applies to the same changes.
+    pub fn check(&self, head: &str) -> Result<(), SessionError> {
+        if self.head == head {
+            return Ok(());
+        }
+        Err(SessionError::HeadMovedError {
+            expected: self.head.clone(),
+            actual: head.to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_backend::{ChangeStatus, FileChange};
+    use tempfile::tempdir;
+
+    fn change(path: &str) -> FileChange {
+        FileChange {
+            status: ChangeStatus::Modified,
+            path: PathBuf::from(path),
+            old_path: None,
+        }
+    }
+
+    #[test]
+    fn test_save_and_resume() -> Result<(), SessionError> {
+        let changes = vec![change("a"), change("b"), change("dir/c")];
+        let mut app = App::new(changes.clone()).expect("");
+        app.tree.borrow_mut().select_paths(&[PathBuf::from("a")]);
+        app.textarea.insert_str("first");
+        app.save_commit().expect("");
+        app.tree
+            .borrow_mut()
+            .select_paths(&[PathBuf::from("dir/c")]);
+        app.textarea.insert_str("draft");
+
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join(SESSION_FILE);
+        let session = Session::new("HEAD~", None, "1234567", &app);
+        session.save(&path)?;
+
+        let loaded = Session::load(&path)?;
+        assert_eq!(loaded, session);
+        assert!(loaded.check("1234567").is_ok());
+        assert!(matches!(
+            loaded.check("7654321"),
+            Err(SessionError::HeadMovedError { .. })
+        ));
+
+        let mut resumed = App::new(changes).expect("");
+        resumed.restore(&loaded).expect("");
+        assert_eq!(resumed.commits[0].file_paths, vec![PathBuf::from("a")]);
+        assert_eq!(resumed.get_stats(), (2, 1));
+        assert_eq!(
+            resumed.tree.borrow().get_selected_file_paths(),
+            vec![PathBuf::from("dir/c")]
+        );
+        assert_eq!(resumed.textarea.lines(), ["draft"]);
+
+        Session::remove(&path)?;
+        Session::remove(&path)?;
+        assert!(matches!(
+            Session::load(&path),
+            Err(SessionError::NoSessionError { .. })
+        ));
+        Ok(())
+    }
+}
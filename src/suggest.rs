@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern, PatternError};
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SuggestError {
+    #[error("Invalid Cargo.toml: {0}", value)]
+    TOMLError { value: toml::de::Error },
+    #[error("Invalid workspace member {0:?}: {1}", member, value)]
+    MemberError { member: String, value: PatternError },
+}
+
+impl From<toml::de::Error> for SuggestError {
+    fn from(value: toml::de::Error) -> Self {
+        SuggestError::TOMLError { value }
+    }
+}
+
+/// How the changed files are grouped into suggested commits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// One commit per top-level directory.
+    Directory,
+    /// One commit per file extension.
+    Extension,
+    /// One commit per member of the Cargo workspace.
+    Crate,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 3] =
+        [Strategy::Directory, Strategy::Extension, Strategy::Crate];
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Strategy::Directory => "One commit per top-level directory",
+            Strategy::Extension => "One commit per file extension",
+            Strategy::Crate => "One commit per crate of the Cargo workspace",
+        }
+    }
+}
+
+/// A suggested commit: the files to select and what they have in common.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub name: String,
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    workspace: Option<WorkspaceTable>,
+    package: Option<PackageTable>,
+}
+
+#[derive(Deserialize)]
+struct WorkspaceTable {
+    #[serde(default)]
+    members: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct PackageTable {
+    name: String,
+}
+
+/// The members of the Cargo workspace at the top of the repository.
+#[derive(Debug)]
+pub struct Workspace {
+    members: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    /// The name of the package of the workspace root, if it is one.
+    root_package: Option<String>,
+}
+
+impl Workspace {
+    /// Reads the members from the manifest at the top of the repository.
+    /// Returns `None` if it is not the manifest of a workspace.
+    pub fn parse(manifest: &str) -> Result<Option<Workspace>, SuggestError> {
+        let manifest: Manifest = toml::from_str(manifest)?;
+        let Some(workspace) = manifest.workspace else {
+            return Ok(None);
+        };
+
+        Ok(Some(Workspace {
+            members: parse_members(&workspace.members)?,
+            exclude: parse_members(&workspace.exclude)?,
+            root_package: manifest.package.map(|package| package.name),
+        }))
+    }
+
+    /// Returns the directory of the member containing the file, the deepest
+    /// one if the members are nested.
+    fn member_of(&self, path: &Path) -> Option<PathBuf> {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::default()
+        };
+        let matches = |patterns: &[Pattern], dir: &Path| {
+            patterns
+                .iter()
+                .any(|pattern| pattern.matches_path_with(dir, options))
+        };
+
+        path.ancestors()
+            .skip(1)
+            .take_while(|dir| !dir.as_os_str().is_empty())
+            .find(|dir| {
+                matches(&self.members, dir) && !matches(&self.exclude, dir)
+            })
+            .map(Path::to_path_buf)
+    }
+}
+
+fn parse_members(members: &[String]) -> Result<Vec<Pattern>, SuggestError> {
+    members
+        .iter()
+        .map(|member| {
+            let dir = member.trim_start_matches("./").trim_end_matches('/');
+            Pattern::new(dir).map_err(|value| SuggestError::MemberError {
+                member: member.clone(),
+                value,
+            })
+        })
+        .collect()
+}
+
+/// Groups the files into suggested commits by the strategy. The groups are
+/// sorted by name, followed by the files which fit in no group, e.g. the
+/// files at the top level for [`Strategy::Directory`].
+///
+/// Nothing is suggested per crate without a workspace.
+pub fn suggest(
+    paths: &[PathBuf],
+    strategy: Strategy,
+    workspace: Option<&Workspace>,
+) -> Vec<Suggestion> {
+    let rest_name = match (strategy, workspace) {
+        (Strategy::Directory, _) => "top-level files".to_owned(),
+        (Strategy::Extension, _) => "files without an extension".to_owned(),
+        (Strategy::Crate, Some(workspace)) => workspace
+            .root_package
+            .clone()
+            .unwrap_or_else(|| "workspace root".to_owned()),
+        (Strategy::Crate, None) => return vec![],
+    };
+
+    let mut groups: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    let mut rest = vec![];
+    for path in paths {
+        let name = match (strategy, workspace) {
+            (Strategy::Directory, _) => path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .and_then(|_| path.iter().next())
+                .map(|dir| format!("{}/", dir.to_string_lossy())),
+            (Strategy::Extension, _) => path
+                .extension()
+                .map(|extension| format!("*.{}", extension.to_string_lossy())),
+            (Strategy::Crate, Some(workspace)) => workspace
+                .member_of(path)
+                .map(|dir| format!("{}/", dir.to_string_lossy())),
+            (Strategy::Crate, None) => None,
+        };
+        match name {
+            Some(name) => groups.entry(name).or_default().push(path.clone()),
+            None => rest.push(path.clone()),
+        }
+    }
+
+    let mut suggestions: Vec<Suggestion> = groups
+        .into_iter()
+        .map(|(name, paths)| Suggestion { name, paths })
+        .collect();
+    if !rest.is_empty() {
+        suggestions.push(Suggestion {
+            name: rest_name,
+            paths: rest,
+        });
+    }
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    fn names(suggestions: &[Suggestion]) -> Vec<&str> {
+        suggestions
+            .iter()
+            .map(|suggestion| suggestion.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_suggest_by_directory_and_extension() {
+        let changed = paths(&[
+            "src/main.rs",
+            "README.md",
+            "docs/guide.md",
+            "src/ui/mod.rs",
+            "Makefile",
+        ]);
+
+        let suggestions = suggest(&changed, Strategy::Directory, None);
+        assert_eq!(names(&suggestions), ["docs/", "src/", "top-level files"]);
+        assert_eq!(
+            suggestions[1].paths,
+            paths(&["src/main.rs", "src/ui/mod.rs"])
+        );
+        assert_eq!(suggestions[2].paths, paths(&["README.md", "Makefile"]));
+
+        let suggestions = suggest(&changed, Strategy::Extension, None);
+        assert_eq!(
+            names(&suggestions),
+            ["*.md", "*.rs", "files without an extension"]
+        );
+        assert_eq!(
+            suggestions[0].paths,
+            paths(&["README.md", "docs/guide.md"])
+        );
+
+        assert!(suggest(&changed, Strategy::Crate, None).is_empty());
+    }
+
+    #[test]
+    fn test_suggest_by_crate() -> Result<(), SuggestError> {
+        let manifest = r#"
+            [package]
+            name = "app"
+
+            [workspace]
+            members = ["./crates/*", "tools/gen/", "crates/core/macros"]
+            exclude = ["crates/old"]
+        "#;
+        let workspace = Workspace::parse(manifest)?;
+        let changed = paths(&[
+            "crates/core/src/lib.rs",
+            "crates/core/macros/src/lib.rs",
+            "crates/ui/Cargo.toml",
+            "crates/old/src/lib.rs",
+            "tools/gen/src/main.rs",
+            "src/main.rs",
+            "Cargo.lock",
+        ]);
+
+        let suggestions =
+            suggest(&changed, Strategy::Crate, workspace.as_ref());
+        assert_eq!(
+            names(&suggestions),
+            [
+                "crates/core/",
+                "crates/core/macros/",
+                "crates/ui/",
+                "tools/gen/",
+                "app"
+            ]
+        );
+        assert_eq!(
+            suggestions[4].paths,
+            paths(&["crates/old/src/lib.rs", "src/main.rs", "Cargo.lock"])
+        );
+
+        let manifest = "[package]\nname = \"app\"\n";
+        assert!(Workspace::parse(manifest)?.is_none());
+        assert!(Workspace::parse("[workspace").is_err());
+        Ok(())
+    }
+}
@@ -1,8 +1,11 @@
 use std::collections::HashMap;
 
+use crate::git_backend::{ChangeStatus, FileChange};
+use crate::patch::{FileDiff, HunkSelection};
+use glob::{MatchOptions, Pattern};
 use path_clean::PathClean;
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{cell::RefCell, rc::Rc};
 use thiserror::Error;
 
@@ -36,6 +39,14 @@ pub struct Node {
     pub key: Option<OsString>,
     pub mark: Mark,
     pub fullpath: Option<PathBuf>,
+    /// How the file is changed if this node is a file.
+    pub status: Option<ChangeStatus>,
+    /// Index of the diff hunk of `fullpath` if this node is a hunk.
+    pub hunk: Option<usize>,
+    /// Changed lines of the hunk which are not committed yet.
+    pub lines: Vec<usize>,
+    /// Subset of `lines` to commit if the hunk is partially selected.
+    pub selected_lines: Vec<usize>,
     pub parent: Option<NodeId>,
     pub children: HashMap<OsString, NodeId>,
 }
@@ -46,6 +57,10 @@ impl Node {
             key: None,
             mark: Mark::Unselected,
             fullpath: None,
+            status: None,
+            hunk: None,
+            lines: vec![],
+            selected_lines: vec![],
             parent: None,
             children: HashMap::new(),
         }
@@ -60,6 +75,8 @@ impl Node {
 pub struct Tree {
     nodes: Vec<Node>,
     leaf_node_ids: Vec<NodeId>,
+    /// Sources of the renamed files by their paths.
+    old_paths: HashMap<PathBuf, PathBuf>,
     pub num_leaf_node: usize,
     pub num_selected: usize,
 }
@@ -71,6 +88,7 @@ impl Default for Tree {
         Self {
             nodes: vec![Node::new_root()],
             leaf_node_ids: vec![],
+            old_paths: HashMap::new(),
             num_leaf_node: 0,
             num_selected: 0,
         }
@@ -91,6 +109,56 @@ impl Tree {
         Ok(tree)
     }
 
+    /// Builds a tree of the changed files, where a renamed file is
+    /// a single entry linked to its source.
+    pub fn new_from_changes(
+        changes: &[FileChange],
+    ) -> Result<TreePtr, TreeError> {
+        let tree = Tree::new_ptr();
+
+        for change in changes {
+            let mut tree = tree.borrow_mut();
+            let node_id = tree.add(change.path.clone())?;
+            tree.nodes[node_id].status = Some(change.status);
+            if let Some(old_path) = &change.old_path {
+                tree.old_paths.insert(change.path.clone(), old_path.clone());
+            }
+        }
+
+        Ok(tree)
+    }
+
+    /// Builds a tree whose files are expanded into their diff hunks.
+    ///
+    /// Files without any hunk, e.g. binary files, remain leaf nodes.
+    pub fn new_from_diffs(diffs: &[FileDiff]) -> Result<TreePtr, TreeError> {
+        let tree = Tree::new_ptr();
+
+        for diff in diffs {
+            if let Some(old_path) = &diff.old_path {
+                tree.borrow_mut()
+                    .old_paths
+                    .insert(diff.path.clone(), old_path.clone());
+            }
+            if diff.hunks.is_empty() {
+                tree.borrow_mut().add(diff.path.clone())?;
+            }
+            for (index, hunk) in diff.hunks.iter().enumerate() {
+                let key = OsString::from(hunk.title());
+                let lines = hunk.changed_lines();
+                tree.borrow_mut().add_hunk(
+                    diff.path.clone(),
+                    index,
+                    key,
+                    lines,
+                )?;
+            }
+            tree.borrow_mut().set_status(&diff.path, diff.status());
+        }
+
+        Ok(tree)
+    }
+
     pub fn new_ptr() -> TreePtr {
         Rc::new(RefCell::new(Tree::default()))
     }
@@ -99,7 +167,7 @@ impl Tree {
         0
     }
 
-    pub fn add(&mut self, value: PathBuf) -> Result<(), TreeError> {
+    pub fn add(&mut self, value: PathBuf) -> Result<NodeId, TreeError> {
         let cleaned_path = value.clean();
 
         let mut curr_id = self.root_id();
@@ -125,6 +193,10 @@ impl Tree {
                         } else {
                             None
                         },
+                        status: None,
+                        hunk: None,
+                        lines: vec![],
+                        selected_lines: vec![],
                         parent: Some(curr_id),
                         children: HashMap::new(),
                     };
@@ -143,7 +215,48 @@ impl Tree {
                 }
             }
         }
-        Ok(())
+        Ok(curr_id)
+    }
+
+    /// Adds a diff hunk of the file as a child of the file node.
+    ///
+    /// `lines` are the changed lines of the hunk which are not committed yet.
+    /// The file node is no longer a leaf node once it has a hunk.
+    pub fn add_hunk(
+        &mut self,
+        file_path: PathBuf,
+        index: usize,
+        key: OsString,
+        lines: Vec<usize>,
+    ) -> Result<NodeId, TreeError> {
+        let file_id = self.add(file_path)?;
+
+        if let Some(pos) =
+            self.leaf_node_ids.iter().position(|id| *id == file_id)
+        {
+            self.leaf_node_ids.remove(pos);
+            self.num_leaf_node -= 1;
+        }
+
+        let child_id = self.nodes.len();
+        let new_node = Node {
+            key: Some(key.clone()),
+            mark: Mark::Unselected,
+            fullpath: self.nodes[file_id].fullpath.clone(),
+            status: None,
+            hunk: Some(index),
+            lines,
+            selected_lines: vec![],
+            parent: Some(file_id),
+            children: HashMap::new(),
+        };
+
+        self.nodes.push(new_node);
+        self.nodes[file_id].children.insert(key, child_id);
+        self.num_leaf_node += 1;
+        self.leaf_node_ids.push(child_id);
+
+        Ok(child_id)
     }
 
     pub fn get_root(&self) -> &Node {
@@ -155,6 +268,34 @@ impl Tree {
         &self.nodes[node_id]
     }
 
+    fn set_status(&mut self, path: &Path, status: ChangeStatus) {
+        if let Some(node_id) = self.find_node(path.to_path_buf()) {
+            self.nodes[node_id].status = Some(status);
+        }
+    }
+
+    /// Counts the files under the node per change status.
+    pub fn count_statuses(
+        &self,
+        node_id: NodeId,
+    ) -> Vec<(ChangeStatus, usize)> {
+        let mut counts: HashMap<ChangeStatus, usize> = HashMap::new();
+        let mut stack = vec![node_id];
+
+        while let Some(node_id) = stack.pop() {
+            let node = self.get_node(node_id);
+            match node.status {
+                Some(status) => *counts.entry(status).or_default() += 1,
+                None => stack.extend(node.children.values()),
+            }
+        }
+
+        let mut counts: Vec<(ChangeStatus, usize)> =
+            counts.into_iter().collect();
+        counts.sort();
+        counts
+    }
+
     pub fn find_node(&self, value: PathBuf) -> Option<NodeId> {
         let cleaned_path = value.clean();
 
@@ -178,9 +319,9 @@ impl Tree {
         let is_leaf_node = stack == cleaned_path;
 
         if is_leaf_node {
-            return Some(curr_id);
+            Some(curr_id)
         } else {
-            return None;
+            None
         }
     }
 
@@ -195,6 +336,7 @@ impl Tree {
         is_first_call: bool,
     ) {
         self.nodes[node_id].mark = new_mark;
+        self.nodes[node_id].selected_lines.clear();
 
         let children_ids: Vec<NodeId> = self
             .get_node(node_id)
@@ -213,6 +355,56 @@ impl Tree {
         }
     }
 
+    /// Selects some of the changed lines of a hunk node.
+    pub fn select_lines(&mut self, node_id: NodeId, lines: Vec<usize>) {
+        let node = &mut self.nodes[node_id];
+        let lines: Vec<usize> = node
+            .lines
+            .iter()
+            .copied()
+            .filter(|line| lines.contains(line))
+            .collect();
+
+        if lines.is_empty() {
+            self.mark(node_id, Mark::Unselected);
+        } else if lines.len() == node.lines.len() {
+            self.mark(node_id, Mark::Selected);
+        } else {
+            node.mark = Mark::PartiallySelected;
+            node.selected_lines = lines;
+            self.correct_parents_mark(node_id);
+            self.update_num_selected();
+        }
+    }
+
+    /// Selects the lines of the hunk which are not committed yet.
+    pub fn select_hunk(&mut self, selection: &HunkSelection) {
+        let Some(file_id) = self.find_node(selection.path.clone()) else {
+            return;
+        };
+        let hunk_id =
+            self.get_node(file_id)
+                .children
+                .values()
+                .copied()
+                .find(|node_id| {
+                    self.get_node(*node_id).hunk == Some(selection.index)
+                });
+        if let Some(hunk_id) = hunk_id {
+            self.select_lines(hunk_id, selection.lines.clone());
+        }
+    }
+
+    /// Returns the selected changed lines of a hunk node.
+    pub fn get_selected_lines(&self, node_id: NodeId) -> Vec<usize> {
+        let node = self.get_node(node_id);
+        match node.mark {
+            Mark::Selected => node.lines.clone(),
+            Mark::PartiallySelected => node.selected_lines.clone(),
+            Mark::Unselected => vec![],
+        }
+    }
+
     fn correct_parents_mark(&mut self, node_id: NodeId) {
         if let Some(parent_id) = self.get_node(node_id).parent {
             self.nodes[parent_id].mark =
@@ -231,12 +423,13 @@ impl Tree {
             if node.mark == Mark::Unselected {
                 continue;
             } else {
-                for (_, node_id) in &node.children {
+                for node_id in node.children.values() {
                     stack.push(*node_id);
                 }
             }
 
-            if node.is_leaf_node() && node.mark == Mark::Selected {
+            // A partially selected leaf node is a hunk with selected lines.
+            if node.is_leaf_node() {
                 num_selected += 1;
             }
         }
@@ -257,11 +450,11 @@ impl Tree {
             .collect();
 
         if children_marks.iter().all(|mark| *mark == Mark::Selected) {
-            return Mark::Selected;
+            Mark::Selected
         } else if children_marks.iter().all(|mark| *mark == Mark::Unselected) {
-            return Mark::Unselected;
+            Mark::Unselected
         } else {
-            return Mark::PartiallySelected;
+            Mark::PartiallySelected
         }
     }
 
@@ -284,16 +477,79 @@ impl Tree {
         path_buf
     }
 
+    /// Selects the unselected files whose paths match the glob, where `*`
+    /// does not cross a `/` but `**` does. Returns the number of newly
+    /// selected files.
+    pub fn select_matching(&mut self, pattern: &Pattern) -> usize {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::default()
+        };
+        let matching: Vec<NodeId> = self
+            .leaf_node_ids
+            .iter()
+            .copied()
+            .filter(|node_id| {
+                let node = self.get_node(*node_id);
+                node.mark == Mark::Unselected
+                    && node.fullpath.as_ref().is_some_and(|path| {
+                        pattern.matches_path_with(path, options)
+                    })
+            })
+            .collect();
+
+        for node_id in &matching {
+            self.mark(*node_id, Mark::Selected);
+        }
+        matching.len()
+    }
+
+    /// Selects the unselected files and hunks of the files at the paths.
+    /// Returns the number of newly selected files and hunks.
+    pub fn select_paths(&mut self, paths: &[PathBuf]) -> usize {
+        let matching: Vec<NodeId> = self
+            .leaf_node_ids
+            .iter()
+            .copied()
+            .filter(|node_id| {
+                let node = self.get_node(*node_id);
+                node.mark == Mark::Unselected
+                    && node
+                        .fullpath
+                        .as_ref()
+                        .is_some_and(|path| paths.contains(path))
+            })
+            .collect();
+
+        for node_id in &matching {
+            self.mark(*node_id, Mark::Selected);
+        }
+        matching.len()
+    }
+
+    /// Returns the paths of all files, once per file even if it is split
+    /// into hunks.
+    pub fn get_file_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = vec![];
+        for node_id in &self.leaf_node_ids {
+            if let Some(path) = &self.get_node(*node_id).fullpath {
+                if paths.last() != Some(path) {
+                    paths.push(path.clone());
+                }
+            }
+        }
+        paths
+    }
+
     pub fn get_selected_file_paths(&self) -> Vec<PathBuf> {
         let selected: Vec<PathBuf> = self
             .leaf_node_ids
             .iter()
             .filter_map(|node_id| {
                 let node = self.get_node(*node_id);
-                if let Mark::Selected = node.mark {
-                    node.fullpath.clone()
-                } else {
-                    None
+                match (node.mark, node.hunk) {
+                    (Mark::Selected, None) => node.fullpath.clone(),
+                    _ => None,
                 }
             })
             .collect();
@@ -301,15 +557,62 @@ impl Tree {
         selected
     }
 
+    pub fn get_selected_hunks(&self) -> Vec<HunkSelection> {
+        self.leaf_node_ids
+            .iter()
+            .filter_map(|node_id| {
+                let node = self.get_node(*node_id);
+                match (node.mark, &node.fullpath, node.hunk) {
+                    (Mark::Unselected, _, _) => None,
+                    (_, Some(path), Some(index)) => Some(HunkSelection {
+                        path: path.clone(),
+                        index,
+                        lines: self.get_selected_lines(*node_id),
+                        hash: None,
+                    }),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the source of the file if it is renamed.
+    pub fn get_old_path(&self, path: &Path) -> Option<&PathBuf> {
+        self.old_paths.get(path)
+    }
+
     pub fn get_remaining_tree(&self) -> Result<TreePtr, TreeError> {
         let new_tree = Tree::new_ptr();
+        new_tree.borrow_mut().old_paths = self.old_paths.clone();
 
         for node_id in &self.leaf_node_ids {
             let node = self.get_node(*node_id);
-            if node.mark == Mark::Unselected {
-                if let Some(path) = node.fullpath.clone() {
-                    new_tree.borrow_mut().add(path)?;
+            if node.mark == Mark::Selected {
+                continue;
+            }
+
+            match (node.fullpath.clone(), node.hunk, node.key.clone()) {
+                (Some(path), Some(index), Some(key)) => {
+                    let lines = node
+                        .lines
+                        .iter()
+                        .copied()
+                        .filter(|line| !node.selected_lines.contains(line))
+                        .collect();
+                    let status = node
+                        .parent
+                        .and_then(|parent| self.get_node(parent).status);
+                    let mut new_tree = new_tree.borrow_mut();
+                    new_tree.add_hunk(path.clone(), index, key, lines)?;
+                    if let Some(status) = status {
+                        new_tree.set_status(&path, status);
+                    }
+                }
+                (Some(path), _, _) if node.mark == Mark::Unselected => {
+                    let node_id = new_tree.borrow_mut().add(path)?;
+                    new_tree.borrow_mut().nodes[node_id].status = node.status;
                 }
+                _ => {}
             }
         }
 
@@ -323,9 +626,7 @@ impl Tree {
 
 #[cfg(test)]
 mod tests {
-    use std::{env, fs, io, str::FromStr};
-
-    use tempfile::tempdir;
+    use std::{io, str::FromStr};
 
     use super::*;
     #[test]
@@ -352,9 +653,7 @@ mod tests {
             PathBuf::from_str("./a/c/file.txt").expect(""),
             PathBuf::from_str("a/b/c/file2.txt").expect(""),
         ];
-        let tree = Tree::new_from_paths(file_paths).expect("");
-
-        tree
+        Tree::new_from_paths(file_paths).expect("")
     }
 
     #[test]
@@ -411,4 +710,142 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_hunk() -> Result<(), io::Error> {
+        let tree = prepare_tree();
+
+        let path = PathBuf::from_str("a/c/file.txt").expect("");
+        for index in 0..3 {
+            let key = OsString::from(format!("@@ hunk {} @@", index));
+            tree.borrow_mut()
+                .add_hunk(path.clone(), index, key, vec![1, 2, 4])
+                .expect("");
+        }
+
+        assert_eq!(tree.borrow().size(), 8 + 1 + 3);
+        assert_eq!(tree.borrow().num_leaf_node, 4 - 1 + 3);
+
+        let file_id = tree.borrow().find_node(path.clone()).expect("");
+        let hunk_id = *tree
+            .borrow()
+            .get_node(file_id)
+            .children
+            .get(&OsString::from("@@ hunk 1 @@"))
+            .expect("");
+        tree.borrow_mut().mark(hunk_id, Mark::Selected);
+
+        assert_eq!(
+            tree.borrow().get_node(file_id).mark,
+            Mark::PartiallySelected
+        );
+        assert_eq!(tree.borrow().get_selected_file_paths().len(), 0);
+        assert_eq!(
+            tree.borrow().get_selected_hunks(),
+            vec![HunkSelection {
+                path: path.clone(),
+                index: 1,
+                lines: vec![1, 2, 4],
+                hash: None,
+            }]
+        );
+
+        let remaining = tree.borrow().get_remaining_tree().expect("");
+
+        assert_eq!(remaining.borrow().num_leaf_node, 4 - 1 + 2);
+        assert_eq!(remaining.borrow().get_selected_hunks().len(), 0);
+
+        // Select some lines of the other hunk.
+        let hunk_id = *tree
+            .borrow()
+            .get_node(file_id)
+            .children
+            .get(&OsString::from("@@ hunk 2 @@"))
+            .expect("");
+        tree.borrow_mut().select_lines(hunk_id, vec![2, 3]);
+
+        assert_eq!(
+            tree.borrow().get_node(hunk_id).mark,
+            Mark::PartiallySelected
+        );
+        assert_eq!(tree.borrow().num_selected, 2);
+        assert_eq!(tree.borrow().get_selected_hunks()[1].lines, vec![2]);
+
+        let remaining = tree.borrow().get_remaining_tree().expect("");
+        let remaining = remaining.borrow();
+        let file_id = remaining.find_node(path).expect("");
+        let hunk_id = *remaining
+            .get_node(file_id)
+            .children
+            .get(&OsString::from("@@ hunk 2 @@"))
+            .expect("");
+
+        assert_eq!(remaining.num_leaf_node, 4 - 1 + 2);
+        assert_eq!(remaining.get_node(hunk_id).lines, vec![1, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() {
+        let change = |status, path: &str| FileChange {
+            status,
+            path: PathBuf::from(path),
+            old_path: None,
+        };
+        let changes = vec![
+            change(ChangeStatus::Added, "a/b/added"),
+            change(ChangeStatus::Deleted, "a/deleted"),
+            change(ChangeStatus::Added, "a/added"),
+            change(ChangeStatus::Modified, "modified"),
+        ];
+        let tree = Tree::new_from_changes(&changes).expect("");
+        let dir_id = tree.borrow().find_node(PathBuf::from("a")).expect("");
+
+        assert_eq!(
+            tree.borrow().count_statuses(dir_id),
+            vec![(ChangeStatus::Added, 2), (ChangeStatus::Deleted, 1)]
+        );
+
+        let file_id = tree.borrow().find_node(PathBuf::from("a/added"));
+        tree.borrow_mut().mark(file_id.expect(""), Mark::Selected);
+
+        let remaining = tree.borrow().get_remaining_tree().expect("");
+        let remaining = remaining.borrow();
+        assert_eq!(
+            remaining.count_statuses(remaining.root_id()),
+            vec![
+                (ChangeStatus::Added, 1),
+                (ChangeStatus::Modified, 1),
+                (ChangeStatus::Deleted, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_paths() {
+        let tree = prepare_tree();
+        let path = PathBuf::from("a/c/file.txt");
+        for index in 0..2 {
+            let key = OsString::from(format!("@@ hunk {} @@", index));
+            tree.borrow_mut()
+                .add_hunk(path.clone(), index, key, vec![1])
+                .expect("");
+        }
+
+        assert_eq!(
+            tree.borrow().get_file_paths(),
+            vec![
+                PathBuf::from("a/b/c/file.txt"),
+                PathBuf::from("a/b/file.txt"),
+                PathBuf::from("a/b/c/file2.txt"),
+                path.clone(),
+            ]
+        );
+
+        let paths = vec![path, PathBuf::from("a/b/file.txt")];
+        assert_eq!(tree.borrow_mut().select_paths(&paths), 3);
+        assert_eq!(tree.borrow().num_selected, 3);
+        assert_eq!(tree.borrow_mut().select_paths(&paths), 0);
+    }
 }
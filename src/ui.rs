@@ -1,28 +1,60 @@
 use crate::app::{App, CurrentScreen};
+use crate::git_backend::ChangeStatus;
+use crate::git_helper::plural;
 use crate::tree::Mark;
 use ratatui::{prelude::*, widgets::*};
+use std::ffi::OsStr;
 
 const HELP_MESSAGE: &str = r#"
 ↑: Move the cursor up
 ↓: Move the cursor down
 ←: Go to the parent directory
 →: Go to the subdirectory
-Space: Select or unselect a file/directory
+→: Go to the hunks of a file or the lines of a hunk (only with --hunk)
+Space: Select or unselect a file/directory/hunk/line
+Status: A added, M modified, D deleted, R renamed, T type changed, P mode
+        changed (counted per status for directories)
 Enter: Save the current selection and go to write the commit message
+u: Undo the last saved commit: its selection is restored and the editor is
+   reopened with its message
+Ctrl + T: Change the author in the commit message editor: the author who
+          changed most of the selected files, you, or any original author
+s: Save the commits made so far as a plan (see --save-plan)
+g: Suggest commits per top-level directory, file extension or crate of the
+   Cargo workspace. The files of each suggestion are selected in turn to be
+   reviewed and tweaked before writing its message
+n: Skip the suggested commit
+p: Open the plan editor to review the saved commits. It opens by itself once
+   every change is committed:
+   Shift + ↑/↓: Move the commit up or down
+   Enter: Edit the message
+   m: Merge the commit with the one below it
+   d: Delete the commit and return its changes to the file navigator
+   Ctrl + W: Apply the commits
 q or Ctrl + c: Quit without making any changes
 h: Open the help pop-up message
 "#;
 
 pub fn render(app: &mut App, f: &mut Frame) {
-    match app.current_screen {
+    match &app.current_screen {
         CurrentScreen::FileNavigator => render_file_navigator(app, f),
+        CurrentScreen::LineSelector => render_line_selector(app, f),
         CurrentScreen::CommitMessageEditor => {
             render_commit_message_editor(app, f)
         }
+        CurrentScreen::SuggestionPicker => {
+            render_file_navigator(app, f);
+            render_suggestion_picker(app, f)
+        }
+        CurrentScreen::PlanEditor => render_plan_editor(app, f),
         CurrentScreen::ErrorMessagePopUp(msg, _) => {
             let title = " Error! Press any key to close this pop up ";
             render_pop_up(f, msg, title)
         }
+        CurrentScreen::InfoMessagePopUp(msg, _) => {
+            let title = " Press any key to close this pop up ";
+            render_pop_up(f, msg, title)
+        }
         CurrentScreen::HelpMessagePopUp(_) => {
             let title = " Press any key to close this pop up ";
             render_pop_up(f, HELP_MESSAGE, title)
@@ -36,6 +68,20 @@ pub fn render_file_navigator(app: &mut App, f: &mut Frame) {
         .constraints([Constraint::Percentage(100), Constraint::Min(3)])
         .split(f.size());
 
+    let list_area = if app.is_hunk_mode() {
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([
+                Constraint::Percentage(50),
+                Constraint::Percentage(50),
+            ])
+            .split(chunks[0]);
+        render_hunk_preview(app, f, columns[1]);
+        columns[0]
+    } else {
+        chunks[0]
+    };
+
     let items: Vec<ListItem> = app
         .items
         .items
@@ -46,24 +92,43 @@ pub fn render_file_navigator(app: &mut App, f: &mut Frame) {
                 Mark::Unselected => "☐",
                 Mark::PartiallySelected => "⚀",
             };
-            let line = if i.is_directory() {
-                format!("{} {}/", prefix, i.key.as_os_str().to_str().expect(""))
+            let name = match i.get_old_path() {
+                Some(old_path) => format!(
+                    "{} -> {}",
+                    display_name(old_path.as_os_str()),
+                    display_name(&i.key)
+                ),
+                None => display_name(&i.key),
+            };
+            let mut spans = vec![Span::raw(format!("{} ", prefix))];
+            if let Some(status) = i.get_status() {
+                spans.push(status_span(status, String::new()));
+                spans.push(Span::raw(" "));
+            }
+            if i.is_directory() {
+                spans.push(Span::raw(format!("{}/", name)));
+                for (status, count) in i.count_statuses() {
+                    spans.push(Span::raw(" "));
+                    spans.push(status_span(status, count.to_string()));
+                }
+            } else if i.num_hunks() > 0 {
+                spans.push(Span::raw(format!(
+                    "{} ({} hunks)",
+                    name,
+                    i.num_hunks()
+                )));
             } else {
-                format!("{} {}", prefix, i.key.as_os_str().to_str().expect(""))
+                spans.push(Span::raw(name));
             };
-            let lines = vec![Line::from(line)];
+            let lines = vec![Line::from(spans)];
             ListItem::new(lines)
                 .style(Style::default().fg(Color::Black).bg(Color::White))
         })
         .collect();
 
     let path_buf = app.get_current_path();
-    let path_str = path_buf
-        .as_os_str()
-        .to_str()
-        .expect("Cannot convert current path to str.");
-
-    let title = format!(" Current path: {} ", path_str);
+    let title =
+        format!(" Current path: {} ", display_name(path_buf.as_os_str()));
     let items = List::new(items)
         .block(Block::default().borders(Borders::ALL).title(title))
         .highlight_style(
@@ -74,13 +139,24 @@ pub fn render_file_navigator(app: &mut App, f: &mut Frame) {
         .highlight_symbol(">> ");
 
     // We can now render the item list
-    f.render_stateful_widget(items, chunks[0], &mut app.items.state);
+    f.render_stateful_widget(items, list_area, &mut app.items.state);
 
     let commit_no = app.commits.len() + 1;
     let (num_total, num_selected) = app.get_stats();
+    let unit = if app.is_hunk_mode() {
+        "changes"
+    } else {
+        "files"
+    };
+    let commit = match app.get_suggestion_text() {
+        Some(suggestion) => {
+            format!("Commit {}, {}, n: skip", commit_no, suggestion)
+        }
+        None => format!("Commit {}", commit_no),
+    };
     let text = format!(
-        "[Commit {}] # of total files: {}, # of selected files: {} (h: help)",
-        commit_no, num_total, num_selected
+        "[{}] # of total {}: {}, # of selected {}: {} (h: help)",
+        commit, unit, num_total, unit, num_selected
     );
 
     let bottom_widget =
@@ -88,12 +164,191 @@ pub fn render_file_navigator(app: &mut App, f: &mut Frame) {
     f.render_widget(bottom_widget, chunks[1]);
 }
 
+pub fn render_plan_editor(app: &mut App, f: &mut Frame) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(100), Constraint::Min(3)])
+        .split(f.size());
+
+    let items: Vec<ListItem> = app
+        .commits
+        .iter()
+        .enumerate()
+        .map(|(index, commit)| {
+            let subject = commit.msg.lines().next().unwrap_or_default();
+            let mut details = vec![plural(commit.file_paths.len(), "file")];
+            if !commit.hunks.is_empty() {
+                details.push(plural(commit.hunks.len(), "hunk"));
+            }
+            if let Some(author) = &commit.author {
+                details.push(format!("by {}", author.name));
+            }
+            ListItem::new(Line::from(vec![
+                Span::raw(format!("{}. {} ", index + 1, subject)),
+                Span::styled(
+                    format!("({})", details.join(", ")),
+                    Style::default().fg(Color::DarkGray),
+                ),
+            ]))
+        })
+        .collect();
+
+    let title = if app.tree.borrow().num_leaf_node == 0 {
+        " Every change is committed. Press 'Ctrl + W' to apply the commits "
+    } else {
+        " Saved commits "
+    };
+    let items = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_symbol(">> ");
+
+    f.render_stateful_widget(items, chunks[0], &mut app.plan);
+
+    let text = "Shift + ↑/↓: move, Enter: edit the message, m: merge with the next, d: delete, Ctrl + W: apply, Esc: go back (h: help)";
+    let bottom_widget =
+        Paragraph::new(text).block(Block::default().borders(Borders::ALL));
+    f.render_widget(bottom_widget, chunks[1]);
+}
+
+/// Renders the strategies to suggest commits with over the file navigator.
+fn render_suggestion_picker(app: &mut App, f: &mut Frame) {
+    let items: Vec<ListItem> = app
+        .strategies
+        .items
+        .iter()
+        .map(|strategy| {
+            let count = app.get_suggestions(*strategy).len();
+            let text = match count {
+                0 => format!("{} (no workspace)", strategy.describe()),
+                1 => format!("{} (1 commit)", strategy.describe()),
+                _ => format!("{} ({} commits)", strategy.describe(), count),
+            };
+            ListItem::new(text)
+        })
+        .collect();
+
+    let title = " Suggest commits: Enter to pick, Esc to cancel ";
+    let items = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_symbol(">> ");
+
+    let area = centered_rect(80, 30, f.size());
+    f.render_widget(Clear, area);
+    f.render_stateful_widget(items, area, &mut app.strategies.state);
+}
+
+/// Renders a file name for display. A name which is not valid UTF-8 is
+/// decoded lossily with a marker, and control characters are escaped so that
+/// they cannot break the layout.
+pub fn display_name(name: &OsStr) -> String {
+    let mut text = String::new();
+    for c in name.to_string_lossy().chars() {
+        if c.is_control() {
+            text.extend(c.escape_default());
+        } else {
+            text.push(c);
+        }
+    }
+    if name.to_str().is_none() {
+        text.push_str(" [invalid UTF-8]");
+    }
+    text
+}
+
+fn status_color(status: ChangeStatus) -> Color {
+    match status {
+        ChangeStatus::Added => Color::Green,
+        ChangeStatus::Modified => Color::Blue,
+        ChangeStatus::Deleted => Color::Red,
+        ChangeStatus::Renamed => Color::Magenta,
+        ChangeStatus::TypeChanged | ChangeStatus::ModeChanged => Color::Cyan,
+    }
+}
+
+/// Renders the status letter followed by the suffix, e.g. a count.
+fn status_span(status: ChangeStatus, suffix: String) -> Span<'static> {
+    Span::styled(
+        format!("{}{}", status.letter(), suffix),
+        Style::default()
+            .fg(status_color(status))
+            .add_modifier(Modifier::BOLD),
+    )
+}
+
+fn diff_line_color(line: &str) -> Color {
+    if line.starts_with("@@") {
+        Color::Cyan
+    } else if line.starts_with('+') {
+        Color::Green
+    } else if line.starts_with('-') {
+        Color::Red
+    } else {
+        Color::Reset
+    }
+}
+
+fn render_hunk_preview(app: &App, f: &mut Frame, area: Rect) {
+    let lines: Vec<Line> = app
+        .get_preview()
+        .into_iter()
+        .map(|line| {
+            let style = Style::default().fg(diff_line_color(&line));
+            Line::styled(line, style)
+        })
+        .collect();
+
+    let preview = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(" Preview "));
+    f.render_widget(preview, area);
+}
+
+pub fn render_line_selector(app: &mut App, f: &mut Frame) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(100), Constraint::Min(3)])
+        .split(f.size());
+
+    let items: Vec<ListItem> = app
+        .lines
+        .items
+        .iter()
+        .map(|line| {
+            let prefix = match (line.is_change(), line.is_selected()) {
+                (true, true) => "☑",
+                (true, false) => "☐",
+                (false, _) => " ",
+            };
+            let style = Style::default().fg(diff_line_color(&line.text));
+            ListItem::new(Line::styled(
+                format!("{} {}", prefix, line.text),
+                style,
+            ))
+        })
+        .collect();
+
+    let title = format!(" Select lines: {} ", app.get_hunk_title());
+    let items = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_symbol(">> ");
+
+    f.render_stateful_widget(items, chunks[0], &mut app.lines.state);
+
+    let text = "Space: select a line, ← or Esc: go back, Enter: write the commit message (h: help)";
+    let bottom_widget =
+        Paragraph::new(text).block(Block::default().borders(Borders::ALL));
+    f.render_widget(bottom_widget, chunks[1]);
+}
+
 pub fn render_commit_message_editor(app: &mut App, f: &mut Frame) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([Constraint::Percentage(100), Constraint::Min(3)])
         .split(f.size());
-    let title = " Enter commit message ";
+    let title =
+        format!(" Enter commit message (author: {}) ", app.get_author_text());
 
     let block = Block::default()
         .borders(Borders::ALL)
@@ -105,7 +360,7 @@ pub fn render_commit_message_editor(app: &mut App, f: &mut Frame) {
 
     f.render_widget(widget, chunks[0]);
 
-    let text = "Press 'Esc' to cancel or 'Ctrl + W' to save the message";
+    let text = "Press 'Esc' to cancel, 'Ctrl + T' to change the author or 'Ctrl + W' to save the message";
     let bottom_widget =
         Paragraph::new(text).block(Block::default().borders(Borders::ALL));
     f.render_widget(bottom_widget, chunks[1]);
@@ -5,10 +5,16 @@ use crate::app::{App, CurrentScreen};
 pub fn update(app: &mut App, key_event: KeyEvent) {
     match app.current_screen {
         CurrentScreen::FileNavigator => update_file_navigator(app, key_event),
+        CurrentScreen::LineSelector => update_line_selector(app, key_event),
         CurrentScreen::CommitMessageEditor => {
             update_commit_message_editor(app, key_event)
         }
+        CurrentScreen::SuggestionPicker => {
+            update_suggestion_picker(app, key_event)
+        }
+        CurrentScreen::PlanEditor => update_plan_editor(app, key_event),
         CurrentScreen::ErrorMessagePopUp(_, _) => app.close_popup(),
+        CurrentScreen::InfoMessagePopUp(_, _) => app.close_popup(),
         CurrentScreen::HelpMessagePopUp(_) => app.close_popup(),
     }
 }
@@ -16,12 +22,17 @@ pub fn update(app: &mut App, key_event: KeyEvent) {
 fn update_file_navigator(app: &mut App, key_event: KeyEvent) {
     match key_event.code {
         KeyCode::Esc | KeyCode::Char('q') => app.quit(),
-        KeyCode::Char('c') | KeyCode::Char('C') => {
-            if key_event.modifiers == KeyModifiers::CONTROL {
-                app.quit()
-            }
+        KeyCode::Char('c') | KeyCode::Char('C')
+            if key_event.modifiers == KeyModifiers::CONTROL =>
+        {
+            app.quit()
         }
         KeyCode::Char('h') | KeyCode::Char('H') => app.open_help_popup(),
+        KeyCode::Char('s') | KeyCode::Char('S') => app.save_plan(),
+        KeyCode::Char('g') | KeyCode::Char('G') => app.open_suggestion_picker(),
+        KeyCode::Char('n') | KeyCode::Char('N') => app.next_suggestion(),
+        KeyCode::Char('p') | KeyCode::Char('P') => app.open_plan_editor(),
+        KeyCode::Char('u') | KeyCode::Char('U') => app.undo_commit(),
         KeyCode::Up => app.items.previous(),
         KeyCode::Down => app.items.next(),
         KeyCode::Right => app.goto_child(),
@@ -32,6 +43,54 @@ fn update_file_navigator(app: &mut App, key_event: KeyEvent) {
     };
 }
 
+fn update_line_selector(app: &mut App, key_event: KeyEvent) {
+    match key_event.code {
+        KeyCode::Esc | KeyCode::Left => app.close_line_selector(),
+        KeyCode::Char('h') | KeyCode::Char('H') => app.open_help_popup(),
+        KeyCode::Up => app.lines.previous(),
+        KeyCode::Down => app.lines.next(),
+        KeyCode::Char(' ') => app.select_line(),
+        KeyCode::Enter => app.open_editor(),
+        _ => {}
+    };
+}
+
+fn update_suggestion_picker(app: &mut App, key_event: KeyEvent) {
+    match key_event.code {
+        KeyCode::Esc | KeyCode::Char('q') => app.close_suggestion_picker(),
+        KeyCode::Up => app.strategies.previous(),
+        KeyCode::Down => app.strategies.next(),
+        KeyCode::Enter => app.pick_suggestions(),
+        _ => {}
+    };
+}
+
+fn update_plan_editor(app: &mut App, key_event: KeyEvent) {
+    match key_event.code {
+        KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('p') => {
+            app.close_plan_editor()
+        }
+        KeyCode::Char('w') | KeyCode::Char('W')
+            if key_event.modifiers.contains(KeyModifiers::CONTROL) =>
+        {
+            app.apply_commits()
+        }
+        KeyCode::Char('h') | KeyCode::Char('H') => app.open_help_popup(),
+        KeyCode::Up if key_event.modifiers.contains(KeyModifiers::SHIFT) => {
+            app.move_commit(false)
+        }
+        KeyCode::Down if key_event.modifiers.contains(KeyModifiers::SHIFT) => {
+            app.move_commit(true)
+        }
+        KeyCode::Up => app.previous_commit(),
+        KeyCode::Down => app.next_commit(),
+        KeyCode::Enter | KeyCode::Char('e') => app.edit_commit(),
+        KeyCode::Char('m') => app.merge_commit(),
+        KeyCode::Char('d') | KeyCode::Delete => app.delete_commit(),
+        _ => {}
+    };
+}
+
 fn update_commit_message_editor(app: &mut App, key_event: KeyEvent) {
     if key_event.code == KeyCode::Esc {
         app.close_editor();
@@ -46,5 +105,13 @@ fn update_commit_message_editor(app: &mut App, key_event: KeyEvent) {
         return;
     }
 
+    if (key_event.code == KeyCode::Char('t')
+        || key_event.code == KeyCode::Char('T'))
+        && key_event.modifiers.contains(KeyModifiers::CONTROL)
+    {
+        app.next_author();
+        return;
+    }
+
     app.textarea.input(key_event);
 }